@@ -0,0 +1,1186 @@
+use crate::compression::{self, Encoder, Settings};
+use crate::config::{self, BackupConfig, BackupEncryptionMode, BackupProfile, CompressionFormat};
+use crate::encryption::{self, BackupKeys, Sink};
+use crate::manifest::{self, HashingReader, Manifest, ManifestEntry};
+use crate::remote;
+use crate::retention;
+use crate::state;
+use crate::task::{self, TaskEvent};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
+use std::ffi::CString;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+use tar::{Archive, Builder, EntryType, Header, HeaderMode};
+
+// Number of archive entries listed before the summary is cut short
+pub const SUMMARY_LIMIT: usize = 500;
+
+// Default profiles that replaced the NeoVim State, Share and Cache backups
+const LEGACY_PROFILES: [&str; 3] = ["nvim-state", "nvim-share", "nvim-cache"];
+
+// How often a running backup updates its gauge
+const REPORT_INTERVAL: Duration = Duration::from_millis(100);
+
+// How far a running backup got, reported to its task gauge. Once the task is
+// cancelled the next update fails, which stops the backup. The error is not
+// Interrupted on purpose, io::copy retries those.
+pub struct Progress<'a> {
+    sender: &'a Sender<TaskEvent>,
+    cancelled: &'a AtomicBool,
+    total: u64,
+    files: usize,
+    bytes: u64,
+    started: Instant,
+    reported: Instant,
+}
+
+impl<'a> Progress<'a> {
+    pub fn new(sender: &'a Sender<TaskEvent>, cancelled: &'a AtomicBool) -> Self {
+        Self {
+            sender,
+            cancelled,
+            total: 0,
+            files: 0,
+            bytes: 0,
+            started: Instant::now(),
+            reported: Instant::now(),
+        }
+    }
+
+    // Start counting towards the size of all files to read
+    pub fn start(&mut self, total: u64) {
+        self.total = total;
+        self.started = Instant::now();
+    }
+
+    pub fn file(&mut self) -> io::Result<()> {
+        self.files += 1;
+        self.update()
+    }
+
+    pub fn read(&mut self, bytes: u64) -> io::Result<()> {
+        self.bytes += bytes;
+        self.update()
+    }
+
+    fn update(&mut self) -> io::Result<()> {
+        if self.cancelled.load(Ordering::Relaxed) {
+            return Err(io::Error::other("the backup was cancelled"));
+        }
+        if self.reported.elapsed() < REPORT_INTERVAL {
+            return Ok(());
+        }
+        self.reported = Instant::now();
+        let seconds = self.started.elapsed().as_secs_f64().max(0.001);
+        let label = format!(
+            "{} files, {} of {}, {}/s",
+            self.files,
+            remote::format_bytes(self.bytes as usize),
+            remote::format_bytes(self.total as usize),
+            remote::format_bytes((self.bytes as f64 / seconds) as usize)
+        );
+        task::progress(self.sender, label, self.bytes as usize, self.total as usize);
+        Ok(())
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    // Show what the backup does after reading the files
+    pub fn stage(&self, label: &str) {
+        task::progress(self.sender, String::from(label), 1, 1);
+    }
+}
+
+// Counts what is read through it towards the progress of a backup
+pub struct ProgressReader<'a, 'b, R> {
+    inner: R,
+    progress: &'a mut Progress<'b>,
+}
+
+impl<'a, 'b, R: Read> ProgressReader<'a, 'b, R> {
+    pub fn new(inner: R, progress: &'a mut Progress<'b>) -> Self {
+        Self { inner, progress }
+    }
+}
+
+impl<R: Read> Read for ProgressReader<'_, '_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.progress.read(read as u64)?;
+        Ok(read)
+    }
+}
+
+// Source paths of a profile, which must all live under $HOME
+pub fn source_paths(profile: &BackupProfile, home: &Path) -> Result<Vec<PathBuf>, String> {
+    profile
+        .paths
+        .iter()
+        .map(|path| {
+            let source = config::expand_home(path);
+            if source.starts_with(home) && source != home {
+                Ok(source)
+            } else {
+                Err(format!("{} is not inside {}", path, home.display()))
+            }
+        })
+        .collect()
+}
+
+// gitignore-style patterns, anchored at the source directory
+fn exclude_matcher(profile: &BackupProfile, source: &Path) -> Result<Gitignore, String> {
+    let root = if source.is_dir() {
+        source
+    } else {
+        source.parent().unwrap_or(source)
+    };
+    let mut builder = GitignoreBuilder::new(root);
+    for pattern in &profile.exclude {
+        builder
+            .add_line(None, pattern)
+            .map_err(|e| format!("Invalid exclude {}: {}", pattern, e))?;
+    }
+    builder.build().map_err(|e| e.to_string())
+}
+
+// Path of an archive entry as recorded in manifests
+fn entry_key(path: &Path) -> String {
+    path.components()
+        .filter(|component| *component != Component::CurDir)
+        .collect::<PathBuf>()
+        .to_string_lossy()
+        .to_string()
+}
+
+// Sources of a profile with the excludes that apply inside each
+pub fn profile_sources(
+    profile: &BackupProfile,
+    home: &Path,
+) -> Result<Vec<(PathBuf, Gitignore)>, String> {
+    source_paths(profile, home)?
+        .into_iter()
+        .map(|source| {
+            let excludes = exclude_matcher(profile, &source)?;
+            Ok((source, excludes))
+        })
+        .collect()
+}
+
+// Visit every path of the sources a backup stores, directories before their
+// contents, with its path relative to $HOME
+pub fn walk_sources(
+    sources: &[(PathBuf, Gitignore)],
+    home: &Path,
+    visit: &mut dyn FnMut(&Path, &Path, &fs::Metadata) -> io::Result<()>,
+) -> io::Result<()> {
+    fn walk(
+        path: &Path,
+        source: &Path,
+        home: &Path,
+        excludes: &Gitignore,
+        visit: &mut dyn FnMut(&Path, &Path, &fs::Metadata) -> io::Result<()>,
+    ) -> io::Result<()> {
+        let metadata = fs::symlink_metadata(path)?;
+        let file_type = metadata.file_type();
+        // Patterns match inside the source, the source itself is always kept
+        if path != source && excludes.matched(path, file_type.is_dir()).is_ignore() {
+            return Ok(());
+        }
+        // Sockets, fifos and devices can not be restored into $HOME
+        if !file_type.is_dir() && !file_type.is_file() && !file_type.is_symlink() {
+            return Ok(());
+        }
+
+        visit(path, path.strip_prefix(home).unwrap_or(path), &metadata)?;
+        if file_type.is_dir() {
+            let mut children: Vec<PathBuf> = fs::read_dir(path)?
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<Result<_, _>>()?;
+            children.sort();
+            for child in children {
+                walk(&child, source, home, excludes, visit)?;
+            }
+        }
+        Ok(())
+    }
+
+    let mut found = false;
+    for (source, excludes) in sources {
+        if fs::symlink_metadata(source).is_ok() {
+            found = true;
+            walk(source, source, home, excludes, visit)?;
+        }
+    }
+    if !found {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "None of the source paths exist",
+        ));
+    }
+    Ok(())
+}
+
+// Archive the sources into `dest` with paths relative to $HOME, so one
+// archive can hold files from several places. Symlinks are stored as links
+// and directories with their metadata, empty ones included. Returns the
+// manifest of what was written.
+pub fn create_archive(
+    sources: &[(PathBuf, Gitignore)],
+    home: &Path,
+    dest: &Path,
+    settings: &Settings,
+    encryptor: Option<age::Encryptor>,
+    progress: &mut Progress,
+) -> Result<Manifest, io::Error> {
+    let sink = Sink::new(File::create(dest)?, encryptor)?;
+    let encoder = Encoder::new(sink, settings)?;
+    let mut tar = Builder::new(encoder);
+    tar.mode(HeaderMode::Complete);
+    tar.follow_symlinks(false);
+
+    let mut manifest = Manifest::new();
+    walk_sources(sources, home, &mut |path, rel_path, metadata| {
+        let file_type = metadata.file_type();
+        let mtime = metadata.mtime().max(0) as u64;
+        let entry = if file_type.is_file() {
+            // Hash exactly the bytes that go into the archive, even if the
+            // file changes while it is read
+            progress.file()?;
+            let file = File::open(path)?;
+            let metadata = file.metadata()?;
+            let mut header = Header::new_gnu();
+            header.set_metadata_in_mode(&metadata, HeaderMode::Complete);
+            let mut reader =
+                HashingReader::new(ProgressReader::new(file.take(metadata.len()), progress));
+            tar.append_data(&mut header, rel_path, &mut reader)?;
+            ManifestEntry {
+                kind: 'f',
+                size: metadata.len(),
+                mtime: header.mtime()?,
+                sha256: reader.finish(),
+            }
+        } else if file_type.is_symlink() {
+            tar.append_path_with_name(path, rel_path)?;
+            ManifestEntry::symlink(fs::read_link(path)?.as_os_str().as_bytes(), mtime)
+        } else {
+            tar.append_path_with_name(path, rel_path)?;
+            ManifestEntry::directory(mtime)
+        };
+        manifest.insert(entry_key(rel_path), entry);
+        Ok(())
+    })?;
+    tar.into_inner()?.finish()?.finish()?.sync_all()?;
+    Ok(manifest)
+}
+
+// Read every entry of an archive back, hashing its content
+pub fn read_manifest(path: &Path, keys: &BackupKeys) -> io::Result<Manifest> {
+    let mut archive = open_archive(path, keys)?;
+    let mut manifest = Manifest::new();
+    for entry in archive.entries()? {
+        let entry = entry?;
+        let key = entry_key(&entry.path()?);
+        let mtime = entry.header().mtime()?;
+        let manifest_entry = match entry.header().entry_type() {
+            EntryType::Directory => ManifestEntry::directory(mtime),
+            EntryType::Symlink => {
+                ManifestEntry::symlink(&entry.link_name_bytes().unwrap_or_default(), mtime)
+            }
+            _ => {
+                let mut reader = HashingReader::new(entry);
+                let size = io::copy(&mut reader, &mut io::sink())?;
+                ManifestEntry {
+                    kind: 'f',
+                    size,
+                    mtime,
+                    sha256: reader.finish(),
+                }
+            }
+        };
+        manifest.insert(key, manifest_entry);
+    }
+    Ok(manifest)
+}
+
+// The files of a profile as they are now, hashed like a backup records them
+pub fn live_manifest(profile: &BackupProfile, home: &Path) -> Result<Manifest, String> {
+    let sources = profile_sources(profile, home)?;
+    let mut manifest = Manifest::new();
+    walk_sources(&sources, home, &mut |path, rel_path, metadata| {
+        let file_type = metadata.file_type();
+        let mtime = metadata.mtime().max(0) as u64;
+        let entry = if file_type.is_file() {
+            let mut reader = HashingReader::new(File::open(path)?);
+            let size = io::copy(&mut reader, &mut io::sink())?;
+            ManifestEntry {
+                kind: 'f',
+                size,
+                mtime,
+                sha256: reader.finish(),
+            }
+        } else if file_type.is_symlink() {
+            ManifestEntry::symlink(fs::read_link(path)?.as_os_str().as_bytes(), mtime)
+        } else {
+            ManifestEntry::directory(mtime)
+        };
+        manifest.insert(entry_key(rel_path), entry);
+        Ok(())
+    })
+    .map_err(|e| e.to_string())?;
+    Ok(manifest)
+}
+
+// Check an archive against its manifest. Archives without one are still
+// read completely, which catches truncation and compression errors.
+pub fn verify(archive: &Path, keys: &BackupKeys) -> Result<String, String> {
+    let expected = manifest::read(archive, keys)?;
+    let actual = read_manifest(archive, keys).map_err(|e| match e.kind() {
+        io::ErrorKind::PermissionDenied => {
+            format!("Failed to decrypt {}: {}", archive.display(), e)
+        }
+        _ => format!("{} is corrupt: {}", archive.display(), e),
+    })?;
+    let Some(expected) = expected else {
+        return Ok(format!(
+            "{} has no manifest. All {} entries could be read.",
+            archive.display(),
+            actual.len()
+        ));
+    };
+    let problems = manifest::compare(&expected, &actual);
+    if problems.is_empty() {
+        return Ok(format!(
+            "{} verified, all {} entries match the manifest.",
+            archive.display(),
+            actual.len()
+        ));
+    }
+    let mut report = format!(
+        "{} does not match its manifest, {} problems:",
+        archive.display(),
+        problems.len()
+    );
+    for problem in problems {
+        report.push_str(&format!("\n- {}", problem));
+    }
+    Err(report)
+}
+
+pub fn backup_dir(config: &BackupConfig) -> PathBuf {
+    if let Some(dir) = &config.dir {
+        return config::expand_home(dir);
+    }
+    let data_dir = match env::var("XDG_DATA_HOME") {
+        Ok(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var("HOME").unwrap_or_default()).join(".local/share"),
+    };
+    data_dir.join("dot-utils/backups")
+}
+
+// Where the backups of a profile go: its own destination, such as a mounted
+// drive, or the shared backup directory
+pub fn profile_dir(config: &BackupConfig, profile: &BackupProfile) -> PathBuf {
+    match &profile.dest {
+        Some(dest) => config::expand_home(dest),
+        None => backup_dir(config),
+    }
+}
+
+// The directory to write backups of a profile to. A configured destination
+// must exist already, so nothing is written to the mount point of a drive
+// that is not mounted.
+pub fn prepare_dir(config: &BackupConfig, profile: &BackupProfile) -> Result<PathBuf, String> {
+    let dir = profile_dir(config, profile);
+    if profile.dest.is_some() && !dir.is_dir() {
+        return Err(format!(
+            "The destination {} does not exist. Is the drive mounted?",
+            dir.display()
+        ));
+    }
+    fs::create_dir_all(&dir).map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+    Ok(dir)
+}
+
+// Bytes the current user may still write to the filesystem holding `dir`
+fn free_space(dir: &Path) -> io::Result<u64> {
+    let path = CString::new(dir.as_os_str().as_bytes())?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+// Refuse to start a backup that is not going to fit
+pub fn check_free_space(dir: &Path, needed: u64) -> Result<(), String> {
+    let available = free_space(dir)
+        .map_err(|e| format!("Failed to check the free space of {}: {}", dir.display(), e))?;
+    if available < needed {
+        return Err(format!(
+            "Not enough space in {}: about {} needed, {} available",
+            dir.display(),
+            remote::format_bytes(needed as usize),
+            remote::format_bytes(available as usize)
+        ));
+    }
+    Ok(())
+}
+
+// Total size of the files a backup of the sources reads
+pub fn source_size(sources: &[(PathBuf, Gitignore)], home: &Path) -> Result<u64, String> {
+    let mut size = 0;
+    walk_sources(sources, home, &mut |_, _, metadata| {
+        if metadata.is_file() {
+            size += metadata.len();
+        }
+        Ok(())
+    })
+    .map_err(|e| e.to_string())?;
+    Ok(size)
+}
+
+// Files are written as `<name>.partial` and renamed once complete, so an
+// interrupted backup never leaves a partial file under its real name
+pub const PARTIAL: &str = ".partial";
+
+pub fn partial_path(path: &Path) -> PathBuf {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(PARTIAL);
+    PathBuf::from(partial)
+}
+
+// Rename a complete file into place and make the rename durable
+pub fn commit_partial(partial: &Path, path: &Path) -> io::Result<()> {
+    fs::rename(partial, path)?;
+    if let Some(dir) = path.parent() {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+pub fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    let partial = partial_path(path);
+    let mut file = File::create(&partial)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    commit_partial(&partial, path)
+}
+
+// Remove what interrupted backups of a profile left behind
+fn remove_partials(dir: &Path, prefix: &str) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.starts_with(prefix) && name.ends_with(PARTIAL) {
+            let _ = fs::remove_file(entry.path());
+        }
+    }
+}
+
+// Archives of one backup made on this host share a name prefix, so the
+// retention policy of one machine never prunes another's archives
+pub fn archive_prefix(name: &str) -> String {
+    format!("{}-{}-", name, state::hostname())
+}
+
+// `<name>-<hostname>-<timestamp>.tar.<format>[.age]` in `dir`
+pub fn new_archive_path(
+    dir: &Path,
+    name: &str,
+    format: CompressionFormat,
+    encrypted: bool,
+) -> PathBuf {
+    dir.join(format!(
+        "{}{}{}{}",
+        archive_prefix(name),
+        chrono::Local::now().format(retention::TIMESTAMP_FORMAT),
+        format.suffix(),
+        if encrypted { encryption::EXTENSION } else { "" }
+    ))
+}
+
+// Archives of a backup made on this host, newest first
+pub fn list_archives(config: &BackupConfig, profile: &BackupProfile) -> Vec<PathBuf> {
+    retention::timestamped_archives(
+        &profile_dir(config, profile),
+        &archive_prefix(&profile.name),
+        &compression::SUFFIXES,
+    )
+    .into_iter()
+    .map(|(_, path)| path)
+    .collect()
+}
+
+// Before backup profiles, the NeoVim backups wrote a single nvim.tar.gz next
+// to the directory, holding paths relative to it. The default profiles still
+// list it so those backups can be restored.
+pub fn legacy_archive(profile: &BackupProfile, home: &Path) -> Option<PathBuf> {
+    if !LEGACY_PROFILES.contains(&profile.name.as_str()) {
+        return None;
+    }
+    let [source] = source_paths(profile, home).ok()?.try_into().ok()?;
+    let archive = source.parent()?.join("nvim.tar.gz");
+    archive.is_file().then_some(archive)
+}
+
+// Write a new archive of a profile and prune old ones, returning a report
+pub fn back_up(
+    config: &BackupConfig,
+    profile: &BackupProfile,
+    home: &Path,
+    keys: &BackupKeys,
+    progress: &mut Progress,
+) -> Result<String, String> {
+    let sources = profile_sources(profile, home)?;
+    let settings = Settings::new(&config.compression)?;
+    let encryptor = encryption::archive_encryptor(&config.encryption, keys)?;
+    let manifest_encryptor = encryption::archive_encryptor(&config.encryption, keys)?;
+    let dir = prepare_dir(config, profile)?;
+    remove_partials(&dir, &archive_prefix(&profile.name));
+    let previous = list_archives(config, profile).into_iter().next();
+    // The new archive should come out near the previous one, without one
+    // assume no compression at all
+    let total = source_size(&sources, home)?;
+    let needed = match previous.as_ref().and_then(|path| fs::metadata(path).ok()) {
+        Some(metadata) => metadata.len() + metadata.len() / 10,
+        None => total,
+    };
+    check_free_space(&dir, needed)?;
+    progress.start(total);
+
+    let archive = new_archive_path(&dir, &profile.name, settings.format, encryptor.is_some());
+    let partial = partial_path(&archive);
+    let started = Instant::now();
+    let written = create_archive(&sources, home, &partial, &settings, encryptor, progress)
+        .and_then(|entries| {
+            commit_partial(&partial, &archive)?;
+            manifest::write(&archive, &entries, manifest_encryptor)?;
+            Ok(entries)
+        });
+    let elapsed = started.elapsed();
+    let entries = match written {
+        Ok(entries) => entries,
+        Err(e) => {
+            let _ = fs::remove_file(&partial);
+            let _ = fs::remove_file(&archive);
+            let _ = fs::remove_file(manifest::manifest_path(&archive));
+            if progress.is_cancelled() {
+                return Err(String::from("Cancelled, the partial archive was deleted."));
+            }
+            return Err(e.to_string());
+        }
+    };
+    // Never prune older archives for one that can not be read back
+    progress.stage("Verifying the new archive...");
+    let verified = match verify(&archive, keys) {
+        Ok(verified) => verified,
+        Err(e) => {
+            let _ = fs::remove_file(&archive);
+            let _ = fs::remove_file(manifest::manifest_path(&archive));
+            return Err(format!("{}\nThe archive was deleted.", e));
+        }
+    };
+
+    let encrypted = match config.encryption.mode {
+        BackupEncryptionMode::None => "",
+        BackupEncryptionMode::Passphrase => "\nEncrypted with the session passphrase",
+        BackupEncryptionMode::Recipients => "\nEncrypted to the configured age recipients",
+    };
+    let mut report = format!(
+        "Archive: {}{}\nManifest: {}\n{}\n\n{}",
+        archive.display(),
+        encrypted,
+        manifest::manifest_path(&archive).display(),
+        verified,
+        compression_report(&archive, &entries, &settings, elapsed, previous.as_deref())
+    );
+    let archives = retention::timestamped_archives(
+        &dir,
+        &archive_prefix(&profile.name),
+        &compression::SUFFIXES,
+    );
+    report.push_str(&retention::prune(&archives, &config.retention));
+    manifest::remove_orphaned(&dir);
+    Ok(report)
+}
+
+// Size and time of a new archive next to the previous one of the backup
+fn compression_report(
+    archive: &Path,
+    entries: &Manifest,
+    settings: &Settings,
+    elapsed: Duration,
+    previous: Option<&Path>,
+) -> String {
+    let size = fs::metadata(archive).map(|m| m.len()).unwrap_or(0);
+    let total: u64 = entries.values().map(|entry| entry.size).sum();
+    let seconds = elapsed.as_secs_f64().max(0.001);
+    let mut report = format!(
+        "Compression: {}\n{} compressed to {} ({:.1}%) in {:.1}s, {}/s",
+        settings.describe(),
+        remote::format_bytes(total as usize),
+        remote::format_bytes(size as usize),
+        size as f64 * 100.0 / total.max(1) as f64,
+        seconds,
+        remote::format_bytes((total as f64 / seconds) as usize)
+    );
+    let previous = previous.and_then(|path| {
+        let size = fs::metadata(path).ok()?.len();
+        Some((path, size, compression::from_name(path)?))
+    });
+    if let Some((path, previous_size, format)) = previous {
+        let change = size as f64 * 100.0 / previous_size.max(1) as f64 - 100.0;
+        report.push_str(&format!(
+            "\nPrevious archive: {} with {}, {}, this one is {:+.1}%",
+            path.file_name().unwrap_or_default().to_string_lossy(),
+            format.name(),
+            remote::format_bytes(previous_size as usize),
+            change
+        ));
+    }
+    report
+}
+
+fn open_archive(path: &Path, keys: &BackupKeys) -> io::Result<Archive<Box<dyn Read>>> {
+    let reader = encryption::archive_reader(path, keys)?;
+    Ok(Archive::new(compression::decoder(reader)?))
+}
+
+// Size of an archive and the files it holds
+pub fn describe(path: &Path, keys: &BackupKeys) -> io::Result<String> {
+    let size = fs::metadata(path)?.len();
+    let mut archive = open_archive(path, keys)?;
+    let mut lines = Vec::new();
+    let mut count = 0;
+    let mut total: u64 = 0;
+    for entry in archive.entries()? {
+        let entry = entry?;
+        count += 1;
+        total += entry.size();
+        if lines.len() < SUMMARY_LIMIT {
+            lines.push(format!(
+                "{:>10}  {}",
+                remote::format_bytes(entry.size() as usize),
+                entry.path()?.display()
+            ));
+        }
+    }
+
+    let mut report = format!(
+        "{}\n{} compressed, {} in {} entries\n\n{}",
+        path.display(),
+        remote::format_bytes(size as usize),
+        remote::format_bytes(total as usize),
+        count,
+        lines.join("\n")
+    );
+    if count > SUMMARY_LIMIT {
+        report.push_str(&format!("\n... and {} more", count - SUMMARY_LIMIT));
+    }
+    Ok(report)
+}
+
+// A relative path that never climbs above the directory it is resolved in
+pub fn stays_inside(path: &Path) -> bool {
+    let mut depth = 0i32;
+    for component in path.components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => {}
+            Component::ParentDir => {
+                depth -= 1;
+                if depth < 0 {
+                    return false;
+                }
+            }
+            Component::RootDir | Component::Prefix(_) => return false,
+        }
+    }
+    true
+}
+
+// Reject archives with entries that would be written outside the target,
+// before anything on disk is touched. Symlinks may point anywhere, but no
+// entry may be written through one.
+fn check_entries(path: &Path, keys: &BackupKeys) -> Result<(), String> {
+    let mut archive = open_archive(path, keys).map_err(|e| e.to_string())?;
+    let entries = archive.entries().map_err(|e| e.to_string())?;
+    let mut symlinks: Vec<PathBuf> = Vec::new();
+    for entry in entries {
+        let entry = entry.map_err(|e| e.to_string())?;
+        let entry_path = entry.path().map_err(|e| e.to_string())?.to_path_buf();
+        if !stays_inside(&entry_path) {
+            return Err(format!("unsafe path {}", entry_path.display()));
+        }
+        if let Some(link) = symlinks
+            .iter()
+            .find(|link| entry_path.starts_with(link) && entry_path != **link)
+        {
+            return Err(format!(
+                "{} is inside the symlink {}",
+                entry_path.display(),
+                link.display()
+            ));
+        }
+        match entry.header().entry_type() {
+            EntryType::Regular | EntryType::Directory | EntryType::GNUSparse => {}
+            EntryType::Symlink => symlinks.push(entry_path),
+            EntryType::Link => {
+                // Hard links resolve from the root of the archive
+                let link = entry
+                    .link_name()
+                    .map_err(|e| e.to_string())?
+                    .ok_or_else(|| format!("link {} has no target", entry_path.display()))?;
+                if !stays_inside(&link) {
+                    return Err(format!(
+                        "link {} points outside the archive to {}",
+                        entry_path.display(),
+                        link.display()
+                    ));
+                }
+            }
+            other => {
+                return Err(format!(
+                    "unsupported entry type {:?} for {}",
+                    other,
+                    entry_path.display()
+                ))
+            }
+        }
+    }
+    Ok(())
+}
+
+fn refused(path: &Path) -> io::Error {
+    io::Error::other(format!("refused to unpack {}", path.display()))
+}
+
+// Unpack with the stored permissions, modification times and, when running
+// as root, owners. Directories come last, deepest first, so restrictive
+// modes and their times are not undone by the entries inside them.
+fn unpack(archive_path: &Path, target: &Path, keys: &BackupKeys) -> io::Result<()> {
+    fs::create_dir_all(target)?;
+    let mut archive = open_archive(archive_path, keys)?;
+    archive.set_preserve_permissions(true);
+    archive.set_preserve_mtime(true);
+    // Only root can hand files to other users
+    archive.set_preserve_ownerships(unsafe { libc::geteuid() } == 0);
+
+    let mut directories = Vec::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if entry.header().entry_type() == EntryType::Directory {
+            directories.push(entry);
+        } else if !entry.unpack_in(target)? {
+            return Err(refused(&entry.path()?));
+        }
+    }
+    directories.sort_by(|a, b| b.path_bytes().cmp(&a.path_bytes()));
+    for mut directory in directories {
+        let path = directory.path()?.to_path_buf();
+        if !directory.unpack_in(target)? {
+            return Err(refused(&path));
+        }
+        let mtime = directory.header().mtime()?;
+        // A directory whose mode forbids reading keeps the time of unpacking
+        if let Ok(dir) = File::open(target.join(&path)) {
+            let _ = dir.set_modified(UNIX_EPOCH + Duration::from_secs(mtime));
+        }
+    }
+    Ok(())
+}
+
+// One entry of an archive, without its content
+pub struct ArchiveEntry {
+    pub path: PathBuf,
+    pub is_dir: bool,
+    pub size: u64,
+}
+
+// Entries of an archive sorted by path, reading only their headers
+pub fn list_entries(path: &Path, keys: &BackupKeys) -> io::Result<Vec<ArchiveEntry>> {
+    let mut archive = open_archive(path, keys)?;
+    let mut entries = Vec::new();
+    for entry in archive.entries()? {
+        let entry = entry?;
+        entries.push(ArchiveEntry {
+            path: PathBuf::from(entry_key(&entry.path()?)),
+            is_dir: entry.header().entry_type() == EntryType::Directory,
+            size: entry.size(),
+        });
+    }
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(entries)
+}
+
+// Content of the given files of an archive, read in one pass
+pub fn read_files(
+    path: &Path,
+    keys: &BackupKeys,
+    wanted: &BTreeSet<String>,
+) -> io::Result<BTreeMap<String, Vec<u8>>> {
+    let mut archive = open_archive(path, keys)?;
+    let mut files = BTreeMap::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let key = entry_key(&entry.path()?);
+        if entry.header().entry_type().is_file() && wanted.contains(&key) {
+            let mut content = Vec::new();
+            entry.read_to_end(&mut content)?;
+            files.insert(key, content);
+        }
+    }
+    Ok(files)
+}
+
+fn remove_path(path: &Path) -> io::Result<()> {
+    if fs::symlink_metadata(path)?.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }
+}
+
+// `<name>.before-restore-<timestamp>`, numbered if that is taken already
+fn aside_path(target: &Path, stamp: &str) -> PathBuf {
+    let name = target
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let mut aside = target.with_file_name(format!("{}.before-restore-{}", name, stamp));
+    let mut number = 1;
+    while fs::symlink_metadata(&aside).is_ok() {
+        number += 1;
+        aside = target.with_file_name(format!("{}.before-restore-{}-{}", name, stamp, number));
+    }
+    aside
+}
+
+// Move the existing targets aside and run `write`. If that fails, whatever
+// it wrote is removed and the previous files are put back. Returns where the
+// previous files were moved to.
+pub fn replace_targets(
+    targets: &[PathBuf],
+    write: impl FnOnce() -> io::Result<()>,
+) -> Result<Vec<PathBuf>, String> {
+    let stamp = chrono::Local::now().format("%Y%m%d%H%M%S").to_string();
+    let mut moved: Vec<(PathBuf, PathBuf)> = Vec::new();
+    for target in targets {
+        if fs::symlink_metadata(target).is_err() {
+            continue;
+        }
+        let aside = aside_path(target, &stamp);
+        if let Err(e) = fs::rename(target, &aside) {
+            for (target, aside) in &moved {
+                let _ = fs::rename(aside, target);
+            }
+            return Err(format!("could not move {} aside: {}", target.display(), e));
+        }
+        moved.push((target.clone(), aside));
+    }
+
+    if let Err(e) = write() {
+        for target in targets {
+            let _ = remove_path(target);
+        }
+        for (target, aside) in &moved {
+            let _ = fs::rename(aside, target);
+        }
+        return Err(format!("{}. The previous files were left as they were.", e));
+    }
+    Ok(moved.into_iter().map(|(_, aside)| aside).collect())
+}
+
+pub fn restore_report(from: &Path, targets: &[PathBuf], moved: &[PathBuf]) -> String {
+    let mut report = format!("Restored {}:", from.display());
+    for target in targets {
+        report.push_str(&format!("\n- {}", target.display()));
+    }
+    if !moved.is_empty() {
+        report.push_str("\n\nThe previous files were moved aside:");
+        for aside in moved {
+            report.push_str(&format!("\n- {}", aside.display()));
+        }
+    }
+    report
+}
+
+// Source paths of a profile that entries relative to $HOME fall into
+pub fn rooted_targets(
+    profile: &BackupProfile,
+    home: &Path,
+    entries: &[PathBuf],
+) -> Result<Vec<PathBuf>, String> {
+    Ok(source_paths(profile, home)?
+        .into_iter()
+        .filter(|source| {
+            source
+                .strip_prefix(home)
+                .is_ok_and(|relative| entries.iter().any(|entry| entry.starts_with(relative)))
+        })
+        .collect())
+}
+
+// Where the entries of an archive belong and the source paths it replaces.
+// Archives hold paths relative to $HOME, a legacy archive (see
+// `legacy_archive`) holds paths relative to the single source directory.
+fn restore_targets(
+    archive: &Path,
+    profile: &BackupProfile,
+    home: &Path,
+    keys: &BackupKeys,
+) -> Result<(PathBuf, Vec<PathBuf>), String> {
+    let entries: Vec<PathBuf> = list_entries(archive, keys)
+        .map_err(|e| e.to_string())?
+        .into_iter()
+        .map(|entry| entry.path)
+        .collect();
+    let sources = source_paths(profile, home)?;
+    let relative: Vec<&Path> = sources
+        .iter()
+        .filter_map(|source| source.strip_prefix(home).ok())
+        .collect();
+    let rooted = entries
+        .iter()
+        .all(|entry| relative.iter().any(|source| entry.starts_with(source)));
+    if rooted {
+        Ok((home.to_path_buf(), rooted_targets(profile, home, &entries)?))
+    } else if sources.len() == 1 {
+        Ok((sources[0].clone(), sources))
+    } else {
+        Err(format!(
+            "{} does not match the paths of backup {}",
+            archive.display(),
+            profile.name
+        ))
+    }
+}
+
+// Extract an archive of a profile after verifying it, moving the sources it
+// holds aside first. A failed extraction puts the previous files back.
+pub fn restore(
+    archive: &Path,
+    profile: &BackupProfile,
+    home: &Path,
+    keys: &BackupKeys,
+) -> Result<String, String> {
+    check_entries(archive, keys)
+        .map_err(|e| format!("Refusing to restore {}: {}", archive.display(), e))?;
+    verify(archive, keys).map_err(|e| format!("Refusing to restore: {}", e))?;
+    let (root, targets) = restore_targets(archive, profile, home, keys)?;
+
+    let moved = replace_targets(&targets, || unpack(archive, &root, keys))
+        .map_err(|e| format!("Failed to restore {}: {}", archive.display(), e))?;
+    Ok(restore_report(archive, &targets, &moved))
+}
+
+// The directory the entries of an archive were backed up from
+pub fn original_root(
+    archive: &Path,
+    profile: &BackupProfile,
+    home: &Path,
+    keys: &BackupKeys,
+) -> Result<PathBuf, String> {
+    restore_targets(archive, profile, home, keys).map(|(root, _)| root)
+}
+
+// The selected path an entry is, or is inside of
+fn selected_by<'a>(path: &Path, selection: &'a BTreeSet<PathBuf>) -> Option<&'a PathBuf> {
+    selection.iter().find(|selected| path.starts_with(selected))
+}
+
+// Extract the selected entries, and everything inside selected directories,
+// below `root`. With `flatten`, selections land directly in `root` instead of
+// keeping their path inside the archive. Existing files are moved aside.
+pub fn extract(
+    archive: &Path,
+    selection: &BTreeSet<PathBuf>,
+    root: &Path,
+    flatten: bool,
+    keys: &BackupKeys,
+) -> Result<String, String> {
+    check_entries(archive, keys)
+        .map_err(|e| format!("Refusing to extract from {}: {}", archive.display(), e))?;
+    fs::create_dir_all(root).map_err(|e| format!("Failed to create {}: {}", root.display(), e))?;
+    let canonical_root = root
+        .canonicalize()
+        .map_err(|e| format!("Failed to resolve {}: {}", root.display(), e))?;
+
+    let stamp = chrono::Local::now().format("%Y%m%d%H%M%S").to_string();
+    let mut extracted = Vec::new();
+    let mut moved = Vec::new();
+    let mut skipped = Vec::new();
+    let result = (|| -> io::Result<()> {
+        let mut tar = open_archive(archive, keys)?;
+        for entry in tar.entries()? {
+            let mut entry = entry?;
+            let path = PathBuf::from(entry_key(&entry.path()?));
+            let Some(selected) = selected_by(&path, selection) else {
+                continue;
+            };
+            if entry.header().entry_type() == EntryType::Link {
+                skipped.push(path);
+                continue;
+            }
+            let relative = match selected.parent() {
+                Some(parent) if flatten => path.strip_prefix(parent).unwrap_or(&path),
+                _ => &path,
+            };
+            let dest = root.join(relative);
+
+            // Never write through a symlink that leads out of the root
+            if let Some(parent) = dest.parent() {
+                fs::create_dir_all(parent)?;
+                if !parent.canonicalize()?.starts_with(&canonical_root) {
+                    return Err(refused(&path));
+                }
+            }
+            let is_dir = entry.header().entry_type() == EntryType::Directory;
+            if let Ok(metadata) = fs::symlink_metadata(&dest) {
+                if is_dir && metadata.is_dir() {
+                    continue;
+                }
+                let aside = aside_path(&dest, &stamp);
+                fs::rename(&dest, &aside)?;
+                moved.push(aside);
+            }
+            entry.set_preserve_permissions(true);
+            entry.set_preserve_mtime(true);
+            entry.unpack(&dest)?;
+            if !is_dir {
+                extracted.push(dest);
+            }
+        }
+        Ok(())
+    })();
+
+    let mut report = match &result {
+        Ok(()) => format!(
+            "Extracted {} files into {}:",
+            extracted.len(),
+            root.display()
+        ),
+        Err(e) => format!(
+            "Extraction from {} stopped after {} files: {}",
+            archive.display(),
+            extracted.len(),
+            e
+        ),
+    };
+    for path in &extracted {
+        report.push_str(&format!("\n- {}", path.display()));
+    }
+    if !moved.is_empty() {
+        report.push_str("\n\nExisting files were moved aside:");
+        for path in &moved {
+            report.push_str(&format!("\n- {}", path.display()));
+        }
+    }
+    if !skipped.is_empty() {
+        report.push_str("\n\nHard links are not extracted:");
+        for path in &skipped {
+            report.push_str(&format!("\n- {}", path.display()));
+        }
+    }
+    match result {
+        Ok(()) => Ok(report),
+        Err(_) => Err(report),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::EncryptionConfig;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use tempfile::TempDir;
+
+    // A gzip compressed archive of empty entries. Their names are written
+    // raw, as tar::Builder itself refuses the `..` an attacker would use.
+    fn archive(entries: &[(EntryType, &str, Option<&str>)]) -> (TempDir, PathBuf) {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("archive.tar.gz");
+        let file = File::create(&path).unwrap();
+        let mut builder = Builder::new(GzEncoder::new(file, Compression::default()));
+        for (kind, name, link) in entries {
+            let mut header = Header::new_gnu();
+            header.set_entry_type(*kind);
+            header.set_mode(0o644);
+            header.set_size(0);
+            header.as_old_mut().name[..name.len()].copy_from_slice(name.as_bytes());
+            if let Some(link) = link {
+                header.as_old_mut().linkname[..link.len()].copy_from_slice(link.as_bytes());
+            }
+            header.set_cksum();
+            builder.append(&header, io::empty()).unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap();
+        (dir, path)
+    }
+
+    fn check(entries: &[(EntryType, &str, Option<&str>)]) -> Result<(), String> {
+        let (_dir, path) = archive(entries);
+        let config = EncryptionConfig::default();
+        let keys = BackupKeys {
+            config: &config,
+            passphrase: None,
+        };
+        check_entries(&path, &keys)
+    }
+
+    #[test]
+    fn accepts_entries_inside_the_target() {
+        let checked = check(&[
+            (EntryType::Directory, "nvim/", None),
+            (EntryType::Regular, "nvim/shada", None),
+            (EntryType::Regular, "nvim/./a/../b", None),
+            (EntryType::Symlink, "nvim/link", Some("../../anywhere")),
+            (EntryType::Link, "nvim/hard", Some("nvim/shada")),
+        ]);
+        assert_eq!(checked, Ok(()));
+    }
+
+    #[test]
+    fn refuses_paths_leaving_the_target() {
+        for path in ["../escape", "nvim/../../escape", "/etc/passwd"] {
+            let refused = check(&[(EntryType::Regular, path, None)]).unwrap_err();
+            assert!(refused.starts_with("unsafe path"), "{}: {}", path, refused);
+        }
+    }
+
+    #[test]
+    fn refuses_entries_written_through_a_symlink() {
+        let refused = check(&[
+            (EntryType::Symlink, "nvim", Some("/etc")),
+            (EntryType::Regular, "nvim/passwd", None),
+        ])
+        .unwrap_err();
+        assert!(refused.contains("inside the symlink nvim"), "{}", refused);
+    }
+
+    #[test]
+    fn refuses_hard_links_leaving_the_target() {
+        let refused =
+            check(&[(EntryType::Link, "nvim/hard", Some("../../etc/shadow"))]).unwrap_err();
+        assert!(refused.contains("points outside"), "{}", refused);
+    }
+
+    #[test]
+    fn refuses_unsupported_entry_types() {
+        let refused = check(&[(EntryType::Fifo, "nvim/fifo", None)]).unwrap_err();
+        assert!(refused.starts_with("unsupported entry type"), "{}", refused);
+    }
+}
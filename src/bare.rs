@@ -0,0 +1,162 @@
+use crate::sparse;
+use git2::build::CheckoutBuilder;
+use git2::{ObjectType, Repository, Status, TreeWalkMode, TreeWalkResult};
+use std::fs;
+use std::path::Path;
+
+// Repository config key set while the tracked files are removed from $HOME
+const UNLINKED_KEY: &str = "dotutils.unlinked";
+
+// Whether Unlink Dotfiles removed the tracked files. Syncing or committing
+// then would record every file as deleted.
+pub fn is_unlinked(repo: &Repository) -> bool {
+    repo.config()
+        .and_then(|config| config.get_bool(UNLINKED_KEY))
+        .unwrap_or(false)
+}
+
+fn set_unlinked(repo: &Repository, unlinked: bool) -> Result<(), git2::Error> {
+    repo.config()?.set_bool(UNLINKED_KEY, unlinked)
+}
+
+// Set up a freshly cloned bare repository the way the git CLI layout expects
+pub fn configure(repo: &Repository) -> Result<(), git2::Error> {
+    // $HOME is mostly untracked files, listing them makes `git status` useless
+    repo.config()?.set_str("status.showUntrackedFiles", "no")
+}
+
+fn same_content(repo: &Repository, target: &Path, id: git2::Oid, mode: i32) -> bool {
+    let Ok(blob) = repo.find_blob(id) else {
+        return false;
+    };
+    if mode == 0o120000 {
+        fs::read_link(target)
+            .map(|link| link.as_os_str().as_encoded_bytes() == blob.content())
+            .unwrap_or(false)
+    } else {
+        fs::symlink_metadata(target)
+            .map(|metadata| metadata.is_file())
+            .unwrap_or(false)
+            && fs::read(target)
+                .map(|content| content == blob.content())
+                .unwrap_or(false)
+    }
+}
+
+// A file standing where one of the path's parent directories should be
+fn blocked_by_file(workdir: &Path, target: &Path) -> bool {
+    target
+        .ancestors()
+        .skip(1)
+        .take_while(|dir| *dir != workdir)
+        .any(|dir| fs::metadata(dir).map(|m| !m.is_dir()).unwrap_or(false))
+}
+
+// Check out the tracked files into $HOME. Files that already exist with other
+// content are never overwritten, they are reported as conflicts instead.
+pub fn checkout_tracked(repo: &Repository) -> Result<String, git2::Error> {
+    let workdir = repo
+        .workdir()
+        .ok_or_else(|| git2::Error::from_str("Bare repository has no work tree set"))?
+        .to_path_buf();
+    let tree = repo.head()?.peel_to_tree()?;
+
+    let mut missing = Vec::new();
+    let mut conflicts = Vec::new();
+    let mut current = 0;
+    tree.walk(TreeWalkMode::PreOrder, |root, entry| {
+        // Submodules are checked out by the submodule update
+        if entry.kind() != Some(ObjectType::Blob) {
+            return TreeWalkResult::Ok;
+        }
+        let path = format!("{}{}", root, entry.name().unwrap_or(""));
+        let target = workdir.join(&path);
+        if fs::symlink_metadata(&target).is_err() {
+            if blocked_by_file(&workdir, &target) {
+                conflicts.push(path);
+            } else {
+                missing.push(path);
+            }
+        } else if same_content(repo, &target, entry.id(), entry.filemode()) {
+            current += 1;
+        } else {
+            conflicts.push(path);
+        }
+        TreeWalkResult::Ok
+    })?;
+
+    let mut index = repo.index()?;
+    index.read_tree(&tree)?;
+    index.write()?;
+    if !missing.is_empty() {
+        let mut checkout = CheckoutBuilder::new();
+        checkout.force();
+        for path in &missing {
+            checkout.path(path);
+        }
+        repo.checkout_index(Some(&mut index), Some(&mut checkout))?;
+    }
+    set_unlinked(repo, false)?;
+
+    let mut report = format!(
+        "Checked out {} files into {}, {} already up to date.",
+        missing.len(),
+        workdir.display(),
+        current
+    );
+    if !conflicts.is_empty() {
+        report.push_str("\n\nConflicts, these existing files were left untouched:");
+        for path in &conflicts {
+            report.push_str(&format!("\n- {}", path));
+        }
+        report.push_str(
+            "\n\nMove them aside and link again to use the tracked version, \
+             or commit them to keep yours.",
+        );
+    }
+    Ok(report)
+}
+
+// Remove the tracked files from $HOME, keeping the ones with local edits
+pub fn remove_tracked(repo: &Repository) -> Result<String, git2::Error> {
+    let workdir = repo
+        .workdir()
+        .ok_or_else(|| git2::Error::from_str("Bare repository has no work tree set"))?
+        .to_path_buf();
+    let paths: Vec<String> = repo
+        .index()?
+        .iter()
+        .map(|entry| String::from_utf8_lossy(&entry.path).to_string())
+        .collect();
+
+    let mut removed = 0;
+    let mut kept = Vec::new();
+    for path in paths {
+        let target = workdir.join(&path);
+        if fs::symlink_metadata(&target).is_err() {
+            continue;
+        }
+        if repo.status_file(Path::new(&path))? == Status::CURRENT {
+            if fs::remove_file(&target).is_ok() {
+                removed += 1;
+                sparse::remove_empty_parents(&target, &workdir);
+            }
+        } else {
+            kept.push(path);
+        }
+    }
+    set_unlinked(repo, true)?;
+
+    let mut report = format!(
+        "Removed {} tracked files from {}.",
+        removed,
+        workdir.display()
+    );
+    if !kept.is_empty() {
+        report.push_str("\n\nKept, these files have local edits:");
+        for path in &kept {
+            report.push_str(&format!("\n- {}", path));
+        }
+    }
+    Ok(report)
+}
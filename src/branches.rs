@@ -0,0 +1,53 @@
+use crate::sparse;
+use git2::{BranchType, Repository};
+
+// Local branches first, then remote branches, with whether each is checked out
+pub fn list_branches(repo: &Repository) -> Result<Vec<(String, BranchType, bool)>, git2::Error> {
+    let mut branches = Vec::new();
+    for branch_type in [BranchType::Local, BranchType::Remote] {
+        for branch in repo.branches(Some(branch_type))? {
+            let (branch, _) = branch?;
+            let Some(name) = branch.name()? else { continue };
+            if name.ends_with("/HEAD") {
+                continue;
+            }
+            branches.push((name.to_string(), branch_type, branch.is_head()));
+        }
+    }
+    Ok(branches)
+}
+
+// Check out a branch, creating a local tracking branch for remote ones.
+// Returns the name of the local branch that is now checked out.
+pub fn checkout_branch(
+    repo: &Repository,
+    name: &str,
+    branch_type: BranchType,
+) -> Result<String, git2::Error> {
+    let local_name = match branch_type {
+        BranchType::Local => name,
+        BranchType::Remote => name.split_once('/').map_or(name, |(_, branch)| branch),
+    };
+
+    let branch = match repo.find_branch(local_name, BranchType::Local) {
+        Ok(branch) => branch,
+        Err(_) => {
+            let commit = repo
+                .find_branch(name, BranchType::Remote)?
+                .get()
+                .peel_to_commit()?;
+            let mut branch = repo.branch(local_name, &commit, false)?;
+            branch.set_upstream(Some(name))?;
+            branch
+        }
+    };
+
+    let reference = branch.get();
+    let refname = reference
+        .name()
+        .ok_or_else(|| git2::Error::from_str("Branch name is not valid UTF-8"))?;
+    let commit = reference.peel_to_commit()?;
+    sparse::checkout_commit(repo, &commit)?;
+    repo.set_head(refname)?;
+    Ok(local_name.to_string())
+}
@@ -0,0 +1,594 @@
+use crate::backup::{self, Progress};
+use crate::bare;
+use crate::compression::{self, Encoder, Settings};
+use crate::config::{self, BackupConfig, Config};
+use crate::encryption::{self, BackupKeys, SessionPassphrase, Sink};
+use crate::layers;
+use crate::manifest::HashingReader;
+use crate::retention;
+use crate::state::{self, State};
+use crate::task::{self, TaskEvent};
+use git2::Repository;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use tar::{Archive, Builder, Header};
+
+// Bundles are named `bundle-<hostname>-<timestamp>.tar.<format>`
+const PREFIX: &str = "bundle-";
+// Always the first member, so a bundle can be described without unpacking it
+const MANIFEST: &str = "manifest.toml";
+const CONFIG: &str = "config.toml";
+const DOTFILES: &str = "dotfiles.bundle";
+// A bundle is put together in `<bundle>.staging` and unpacked next to itself
+// into `<bundle>.import`
+const STAGING: &str = ".staging";
+const IMPORT: &str = ".import";
+
+// Packages the exporting machine installs and links
+#[derive(Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Packages {
+    pub system: Vec<String>,
+    pub aur: Vec<String>,
+    pub stow: Vec<String>,
+}
+
+// What a bundle holds, with the SHA-256 of every other member
+#[derive(Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct BundleManifest {
+    pub created: String,
+    pub host: String,
+    pub repo_url: String,
+    pub bare: bool,
+    // Layer names, each bundled as `layers/<name>.bundle`
+    pub layers: Vec<String>,
+    pub packages: Packages,
+    // Backup profile names and the archive of each
+    pub backups: BTreeMap<String, String>,
+    pub files: BTreeMap<String, String>,
+}
+
+// Everything an export needs from the config and the UI, owned so it can
+// move into a background task
+pub struct Contents {
+    pub dotfiles: PathBuf,
+    pub repo_url: String,
+    pub bare: bool,
+    pub layers: Vec<(String, PathBuf)>,
+    pub packages: Packages,
+    pub backups: BackupConfig,
+}
+
+// Next to the backup archives, so a bundle lands on the same drive
+pub fn bundles_dir(config: &BackupConfig) -> PathBuf {
+    backup::backup_dir(config).join("bundles")
+}
+
+// Bundles from any host, newest first
+pub fn list_bundles(config: &BackupConfig) -> Vec<PathBuf> {
+    let Ok(entries) = fs::read_dir(bundles_dir(config)) else {
+        return Vec::new();
+    };
+    let mut bundles: Vec<PathBuf> = entries
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            path.is_file()
+                && name.starts_with(PREFIX)
+                && compression::SUFFIXES
+                    .iter()
+                    .any(|suffix| name.ends_with(suffix))
+        })
+        .collect();
+    bundles.sort_by_key(|path| {
+        std::cmp::Reverse(
+            fs::metadata(path)
+                .and_then(|metadata| metadata.modified())
+                .ok(),
+        )
+    });
+    bundles
+}
+
+fn run(command: &mut Command) -> Result<(), String> {
+    let output = command
+        .output()
+        .map_err(|e| format!("Failed to run git: {}", e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "git failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+// Every branch and tag of a repository in a single file
+fn git_bundle(repo: &Path, dest: &Path) -> Result<(), String> {
+    run(Command::new("git")
+        .arg("-C")
+        .arg(repo)
+        .args(["bundle", "create"])
+        .arg(dest)
+        .arg("--all"))
+    .map_err(|e| format!("Failed to bundle {}: {}", repo.display(), e))
+}
+
+fn hash_file(path: &Path) -> io::Result<String> {
+    let mut reader = HashingReader::new(File::open(path)?);
+    io::copy(&mut reader, &mut io::sink())?;
+    Ok(reader.finish())
+}
+
+// Write the members of a bundle into `staging`, returning its manifest and
+// notes about what was left out
+fn stage(
+    contents: &Contents,
+    home: &Path,
+    keys: &BackupKeys,
+    settings: &Settings,
+    staging: &Path,
+    progress: &mut Progress,
+) -> Result<(BundleManifest, Vec<String>), String> {
+    let mut manifest = BundleManifest {
+        created: chrono::Local::now().format("%Y-%m-%d %H:%M").to_string(),
+        host: state::hostname(),
+        repo_url: contents.repo_url.clone(),
+        bare: contents.bare,
+        packages: contents.packages.clone(),
+        ..BundleManifest::default()
+    };
+    let mut members = vec![String::from(DOTFILES)];
+    let mut notes = Vec::new();
+    let create_dir = |dir: &Path| {
+        fs::create_dir_all(dir).map_err(|e| format!("Failed to create {}: {}", dir.display(), e))
+    };
+    create_dir(&staging.join("layers"))?;
+    create_dir(&staging.join("backups"))?;
+
+    progress.stage("Bundling the dotfiles repository...");
+    git_bundle(&contents.dotfiles, &staging.join(DOTFILES))?;
+    for (name, path) in &contents.layers {
+        if !path.exists() {
+            notes.push(format!("Layer {} is not cloned, left out.", name));
+            continue;
+        }
+        let member = format!("layers/{}.bundle", name);
+        git_bundle(path, &staging.join(&member))?;
+        manifest.layers.push(name.clone());
+        members.push(member);
+    }
+    let config_file = config::config_dir().join(CONFIG);
+    if config_file.exists() {
+        fs::copy(&config_file, staging.join(CONFIG))
+            .map_err(|e| format!("Failed to copy {}: {}", config_file.display(), e))?;
+        members.push(String::from(CONFIG));
+    }
+
+    let mut profiles = Vec::new();
+    let mut total = 0;
+    for profile in &contents.backups.profiles {
+        let sources = backup::profile_sources(profile, home)
+            .and_then(|sources| Ok((backup::source_size(&sources, home)?, sources)));
+        match sources {
+            Ok((size, sources)) => {
+                total += size;
+                profiles.push((profile, sources));
+            }
+            Err(e) => notes.push(format!("Backup {} left out: {}", profile.name, e)),
+        }
+    }
+    backup::check_free_space(staging, total)?;
+    progress.start(total);
+    for (profile, sources) in profiles {
+        let encryptor = encryption::archive_encryptor(&contents.backups.encryption, keys)?;
+        let member = format!(
+            "backups/{}{}{}",
+            profile.name,
+            settings.format.suffix(),
+            if encryptor.is_some() {
+                encryption::EXTENSION
+            } else {
+                ""
+            }
+        );
+        backup::create_archive(
+            &sources,
+            home,
+            &staging.join(&member),
+            settings,
+            encryptor,
+            progress,
+        )
+        .map_err(|e| format!("Failed to back up {}: {}", profile.name, e))?;
+        manifest
+            .backups
+            .insert(profile.name.clone(), member.clone());
+        members.push(member);
+    }
+
+    progress.stage("Writing the bundle...");
+    for member in members {
+        let sha256 = hash_file(&staging.join(&member))
+            .map_err(|e| format!("Failed to read {}: {}", member, e))?;
+        manifest.files.insert(member, sha256);
+    }
+    Ok((manifest, notes))
+}
+
+fn write_bundle(
+    staging: &Path,
+    manifest: &BundleManifest,
+    dest: &Path,
+    settings: &Settings,
+) -> io::Result<()> {
+    let encoder = Encoder::new(Sink::new(File::create(dest)?, None)?, settings)?;
+    let mut tar = Builder::new(encoder);
+    let content = toml::to_string(manifest).map_err(io::Error::other)?;
+    let mut header = Header::new_gnu();
+    header.set_size(content.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(chrono::Utc::now().timestamp().max(0) as u64);
+    header.set_cksum();
+    tar.append_data(&mut header, MANIFEST, content.as_bytes())?;
+    for member in manifest.files.keys() {
+        tar.append_path_with_name(staging.join(member), member)?;
+    }
+    tar.into_inner()?.finish()?.finish()?.sync_all()
+}
+
+// Bundle the dotfiles repository and its layers, fresh archives of every
+// backup profile, the config and the package lists into one file that can
+// bootstrap a machine without network access
+pub fn export(
+    contents: &Contents,
+    home: &Path,
+    keys: &BackupKeys,
+    progress: &mut Progress,
+) -> Result<String, String> {
+    if !contents.dotfiles.exists() {
+        return Err(format!(
+            "No dotfiles repository at {}, clone it first.",
+            contents.dotfiles.display()
+        ));
+    }
+    let settings = Settings::new(&contents.backups.compression)?;
+    let dir = bundles_dir(&contents.backups);
+    fs::create_dir_all(&dir).map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+    let bundle = dir.join(format!(
+        "{}{}-{}{}",
+        PREFIX,
+        state::hostname(),
+        chrono::Local::now().format(retention::TIMESTAMP_FORMAT),
+        settings.format.suffix()
+    ));
+    let partial = backup::partial_path(&bundle);
+    let staging = with_suffix(&bundle, STAGING);
+    let _ = fs::remove_dir_all(&staging);
+
+    let written =
+        stage(contents, home, keys, &settings, &staging, progress).and_then(|(manifest, notes)| {
+            write_bundle(&staging, &manifest, &partial, &settings)
+                .and_then(|_| backup::commit_partial(&partial, &bundle))
+                .map_err(|e| format!("Failed to write {}: {}", bundle.display(), e))?;
+            Ok((manifest, notes))
+        });
+    let _ = fs::remove_dir_all(&staging);
+    let (manifest, notes) = match written {
+        Ok(written) => written,
+        Err(e) => {
+            let _ = fs::remove_file(&partial);
+            if progress.is_cancelled() {
+                return Err(String::from("Cancelled, the partial bundle was deleted."));
+            }
+            return Err(e);
+        }
+    };
+
+    let size = fs::metadata(&bundle)
+        .map(|metadata| metadata.len())
+        .unwrap_or(0);
+    let mut report = format!(
+        "{} ({})\n\n{}",
+        bundle.display(),
+        crate::remote::format_bytes(size as usize),
+        describe(&manifest)
+    );
+    for note in notes {
+        report.push_str(&format!("\n{}", note));
+    }
+    Ok(report)
+}
+
+// The manifest of a bundle, reading only its first member
+pub fn read_manifest(bundle: &Path) -> Result<BundleManifest, String> {
+    let invalid = |e: String| format!("{} is not a readable bundle: {}", bundle.display(), e);
+    let reader = File::open(bundle)
+        .and_then(|file| compression::decoder(Box::new(file)))
+        .map_err(|e| invalid(e.to_string()))?;
+    let mut archive = Archive::new(reader);
+    let mut entry = archive
+        .entries()
+        .and_then(|mut entries| {
+            entries
+                .next()
+                .unwrap_or_else(|| Err(io::Error::other("empty")))
+        })
+        .map_err(|e| invalid(e.to_string()))?;
+    if entry.path().map_err(|e| invalid(e.to_string()))? != Path::new(MANIFEST) {
+        return Err(invalid(format!("{} is not its first member", MANIFEST)));
+    }
+    let mut content = String::new();
+    entry
+        .read_to_string(&mut content)
+        .map_err(|e| invalid(e.to_string()))?;
+    toml::from_str(&content).map_err(|e| invalid(e.to_string()))
+}
+
+pub fn describe(manifest: &BundleManifest) -> String {
+    let list = |items: &[String]| {
+        if items.is_empty() {
+            String::from("none")
+        } else {
+            items.join(", ")
+        }
+    };
+    let backups: Vec<String> = manifest.backups.keys().cloned().collect();
+    format!(
+        "Created {} on {}\nDotfiles: {}{}\nLayers: {}\nConfig: {}\nPackages: {}\nAUR packages: {}\nStow packages: {}\nBackups: {}",
+        manifest.created,
+        manifest.host,
+        manifest.repo_url,
+        if manifest.bare { " (bare)" } else { "" },
+        list(&manifest.layers),
+        if manifest.files.contains_key(CONFIG) {
+            "included"
+        } else {
+            "not included"
+        },
+        list(&manifest.packages.system),
+        list(&manifest.packages.aur),
+        list(&manifest.packages.stow),
+        list(&backups)
+    )
+}
+
+// Whether a backup in the bundle is encrypted with a passphrase the given
+// one does not open, read straight from the bundle so the import can ask for
+// it before unpacking anything
+pub fn locked(
+    bundle: &Path,
+    manifest: &BundleManifest,
+    passphrase: Option<&SessionPassphrase>,
+) -> Result<bool, String> {
+    let mut archives: Vec<&Path> = manifest.backups.values().map(Path::new).collect();
+    if archives.is_empty() {
+        return Ok(false);
+    }
+    let failed = |e: io::Error| format!("Failed to read {}: {}", bundle.display(), e);
+    let reader = File::open(bundle)
+        .and_then(|file| compression::decoder(Box::new(file)))
+        .map_err(failed)?;
+    let mut archive = Archive::new(reader);
+    for entry in archive.entries().map_err(failed)? {
+        let entry = entry.map_err(failed)?;
+        let path = entry.path().map_err(failed)?.into_owned();
+        let Some(position) = archives.iter().position(|archive| *archive == path) else {
+            continue;
+        };
+        if encryption::locked(BufReader::new(entry), passphrase).map_err(failed)? {
+            return Ok(true);
+        }
+        // Backups come right after the manifest, the rest need not be read
+        archives.remove(position);
+        if archives.is_empty() {
+            break;
+        }
+    }
+    Ok(false)
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+pub fn import_dir(bundle: &Path) -> PathBuf {
+    with_suffix(bundle, IMPORT)
+}
+
+// Unpack a bundle into its import directory and check every member against
+// the manifest. The directory stays until the caller removes it.
+pub fn unpack(bundle: &Path) -> Result<(PathBuf, BundleManifest), String> {
+    let dir = import_dir(bundle);
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+    File::open(bundle)
+        .and_then(|file| compression::decoder(Box::new(file)))
+        .and_then(|reader| Archive::new(reader).unpack(&dir))
+        .map_err(|e| format!("Failed to unpack {}: {}", bundle.display(), e))?;
+    let content = fs::read_to_string(dir.join(MANIFEST))
+        .map_err(|e| format!("Failed to read the bundle manifest: {}", e))?;
+    let manifest: BundleManifest =
+        toml::from_str(&content).map_err(|e| format!("Invalid bundle manifest: {}", e))?;
+    for (member, sha256) in &manifest.files {
+        if !backup::stays_inside(Path::new(member)) {
+            return Err(format!("Refusing to import {}: unsafe path", member));
+        }
+        match hash_file(&dir.join(member)) {
+            Ok(found) if found == *sha256 => {}
+            Ok(_) => return Err(format!("{} does not match its checksum", member)),
+            Err(e) => return Err(format!("Failed to read {}: {}", member, e)),
+        }
+    }
+    Ok((dir, manifest))
+}
+
+// The config of the bundle, installed unless this machine has one already
+pub fn install_config(dir: &Path) -> Result<String, String> {
+    let bundled = dir.join(CONFIG);
+    let target = config::config_dir().join(CONFIG);
+    if !bundled.exists() {
+        return Ok(String::from("Config: not included in the bundle."));
+    }
+    if target.exists() {
+        return Ok(format!("Config: kept the existing {}.", target.display()));
+    }
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)
+            .map_err(|e| format!("Failed to create {}: {}", parent.display(), e))?;
+    }
+    fs::copy(&bundled, &target)
+        .map_err(|e| format!("Failed to install {}: {}", target.display(), e))?;
+    Ok(format!("Config: installed {}.", target.display()))
+}
+
+// Clone a repository from its bundle, pointing origin back at `url` so
+// syncing works once the machine is online
+pub fn clone(bundle: &Path, path: &Path, url: &str, bare: bool) -> String {
+    if path.exists() {
+        return format!("{} exists already, not cloned.", path.display());
+    }
+    let mut command = Command::new("git");
+    command.arg("clone");
+    if bare {
+        command.arg("--bare");
+    }
+    if let Err(e) = run(command.arg(bundle).arg(path)) {
+        return format!("Failed to clone {}: {}", path.display(), e);
+    }
+    let configured = Repository::open(path).and_then(|repo| {
+        repo.remote_set_url("origin", url)?;
+        if bare {
+            bare::configure(&repo)?;
+        }
+        Ok(())
+    });
+    match configured {
+        Ok(()) => format!("Cloned {} from the bundle.", path.display()),
+        Err(e) => format!(
+            "Cloned {} from the bundle, but failed to configure it: {}",
+            path.display(),
+            e
+        ),
+    }
+}
+
+// Set up this machine from a bundle without network access: install its
+// config, clone the repositories and restore the backups, removing the
+// unpacked bundle again on every path. Runs as a background task and sets
+// `unpacked` once the bundle checked out and fits the config, linking the
+// packages is left to the caller.
+pub fn import(
+    bundle: &Path,
+    dotfiles: &Path,
+    home: &Path,
+    passphrase: Option<&SessionPassphrase>,
+    unpacked: &AtomicBool,
+    sender: &Sender<TaskEvent>,
+) -> Result<String, String> {
+    task::progress(sender, String::from("Unpacking the bundle..."), 0, 4);
+    let (dir, manifest) = match unpack(bundle) {
+        Ok(unpacked) => unpacked,
+        Err(e) => {
+            let _ = fs::remove_dir_all(import_dir(bundle));
+            return Err(e);
+        }
+    };
+
+    task::progress(sender, String::from("Installing the config..."), 1, 4);
+    let mut report = vec![match install_config(&dir) {
+        Ok(report) => report,
+        Err(e) => e,
+    }];
+    let config = match config::load() {
+        // The repository is cloned the way it was exported, which an existing
+        // config on this machine has to agree with
+        Ok(config) if config.bare != manifest.bare => {
+            let _ = fs::remove_dir_all(&dir);
+            return Err(format!(
+                "The bundle was exported with bare = {} but the config here sets \
+                 bare = {}. Change the config or remove it to import the bundle.",
+                manifest.bare, config.bare
+            ));
+        }
+        Ok(config) => config,
+        Err(e) => {
+            report.push(format!("{}\nUsing the default configuration.", e));
+            Config::default()
+        }
+    };
+    unpacked.store(true, Ordering::Relaxed);
+
+    task::progress(sender, String::from("Cloning the repositories..."), 2, 4);
+    report.push(clone(
+        &dir.join(DOTFILES),
+        dotfiles,
+        &manifest.repo_url,
+        manifest.bare,
+    ));
+    for layer in &config.layers {
+        if manifest.layers.contains(&layer.name) {
+            report.push(clone(
+                &dir.join(format!("layers/{}.bundle", layer.name)),
+                &layers::layer_path(layer),
+                &layer.url,
+                false,
+            ));
+        }
+    }
+
+    let mut state = State::load();
+    if state.packages_for_host().is_none() && !manifest.packages.stow.is_empty() {
+        state
+            .packages
+            .insert(state::hostname(), manifest.packages.stow.clone());
+        if let Err(e) = state.save() {
+            report.push(e);
+        }
+    }
+
+    task::progress(sender, String::from("Restoring the backups..."), 3, 4);
+    let keys = BackupKeys {
+        config: &config.encryption,
+        passphrase,
+    };
+    for (name, archive) in &manifest.backups {
+        let Some(profile) = config
+            .backups
+            .profiles
+            .iter()
+            .find(|profile| profile.name == *name)
+        else {
+            report.push(format!(
+                "Backup {}: no profile with that name, skipped.",
+                name
+            ));
+            continue;
+        };
+        report.push(
+            match backup::restore(&dir.join(archive), profile, home, &keys) {
+                Ok(restored) => format!("Backup {}: {}", name, restored),
+                Err(e) => format!("Backup {}: {}", name, e),
+            },
+        );
+    }
+    let _ = fs::remove_dir_all(&dir);
+
+    let mut packages = manifest.packages.system.clone();
+    packages.extend(manifest.packages.aur.iter().cloned());
+    report.push(format!(
+        "Packages to install from a local mirror or cache: {}",
+        packages.join(" ")
+    ));
+    Ok(report.join("\n\n"))
+}
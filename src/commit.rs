@@ -0,0 +1,269 @@
+use crate::config::{self, Config};
+use crate::encryption;
+use crate::history;
+use crate::remote;
+use crate::secrets;
+use crate::state;
+use crate::sync;
+use git2::{IndexAddOption, Repository};
+use std::fs;
+use std::path::Path;
+
+// Stage every change in the checked out packages, i.e. `git add -A` limited
+// to the sparse set when there is one
+fn stage_changes(repo: &Repository, bare: bool) -> Result<(), git2::Error> {
+    let pathspecs = sync::sparse_pathspecs(repo);
+    let mut index = repo.index()?;
+    // The work tree of a bare repository is $HOME, only tracked files count
+    if !bare {
+        index.add_all(pathspecs.iter(), IndexAddOption::DEFAULT, None)?;
+    }
+    index.update_all(pathspecs.iter(), None)?;
+    index.write()
+}
+
+// Put back the index file saved before staging, so a failed commit leaves
+// nothing staged for a later `git commit` in a shell to pick up
+fn restore_index(repo: &Repository, saved: Option<Vec<u8>>) -> Result<(), String> {
+    let path = repo.path().join("index");
+    match saved {
+        Some(bytes) => fs::write(&path, bytes),
+        None => fs::remove_file(&path),
+    }
+    .map_err(|e| format!("Failed to restore the index: {}", e))?;
+    repo.index()
+        .and_then(|mut index| index.read(true))
+        .map_err(|e| format!("Failed to reload the index: {}", e))
+}
+
+fn has_staged_changes(repo: &Repository) -> Result<bool, git2::Error> {
+    let head_tree = repo.head()?.peel_to_tree()?;
+    let index = repo.index()?;
+    let diff = repo.diff_tree_to_index(Some(&head_tree), Some(&index), None)?;
+    Ok(diff.deltas().len() > 0)
+}
+
+// Refuse staged changes that contain decrypted copies of encrypted files or
+// secrets that are not allowed
+fn check_staged(repo: &Repository) -> Result<(), String> {
+    let staged: Vec<String> = repo
+        .index()
+        .map_err(|e| format!("Failed to read index: {}", e))?
+        .iter()
+        .map(|entry| String::from_utf8_lossy(&entry.path).to_string())
+        .collect();
+    let plaintext = encryption::plaintext_copies(&staged);
+    if !plaintext.is_empty() {
+        let mut blocked =
+            String::from("Commit blocked: decrypted copies of encrypted files are staged:");
+        for path in plaintext {
+            blocked.push_str(&format!("\n- {}", path));
+        }
+        blocked
+            .push_str("\n\nDelete them from the repository, only the .age files may be committed.");
+        return Err(blocked);
+    }
+    let allowed = secrets::allowed_fingerprints(repo);
+    let findings: Vec<secrets::Finding> = secrets::scan_staged(repo)
+        .map_err(|e| format!("Failed to scan staged changes: {}", e))?
+        .into_iter()
+        .filter(|finding| !allowed.contains(&finding.fingerprint))
+        .collect();
+    if !findings.is_empty() {
+        let mut blocked = format!(
+            "Commit blocked: {} possible secrets in the staged changes:",
+            findings.len()
+        );
+        for finding in &findings {
+            blocked.push_str(&format!(
+                "\n- {}:{} {} ({}) [{}]",
+                finding.path,
+                finding.line,
+                finding.rule,
+                finding.redacted(),
+                finding.fingerprint
+            ));
+        }
+        blocked.push_str(&format!(
+            "\n\nRemove them, or add the fingerprints of false positives to {} \
+             in the repository root to allow them.",
+            secrets::IGNORE_FILE
+        ));
+        return Err(blocked);
+    }
+    Ok(())
+}
+
+// Stage local changes, refuse to commit while they contain secrets that are
+// not allowed, then commit and push the current branch to origin
+pub fn commit_and_push(repo: &Repository, config: &Config) -> Result<String, String> {
+    let workdir = repo
+        .workdir()
+        .ok_or_else(|| String::from("The dotfiles repository has no working tree"))?;
+
+    let saved = fs::read(repo.path().join("index")).ok();
+    let (report, branch) = match stage_and_commit(repo, config, workdir) {
+        Ok(committed) => committed,
+        Err(e) => {
+            return Err(match restore_index(repo, saved) {
+                Ok(()) => e,
+                Err(restore) => format!("{}\n\n{}", e, restore),
+            })
+        }
+    };
+    let Some(branch) = branch else {
+        return Ok(report);
+    };
+    match remote::push_branch(repo, &branch, config.ssh_key.as_deref()) {
+        Ok(()) => Ok(format!("{}\nPushed {} to origin.", report, branch)),
+        Err(e) => Err(format!("{}\n{}", report, e)),
+    }
+}
+
+// Everything up to the commit. Any error leaves the index to be put back as
+// it was. Returns the report and the branch that got a new commit, if any.
+fn stage_and_commit(
+    repo: &Repository,
+    config: &Config,
+    workdir: &Path,
+) -> Result<(String, Option<String>), String> {
+    stage_changes(repo, config.bare).map_err(|e| format!("Failed to stage changes: {}", e))?;
+    check_staged(repo)?;
+
+    // Only touch the encrypted files once the commit is known to go ahead
+    let reencrypted = if config.bare {
+        Vec::new()
+    } else {
+        encryption::reencrypt(workdir, &config::expand_home("~/"), &config.encryption)?
+    };
+    let mut report = String::new();
+    let mut index = repo
+        .index()
+        .map_err(|e| format!("Failed to read index: {}", e))?;
+    for file in &reencrypted {
+        index
+            .add_path(Path::new(file))
+            .map_err(|e| format!("Failed to stage {}: {}", file, e))?;
+        report.push_str(&format!("Re-encrypted {}\n", file));
+    }
+    index
+        .write()
+        .map_err(|e| format!("Failed to write index: {}", e))?;
+
+    if !has_staged_changes(repo).map_err(|e| format!("Failed to read changes: {}", e))? {
+        report.push_str("Nothing to commit, the dotfiles are up to date.");
+        return Ok((report, None));
+    }
+
+    let head = repo
+        .head()
+        .map_err(|e| format!("Failed to get HEAD: {}", e))?;
+    let branch = head
+        .shorthand()
+        .filter(|_| head.is_branch())
+        .ok_or_else(|| String::from("HEAD is detached, switch to a branch before committing"))?
+        .to_string();
+    let parent = head
+        .peel_to_commit()
+        .map_err(|e| format!("Failed to get HEAD commit: {}", e))?;
+
+    let tree = index
+        .write_tree()
+        .and_then(|oid| repo.find_tree(oid))
+        .map_err(|e| format!("Failed to write tree: {}", e))?;
+    let signature = repo
+        .signature()
+        .map_err(|e| format!("Failed to create signature: {}", e))?;
+    let message = format!("Update dotfiles from {}", state::hostname());
+    let oid = repo
+        .commit(
+            Some("HEAD"),
+            &signature,
+            &signature,
+            &message,
+            &tree,
+            &[&parent],
+        )
+        .map_err(|e| format!("Failed to commit: {}", e))?;
+
+    report.push_str(&format!(
+        "Committed {} \"{}\".",
+        history::short_id(oid),
+        message
+    ));
+    Ok((report, Some(branch)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use git2::Status;
+
+    fn repo() -> (tempfile::TempDir, Repository) {
+        let (dir, repo) = testing::repo();
+        testing::stage(&repo, "git/.gitconfig", "[user]\n");
+        testing::commit(&repo, "Add git");
+        testing::write(&repo, "git/.gitconfig", "[user]\n\tname = Test\n");
+        (dir, repo)
+    }
+
+    // An encrypted file next to the change, with an identity that is missing
+    fn encrypted(repo: &Repository) -> Config {
+        testing::stage(repo, "git/.token.age", "ciphertext");
+        testing::commit(repo, "Add token");
+        let mut config = Config::default();
+        let identity = repo.path().join("missing-identity.txt");
+        config.encryption.identity = Some(identity.to_string_lossy().to_string());
+        config
+    }
+
+    fn status(repo: &Repository, path: &str) -> Status {
+        repo.status_file(Path::new(path)).unwrap()
+    }
+
+    #[test]
+    fn leaves_nothing_staged_when_blocked() {
+        let (_dir, repo) = repo();
+        let config = encrypted(&repo);
+        testing::write(&repo, "git/.token", "plaintext");
+
+        let error = commit_and_push(&repo, &config).unwrap_err();
+
+        assert!(error.contains("decrypted copies of encrypted files"));
+        assert_eq!(status(&repo, "git/.gitconfig"), Status::WT_MODIFIED);
+        assert_eq!(status(&repo, "git/.token"), Status::WT_NEW);
+    }
+
+    #[test]
+    fn leaves_nothing_staged_when_reencrypting_fails() {
+        let (_dir, repo) = repo();
+        let config = encrypted(&repo);
+
+        let error = commit_and_push(&repo, &config).unwrap_err();
+
+        assert!(error.contains("Failed to read age identity"));
+        assert_eq!(status(&repo, "git/.gitconfig"), Status::WT_MODIFIED);
+    }
+
+    #[test]
+    fn leaves_nothing_staged_when_the_commit_fails() {
+        let (_dir, repo) = repo();
+        let head = repo.head().unwrap().target().unwrap();
+        repo.set_head_detached(head).unwrap();
+
+        let error = commit_and_push(&repo, &Config::default()).unwrap_err();
+
+        assert!(error.contains("HEAD is detached"));
+        assert_eq!(status(&repo, "git/.gitconfig"), Status::WT_MODIFIED);
+        assert_eq!(repo.head().unwrap().target(), Some(head));
+    }
+
+    #[test]
+    fn reports_nothing_to_commit() {
+        let (_dir, repo) = repo();
+        testing::write(&repo, "git/.gitconfig", "[user]\n");
+        let report = commit_and_push(&repo, &Config::default()).unwrap();
+        assert_eq!(report, "Nothing to commit, the dotfiles are up to date.");
+    }
+}
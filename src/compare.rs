@@ -0,0 +1,222 @@
+use crate::backup;
+use crate::config::BackupProfile;
+use crate::encryption::BackupKeys;
+use crate::manifest::{self, Manifest, ManifestEntry};
+use crate::remote;
+use crate::store;
+use similar::TextDiff;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::Path;
+
+// Modified files up to this size on both sides get a text diff
+const DIFF_LIMIT: u64 = 64 * 1024;
+
+// One side of a comparison: a backup of the profile or its files as they
+// are now
+pub enum Side<'a> {
+    Archive(&'a Path),
+    Snapshot(&'a Path),
+    Live,
+}
+
+impl Side<'_> {
+    fn name(&self) -> String {
+        match self {
+            Side::Archive(path) | Side::Snapshot(path) => path
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .to_string(),
+            Side::Live => String::from("the live files"),
+        }
+    }
+
+    // Archives written with a manifest are not read at all
+    fn manifest(
+        &self,
+        profile: &BackupProfile,
+        home: &Path,
+        keys: &BackupKeys,
+    ) -> Result<Manifest, String> {
+        match self {
+            Side::Archive(path) => match manifest::read(path, keys)? {
+                Some(manifest) => Ok(manifest),
+                None => backup::read_manifest(path, keys)
+                    .map_err(|e| format!("Failed to read {}: {}", path.display(), e)),
+            },
+            Side::Snapshot(path) => store::manifest(path),
+            Side::Live => backup::live_manifest(profile, home),
+        }
+    }
+
+    fn read_files(
+        &self,
+        wanted: &BTreeSet<String>,
+        home: &Path,
+        keys: &BackupKeys,
+    ) -> Result<BTreeMap<String, Vec<u8>>, String> {
+        match self {
+            Side::Archive(path) => backup::read_files(path, keys, wanted)
+                .map_err(|e| format!("Failed to read {}: {}", path.display(), e)),
+            Side::Snapshot(path) => store::read_files(path, wanted),
+            Side::Live => Ok(wanted
+                .iter()
+                .filter_map(|path| Some((path.clone(), fs::read(home.join(path)).ok()?)))
+                .collect()),
+        }
+    }
+}
+
+fn kind_name(entry: &ManifestEntry) -> &'static str {
+    match entry.kind {
+        'd' => "directory",
+        'l' => "symlink",
+        _ => "file",
+    }
+}
+
+// Text that a diff can be shown for
+fn as_text(content: &[u8]) -> Option<&str> {
+    if content.contains(&0) {
+        return None;
+    }
+    std::str::from_utf8(content).ok()
+}
+
+fn push_limited(report: &mut String, title: &str, lines: &[String]) {
+    if lines.is_empty() {
+        return;
+    }
+    report.push_str(&format!("\n\n{}:", title));
+    for line in lines.iter().take(backup::SUMMARY_LIMIT) {
+        report.push_str(&format!("\n{}", line));
+    }
+    if lines.len() > backup::SUMMARY_LIMIT {
+        report.push_str(&format!(
+            "\n... and {} more",
+            lines.len() - backup::SUMMARY_LIMIT
+        ));
+    }
+}
+
+// What changed from one side to the other: paths added and removed, files
+// whose checksum differs, and text diffs for the small text files among them
+pub fn compare(
+    from: &Side,
+    to: &Side,
+    profile: &BackupProfile,
+    home: &Path,
+    keys: &BackupKeys,
+) -> Result<String, String> {
+    let old = from.manifest(profile, home, keys)?;
+    let new = to.manifest(profile, home, keys)?;
+
+    let mut added = Vec::new();
+    let mut removed = Vec::new();
+    let mut modified = Vec::new();
+    let mut unchanged = 0;
+    for (path, entry) in &new {
+        match old.get(path) {
+            None if entry.kind == 'd' => added.push(format!("+ {}/", path)),
+            None => added.push(format!(
+                "+ {} ({})",
+                path,
+                remote::format_bytes(entry.size as usize)
+            )),
+            Some(before) if before.kind != entry.kind || before.sha256 != entry.sha256 => {
+                modified.push((path, before, entry))
+            }
+            Some(_) => unchanged += 1,
+        }
+    }
+    for (path, entry) in &old {
+        if !new.contains_key(path) {
+            removed.push(match entry.kind {
+                'd' => format!("- {}/", path),
+                _ => format!("- {}", path),
+            });
+        }
+    }
+
+    let mut report = format!(
+        "Comparing {} with {}\n{} added, {} removed, {} modified, {} unchanged",
+        from.name(),
+        to.name(),
+        added.len(),
+        removed.len(),
+        modified.len(),
+        unchanged
+    );
+    if added.is_empty() && removed.is_empty() && modified.is_empty() {
+        report.push_str("\n\nNo differences.");
+        return Ok(report);
+    }
+
+    let diffable: BTreeSet<String> = modified
+        .iter()
+        .take(backup::SUMMARY_LIMIT)
+        .filter(|(_, before, after)| {
+            before.kind == 'f'
+                && after.kind == 'f'
+                && before.size <= DIFF_LIMIT
+                && after.size <= DIFF_LIMIT
+        })
+        .map(|(path, _, _)| path.to_string())
+        .collect();
+    let (old_files, new_files) = if diffable.is_empty() {
+        (BTreeMap::new(), BTreeMap::new())
+    } else {
+        (
+            from.read_files(&diffable, home, keys)?,
+            to.read_files(&diffable, home, keys)?,
+        )
+    };
+
+    let mut diffs = Vec::new();
+    let modified: Vec<String> = modified
+        .iter()
+        .map(|(path, before, after)| {
+            let line = if before.kind != after.kind {
+                format!("~ {} ({} -> {})", path, kind_name(before), kind_name(after))
+            } else {
+                format!(
+                    "~ {} ({} -> {})",
+                    path,
+                    remote::format_bytes(before.size as usize),
+                    remote::format_bytes(after.size as usize)
+                )
+            };
+            if before.kind != 'f' || after.kind != 'f' {
+                return line;
+            }
+            if !diffable.contains(*path) {
+                return format!("{}, too large to diff", line);
+            }
+            let texts = old_files
+                .get(*path)
+                .and_then(|content| as_text(content))
+                .zip(new_files.get(*path).and_then(|content| as_text(content)));
+            match texts {
+                Some((old_text, new_text)) => {
+                    diffs.push(
+                        TextDiff::from_lines(old_text, new_text)
+                            .unified_diff()
+                            .header(&format!("a/{}", path), &format!("b/{}", path))
+                            .to_string(),
+                    );
+                    line
+                }
+                None => format!("{}, binary", line),
+            }
+        })
+        .collect();
+
+    push_limited(&mut report, "Added", &added);
+    push_limited(&mut report, "Removed", &removed);
+    push_limited(&mut report, "Modified", &modified);
+    if !diffs.is_empty() {
+        report.push_str(&format!("\n\nDiffs:\n{}", diffs.join("\n")));
+    }
+    Ok(report)
+}
@@ -0,0 +1,266 @@
+use crate::config::{CompressionConfig, CompressionFormat};
+use crate::encryption::{self, Sink};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::Path;
+use std::thread;
+use xz2::read::XzDecoder;
+use xz2::stream::MtStreamBuilder;
+use xz2::write::XzEncoder;
+
+// Every suffix archives are written with, plain and encrypted, for listing
+// and pruning
+pub const SUFFIXES: [&str; 6] = [
+    ".tar.gz",
+    ".tar.zst",
+    ".tar.xz",
+    ".tar.gz.age",
+    ".tar.zst.age",
+    ".tar.xz.age",
+];
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+const XZ_MAGIC: &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0x00];
+
+impl CompressionFormat {
+    pub fn name(self) -> &'static str {
+        match self {
+            CompressionFormat::Gzip => "gzip",
+            CompressionFormat::Zstd => "zstd",
+            CompressionFormat::Xz => "xz",
+        }
+    }
+
+    pub fn suffix(self) -> &'static str {
+        match self {
+            CompressionFormat::Gzip => SUFFIXES[0],
+            CompressionFormat::Zstd => SUFFIXES[1],
+            CompressionFormat::Xz => SUFFIXES[2],
+        }
+    }
+
+    fn default_level(self) -> i32 {
+        match self {
+            CompressionFormat::Gzip => 6,
+            CompressionFormat::Zstd => 3,
+            CompressionFormat::Xz => 6,
+        }
+    }
+
+    fn levels(self) -> (i32, i32) {
+        match self {
+            CompressionFormat::Gzip | CompressionFormat::Xz => (0, 9),
+            CompressionFormat::Zstd => (1, 22),
+        }
+    }
+}
+
+// Format, level and threads a backup is written with
+pub struct Settings {
+    pub format: CompressionFormat,
+    pub level: i32,
+    pub threads: u32,
+}
+
+impl Settings {
+    pub fn new(config: &CompressionConfig) -> Result<Self, String> {
+        let format = config.format;
+        let level = config.level.unwrap_or(format.default_level());
+        let (min, max) = format.levels();
+        if level < min || level > max {
+            return Err(format!(
+                "Invalid {} compression level {}, expected {} to {}",
+                format.name(),
+                level,
+                min,
+                max
+            ));
+        }
+        let threads = config.threads.unwrap_or_else(|| {
+            thread::available_parallelism()
+                .map(|threads| threads.get() as u32)
+                .unwrap_or(1)
+        });
+        Ok(Self {
+            format,
+            level,
+            threads: threads.max(1),
+        })
+    }
+
+    pub fn describe(&self) -> String {
+        match self.format {
+            CompressionFormat::Gzip => format!("gzip level {}", self.level),
+            format => format!(
+                "{} level {}, {} thread{}",
+                format.name(),
+                self.level,
+                self.threads,
+                if self.threads == 1 { "" } else { "s" }
+            ),
+        }
+    }
+}
+
+pub enum Encoder {
+    Gzip(GzEncoder<Sink>),
+    Zstd(zstd::Encoder<'static, Sink>),
+    Xz(XzEncoder<Sink>),
+}
+
+impl Encoder {
+    pub fn new(file: Sink, settings: &Settings) -> io::Result<Self> {
+        Ok(match settings.format {
+            CompressionFormat::Gzip => Encoder::Gzip(GzEncoder::new(
+                file,
+                Compression::new(settings.level as u32),
+            )),
+            CompressionFormat::Zstd => {
+                let mut encoder = zstd::Encoder::new(file, settings.level)?;
+                if settings.threads > 1 {
+                    encoder.multithread(settings.threads)?;
+                }
+                Encoder::Zstd(encoder)
+            }
+            CompressionFormat::Xz => {
+                let stream = MtStreamBuilder::new()
+                    .preset(settings.level as u32)
+                    .threads(settings.threads)
+                    .encoder()
+                    .map_err(io::Error::other)?;
+                Encoder::Xz(XzEncoder::new_stream(file, stream))
+            }
+        })
+    }
+
+    // Write the end of the compressed stream and hand back the file
+    pub fn finish(self) -> io::Result<Sink> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.finish(),
+            Encoder::Zstd(encoder) => encoder.finish(),
+            Encoder::Xz(encoder) => encoder.finish(),
+        }
+    }
+}
+
+impl Write for Encoder {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.write(buf),
+            Encoder::Zstd(encoder) => encoder.write(buf),
+            Encoder::Xz(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.flush(),
+            Encoder::Zstd(encoder) => encoder.flush(),
+            Encoder::Xz(encoder) => encoder.flush(),
+        }
+    }
+}
+
+// Format of a compressed stream, going by its first bytes
+fn detect(reader: &mut impl BufRead) -> io::Result<CompressionFormat> {
+    let magic = reader.fill_buf()?;
+    if magic.starts_with(GZIP_MAGIC) {
+        Ok(CompressionFormat::Gzip)
+    } else if magic.starts_with(ZSTD_MAGIC) {
+        Ok(CompressionFormat::Zstd)
+    } else if magic.starts_with(XZ_MAGIC) {
+        Ok(CompressionFormat::Xz)
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a gzip, zstd or xz compressed archive",
+        ))
+    }
+}
+
+// Format of an archive, going by its name
+pub fn from_name(path: &Path) -> Option<CompressionFormat> {
+    let name = path.file_name()?.to_string_lossy().to_string();
+    let name = name.strip_suffix(encryption::EXTENSION).unwrap_or(&name);
+    [
+        CompressionFormat::Gzip,
+        CompressionFormat::Zstd,
+        CompressionFormat::Xz,
+    ]
+    .into_iter()
+    .find(|format| name.ends_with(format.suffix()))
+}
+
+// Decompressing reader for an archive in any supported format
+pub fn decoder(reader: Box<dyn Read>) -> io::Result<Box<dyn Read>> {
+    let mut reader = BufReader::new(reader);
+    Ok(match detect(&mut reader)? {
+        CompressionFormat::Gzip => Box::new(GzDecoder::new(reader)),
+        CompressionFormat::Zstd => Box::new(zstd::Decoder::with_buffer(reader)?),
+        CompressionFormat::Xz => Box::new(XzDecoder::new_multi_decoder(reader)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const CONTENT: &[u8] = b"dotfiles and their backups, compressed three ways";
+
+    fn compressed() -> [(CompressionFormat, Vec<u8>); 3] {
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+        gzip.write_all(CONTENT).unwrap();
+        let mut xz = XzEncoder::new(Vec::new(), 6);
+        xz.write_all(CONTENT).unwrap();
+        [
+            (CompressionFormat::Gzip, gzip.finish().unwrap()),
+            (
+                CompressionFormat::Zstd,
+                zstd::encode_all(CONTENT, 3).unwrap(),
+            ),
+            (CompressionFormat::Xz, xz.finish().unwrap()),
+        ]
+    }
+
+    #[test]
+    fn detects_each_format() {
+        for (format, data) in compressed() {
+            let detected = detect(&mut Cursor::new(&data)).unwrap();
+            assert!(detected == format, "{}", format.name());
+        }
+    }
+
+    #[test]
+    fn decodes_each_format() {
+        for (format, data) in compressed() {
+            let mut decoded = Vec::new();
+            decoder(Box::new(Cursor::new(data)))
+                .and_then(|mut reader| reader.read_to_end(&mut decoded))
+                .unwrap();
+            assert_eq!(decoded, CONTENT, "{}", format.name());
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_data() {
+        for data in [&b""[..], b"plain text", b"\x1f", &XZ_MAGIC[..5]] {
+            let detected = detect(&mut Cursor::new(data));
+            assert!(detected.is_err_and(|e| e.kind() == io::ErrorKind::InvalidData));
+        }
+    }
+
+    #[test]
+    fn detects_formats_by_name() {
+        let format = |name: &str| from_name(Path::new(name)).map(CompressionFormat::name);
+        assert_eq!(format("nvim-20240110-120000.tar.gz"), Some("gzip"));
+        assert_eq!(format("nvim-20240110-120000.tar.zst.age"), Some("zstd"));
+        assert_eq!(format("nvim-20240110-120000.250.tar.xz"), Some("xz"));
+        assert_eq!(format("dir/nvim.tar.xz"), Some("xz"));
+        assert_eq!(format("nvim.tar"), None);
+        assert_eq!(format("nvim.tar.gz.manifest"), None);
+    }
+}
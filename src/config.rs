@@ -0,0 +1,299 @@
+use serde::Deserialize;
+use std::env;
+use std::path::{Component, Path, PathBuf};
+
+const DEFAULT_REPO_URL: &str = "https://github.com/farukerdem34/dotfiles.git";
+
+// User configuration read from $XDG_CONFIG_HOME/dot-utils/config.toml
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub repo_url: String,
+    pub branch: Option<String>,
+    pub ssh_key: Option<String>,
+    // Only check out the selected stow packages
+    pub sparse: bool,
+    // Track $HOME directly with a bare repository instead of stow packages
+    pub bare: bool,
+    pub verify_signatures: VerifyConfig,
+    pub encryption: EncryptionConfig,
+    // Repositories layered over the main one, later entries win per file
+    pub layers: Vec<LayerConfig>,
+    pub backups: BackupConfig,
+}
+
+// Policy for commits pulled in by a sync
+#[derive(Default, Deserialize)]
+#[serde(default)]
+pub struct VerifyConfig {
+    // Refuse to merge commits without a trusted signature
+    pub enabled: bool,
+    // ssh-keygen allowed signers file used for SSH signatures
+    pub allowed_signers: Option<String>,
+    // GnuPG home holding the trusted keyring, defaults to the user's own
+    pub gpg_home: Option<String>,
+}
+
+// Keys for the `*.age` files kept in the repository
+#[derive(Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct EncryptionConfig {
+    // age identity file, defaults to identity.txt next to this config
+    pub identity: Option<String>,
+    // Recipients files are re-encrypted to, defaults to the identity's own key
+    pub recipients: Vec<String>,
+}
+
+// An additional dotfiles repository, cloned to ~/.dotfiles.d/<name>
+#[derive(Deserialize)]
+pub struct LayerConfig {
+    pub name: String,
+    pub url: String,
+    pub branch: Option<String>,
+}
+
+// Where backup archives go, how many are kept and what is backed up
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct BackupConfig {
+    // Defaults to $XDG_DATA_HOME/dot-utils/backups
+    pub dir: Option<String>,
+    pub retention: RetentionConfig,
+    pub compression: CompressionConfig,
+    pub encryption: BackupEncryption,
+    // Replaces the default NeoVim profiles when set
+    pub profiles: Vec<BackupProfile>,
+}
+
+// A named set of paths under $HOME archived together
+#[derive(Clone, Deserialize)]
+pub struct BackupProfile {
+    pub name: String,
+    pub paths: Vec<String>,
+    // gitignore-style patterns, relative to each source directory
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    #[serde(default)]
+    pub target: BackupTarget,
+    // Directory backups of this profile go to instead of backups.dir, such
+    // as a mounted drive. It has to exist.
+    pub dest: Option<String>,
+}
+
+// Where a profile is backed up to
+#[derive(Clone, Copy, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BackupTarget {
+    // A compressed tar archive per backup
+    #[default]
+    Archive,
+    // Deduplicated chunks in the shared store, with a snapshot per backup
+    Store,
+}
+
+impl BackupProfile {
+    fn new(name: &str, path: &str) -> Self {
+        Self {
+            name: String::from(name),
+            paths: vec![String::from(path)],
+            // Plugin managers clone into here, their history is not needed
+            exclude: vec![String::from(".git/")],
+            target: BackupTarget::Archive,
+            dest: None,
+        }
+    }
+}
+
+impl Default for BackupConfig {
+    fn default() -> Self {
+        Self {
+            dir: None,
+            retention: RetentionConfig::default(),
+            compression: CompressionConfig::default(),
+            encryption: BackupEncryption::default(),
+            profiles: vec![
+                BackupProfile::new("nvim-state", "~/.local/state/nvim"),
+                BackupProfile::new("nvim-share", "~/.local/share/nvim"),
+                BackupProfile::new("nvim-cache", "~/.cache/nvim"),
+            ],
+        }
+    }
+}
+
+// How new archives are compressed. Restores detect the format by itself.
+#[derive(Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct CompressionConfig {
+    pub format: CompressionFormat,
+    // Defaults to the format's own default level
+    pub level: Option<i32>,
+    // zstd and xz worker threads, defaults to the number of CPUs
+    pub threads: Option<u32>,
+}
+
+#[derive(Clone, Copy, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CompressionFormat {
+    #[default]
+    Gzip,
+    Zstd,
+    Xz,
+}
+
+// Whether new archives are encrypted with age. Restores detect it by
+// themselves.
+#[derive(Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct BackupEncryption {
+    pub mode: BackupEncryptionMode,
+    // Recipients for the `recipients` mode, defaults to those of [encryption]
+    pub recipients: Vec<String>,
+}
+
+#[derive(Clone, Copy, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BackupEncryptionMode {
+    #[default]
+    None,
+    // A passphrase asked for in the TUI
+    Passphrase,
+    // age public keys, decrypted with the identity of [encryption]
+    Recipients,
+}
+
+// Archives kept per backup and host, pruning the rest after each backup
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct RetentionConfig {
+    // The most recent archives
+    pub keep_last: usize,
+    // The newest archive of each of the last N days
+    pub keep_daily: usize,
+    // The newest archive of each of the last N ISO weeks
+    pub keep_weekly: usize,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        Self {
+            keep_last: 3,
+            keep_daily: 7,
+            keep_weekly: 4,
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            repo_url: String::from(DEFAULT_REPO_URL),
+            branch: None,
+            ssh_key: None,
+            sparse: false,
+            bare: false,
+            verify_signatures: VerifyConfig::default(),
+            encryption: EncryptionConfig::default(),
+            layers: Vec::new(),
+            backups: BackupConfig::default(),
+        }
+    }
+}
+
+// Expand a leading `~/` to the home directory
+pub fn expand_home(path: &str) -> PathBuf {
+    match path.strip_prefix("~/") {
+        Some(rest) => PathBuf::from(env::var("HOME").unwrap_or_default()).join(rest),
+        None => PathBuf::from(path),
+    }
+}
+
+pub fn config_dir() -> PathBuf {
+    match env::var("XDG_CONFIG_HOME") {
+        Ok(dir) if !dir.is_empty() => PathBuf::from(dir).join("dot-utils"),
+        _ => PathBuf::from(env::var("HOME").unwrap_or_default()).join(".config/dot-utils"),
+    }
+}
+
+pub fn load() -> Result<Config, String> {
+    let path = config_dir().join("config.toml");
+    if !path.exists() {
+        return Ok(Config::default());
+    }
+    let content = std::fs::read_to_string(&path)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let config: Config = toml::from_str(&content)
+        .map_err(|e| format!("Invalid config {}: {}", path.display(), e))?;
+    check_names(&config).map_err(|e| format!("Invalid config {}: {}", path.display(), e))?;
+    Ok(config)
+}
+
+// A trailing slash would still parse as one component
+fn is_single_name(name: &str) -> bool {
+    let mut components = Path::new(name).components();
+    !name.contains('/')
+        && matches!(
+            (components.next(), components.next()),
+            (Some(Component::Normal(_)), None)
+        )
+}
+
+// Layers are cloned to ~/.dotfiles.d/<name> and profile names start the file
+// names of their archives, so both have to stay inside their directory
+fn check_names(config: &Config) -> Result<(), String> {
+    if let Some(layer) = config.layers.iter().find(|l| !is_single_name(&l.name)) {
+        return Err(format!(
+            "layer name {:?} is not a single directory name",
+            layer.name
+        ));
+    }
+    if let Some(profile) = config
+        .backups
+        .profiles
+        .iter()
+        .find(|p| !is_single_name(&p.name))
+    {
+        return Err(format!(
+            "backup profile name {:?} is not a single file name",
+            profile.name
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile(name: &str) -> Result<(), String> {
+        let config: Config = toml::from_str(&format!(
+            "[[backups.profiles]]\nname = {:?}\npaths = [\"~/.config/nvim\"]\n",
+            name
+        ))
+        .unwrap();
+        check_names(&config)
+    }
+
+    #[test]
+    fn accepts_single_names() {
+        assert_eq!(profile("nvim-state"), Ok(()));
+        assert_eq!(profile("work.laptop"), Ok(()));
+    }
+
+    #[test]
+    fn rejects_profile_names_leaving_the_backup_directory() {
+        for name in ["", ".", "..", "../nvim", "/tmp/nvim", "nvim/state", "nvim/"] {
+            let error = profile(name).unwrap_err();
+            assert!(error.contains("is not a single file name"), "{}", name);
+        }
+    }
+
+    #[test]
+    fn rejects_layer_names_leaving_the_layer_directory() {
+        let config: Config =
+            toml::from_str("[[layers]]\nname = \"../work\"\nurl = \"git@example.com:work\"\n")
+                .unwrap();
+        assert!(check_names(&config)
+            .unwrap_err()
+            .contains("is not a single directory name"));
+    }
+}
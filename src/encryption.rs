@@ -0,0 +1,686 @@
+use crate::config::{self, BackupEncryption, BackupEncryptionMode, EncryptionConfig};
+use age::secrecy::{ExposeSecret, SecretBox, SecretString};
+use age::stream::StreamWriter;
+use age::x25519::{Identity, Recipient};
+use age_core::format::{FileKey, Stanza};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::iter;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+// Encrypted files are stored as `<name>.age` and decrypted to `<name>`,
+// encrypted backup archives as `<archive>.age`
+pub const EXTENSION: &str = ".age";
+
+// Start of every file in the binary age format
+const MAGIC: &[u8] = b"age-encryption.org/";
+
+// Passed to stow so encrypted files are never linked into $HOME
+pub const STOW_IGNORE: &str = "--ignore=\\.age$";
+
+struct Keys {
+    identities: Vec<Identity>,
+    recipients: Vec<Recipient>,
+}
+
+fn identity_path(config: &EncryptionConfig) -> PathBuf {
+    match &config.identity {
+        Some(identity) => config::expand_home(identity),
+        None => config::config_dir().join("identity.txt"),
+    }
+}
+
+// Read the age identity file and the recipients new ciphertext is written for.
+// Without configured recipients, files are encrypted to the identity itself.
+fn load_keys(config: &EncryptionConfig) -> Result<Keys, String> {
+    let path = identity_path(config);
+    let content = fs::read_to_string(&path)
+        .map_err(|e| format!("Failed to read age identity {}: {}", path.display(), e))?;
+    let identities = content
+        .lines()
+        .map(str::trim)
+        .filter(|line| line.starts_with("AGE-SECRET-KEY-"))
+        .map(|line| line.parse::<Identity>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("Invalid age identity in {}: {}", path.display(), e))?;
+    if identities.is_empty() {
+        return Err(format!("No age identity found in {}", path.display()));
+    }
+
+    let recipients = if config.recipients.is_empty() {
+        identities.iter().map(Identity::to_public).collect()
+    } else {
+        parse_recipients(&config.recipients)?
+    };
+    Ok(Keys {
+        identities,
+        recipients,
+    })
+}
+
+fn parse_recipients(recipients: &[String]) -> Result<Vec<Recipient>, String> {
+    recipients
+        .iter()
+        .map(|recipient| {
+            recipient
+                .parse::<Recipient>()
+                .map_err(|e| format!("Invalid age recipient {}: {}", recipient, e))
+        })
+        .collect()
+}
+
+fn decrypt(keys: &Keys, ciphertext: &[u8]) -> Result<Vec<u8>, String> {
+    let mut last_error = String::from("no identity");
+    for identity in &keys.identities {
+        match age::decrypt(identity, ciphertext) {
+            Ok(plaintext) => return Ok(plaintext),
+            Err(e) => last_error = e.to_string(),
+        }
+    }
+    Err(last_error)
+}
+
+fn encrypt(keys: &Keys, plaintext: &[u8]) -> Result<Vec<u8>, String> {
+    let encryptor = age::Encryptor::with_recipients(
+        keys.recipients
+            .iter()
+            .map(|recipient| recipient as &dyn age::Recipient),
+    )
+    .map_err(|e| e.to_string())?;
+    let mut ciphertext = Vec::new();
+    let mut writer = encryptor
+        .wrap_output(&mut ciphertext)
+        .map_err(|e| e.to_string())?;
+    writer.write_all(plaintext).map_err(|e| e.to_string())?;
+    writer.finish().map_err(|e| e.to_string())?;
+    Ok(ciphertext)
+}
+
+fn collect_encrypted(dir: &Path, prefix: &Path, files: &mut Vec<PathBuf>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+        let relative = prefix.join(entry.file_name());
+        if file_type.is_dir() {
+            collect_encrypted(&entry.path(), &relative, files);
+        } else if file_type.is_file() && entry.file_name().to_string_lossy().ends_with(EXTENSION) {
+            files.push(relative);
+        }
+    }
+}
+
+// Encrypted files of a package, relative to the package directory
+fn encrypted_files(dotfiles: &Path, package: &str) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    collect_encrypted(&dotfiles.join(package), Path::new(""), &mut files);
+    files.sort();
+    files
+}
+
+// Where the plaintext of an encrypted file lives, mirroring stow's layout
+fn target_path(home: &Path, relative: &Path) -> PathBuf {
+    let name = relative.to_string_lossy();
+    home.join(name.strip_suffix(EXTENSION).unwrap_or(&name))
+}
+
+// Plaintext written through a directory stow folded into a symlink would end
+// up inside the repository, unencrypted
+fn inside_repository(dotfiles: &Path, target: &Path) -> bool {
+    let (Some(parent), Ok(dotfiles)) = (target.parent(), dotfiles.canonicalize()) else {
+        return false;
+    };
+    parent
+        .canonicalize()
+        .map(|parent| parent.starts_with(dotfiles))
+        .unwrap_or(false)
+}
+
+fn write_private(path: &Path, content: &[u8]) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let temporary = path.with_file_name(format!(".{}.dot-utils-tmp", name));
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&temporary)?;
+    file.write_all(content)?;
+    file.sync_all()?;
+    fs::rename(&temporary, path)
+}
+
+// Decrypt the encrypted files of the given packages next to their stow links.
+// Plaintext that was edited since is left alone so edits are never lost.
+pub fn decrypt_packages(
+    dotfiles: &Path,
+    home: &Path,
+    packages: &[String],
+    config: &EncryptionConfig,
+) -> String {
+    let files: Vec<(String, PathBuf)> = packages
+        .iter()
+        .flat_map(|package| {
+            encrypted_files(dotfiles, package)
+                .into_iter()
+                .map(move |file| (package.clone(), file))
+        })
+        .collect();
+    if files.is_empty() {
+        return String::new();
+    }
+    let keys = match load_keys(config) {
+        Ok(keys) => keys,
+        Err(e) => return format!("\n\nEncrypted files were not decrypted: {}", e),
+    };
+
+    let mut report = String::from("\n\nEncrypted files:");
+    for (package, file) in files {
+        let source = dotfiles.join(&package).join(&file);
+        let target = target_path(home, &file);
+        let label = format!("{}/{}", package, file.display());
+        let result = fs::read(&source)
+            .map_err(|e| e.to_string())
+            .and_then(|ciphertext| decrypt(&keys, &ciphertext));
+        let plaintext = match result {
+            Ok(plaintext) => plaintext,
+            Err(e) => {
+                report.push_str(&format!("\n- {}: failed to decrypt: {}", label, e));
+                continue;
+            }
+        };
+        if inside_repository(dotfiles, &target) {
+            report.push_str(&format!(
+                "\n- {}: {} is inside a folded stow link, relink with `stow --no-folding`",
+                label,
+                target.display()
+            ));
+            continue;
+        }
+
+        let is_file = fs::symlink_metadata(&target)
+            .map(|metadata| metadata.is_file())
+            .unwrap_or(false);
+        if is_file {
+            match fs::read(&target) {
+                Ok(current) if current == plaintext => {
+                    let _ = fs::set_permissions(&target, fs::Permissions::from_mode(0o600));
+                    report.push_str(&format!("\n- {}: up to date", label));
+                }
+                _ => report.push_str(&format!(
+                    "\n- {}: {} has local edits, commit to re-encrypt them",
+                    label,
+                    target.display()
+                )),
+            }
+            continue;
+        }
+        match write_private(&target, &plaintext) {
+            Ok(()) => report.push_str(&format!("\n- {}: decrypted to {}", label, target.display())),
+            Err(e) => report.push_str(&format!("\n- {}: {}", label, e)),
+        }
+    }
+    report
+}
+
+// Remove decrypted files of unlinked packages, keeping any with local edits
+pub fn remove_decrypted(
+    dotfiles: &Path,
+    home: &Path,
+    packages: &[String],
+    config: &EncryptionConfig,
+) -> String {
+    let keys = load_keys(config);
+    let mut report = String::new();
+    for package in packages {
+        for file in encrypted_files(dotfiles, package) {
+            let target = target_path(home, &file);
+            let Ok(current) = fs::read(&target) else {
+                continue;
+            };
+            let decrypted = keys.as_ref().map_err(Clone::clone).and_then(|keys| {
+                fs::read(dotfiles.join(package).join(&file))
+                    .map_err(|e| e.to_string())
+                    .and_then(|ciphertext| decrypt(keys, &ciphertext))
+            });
+            let line = match decrypted {
+                Ok(plaintext) if plaintext == current => match fs::remove_file(&target) {
+                    Ok(()) => String::from("removed"),
+                    Err(e) => format!("failed to remove: {}", e),
+                },
+                Ok(_) => String::from("kept, it has local edits"),
+                Err(e) => format!("kept, failed to decrypt {}: {}", file.display(), e),
+            };
+            report.push_str(&format!("\n- {}: {}", target.display(), line));
+        }
+    }
+    report
+}
+
+// Stow packages are the visible top-level directories of the repository
+fn package_dirs(dotfiles: &Path) -> Vec<String> {
+    let Ok(entries) = fs::read_dir(dotfiles) else {
+        return Vec::new();
+    };
+    entries
+        .flatten()
+        .filter(|entry| entry.file_type().map(|t| t.is_dir()).unwrap_or(false))
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .filter(|name| !name.starts_with('.'))
+        .collect()
+}
+
+// Encrypt edited plaintext back into the repository before committing.
+// Returns the re-encrypted files.
+pub fn reencrypt(
+    dotfiles: &Path,
+    home: &Path,
+    config: &EncryptionConfig,
+) -> Result<Vec<String>, String> {
+    let files: Vec<(String, PathBuf)> = package_dirs(dotfiles)
+        .into_iter()
+        .flat_map(|package| {
+            encrypted_files(dotfiles, &package)
+                .into_iter()
+                .map(move |file| (package.clone(), file))
+        })
+        .collect();
+    if files.is_empty() {
+        return Ok(Vec::new());
+    }
+    let keys = load_keys(config)?;
+
+    let mut updated = Vec::new();
+    for (package, file) in files {
+        let source = dotfiles.join(&package).join(&file);
+        let target = target_path(home, &file);
+        let label = format!("{}/{}", package, file.display());
+        let is_file = fs::symlink_metadata(&target)
+            .map(|metadata| metadata.is_file())
+            .unwrap_or(false);
+        if !is_file || inside_repository(dotfiles, &target) {
+            continue;
+        }
+        let current =
+            fs::read(&target).map_err(|e| format!("Failed to read {}: {}", target.display(), e))?;
+        let stored = fs::read(&source)
+            .map_err(|e| e.to_string())
+            .and_then(|ciphertext| decrypt(&keys, &ciphertext))
+            .map_err(|e| format!("Failed to decrypt {}: {}", label, e))?;
+        // age output is randomised, so only rewrite files whose plaintext changed
+        if current == stored {
+            continue;
+        }
+        let ciphertext =
+            encrypt(&keys, &current).map_err(|e| format!("Failed to encrypt {}: {}", label, e))?;
+        fs::write(&source, ciphertext)
+            .map_err(|e| format!("Failed to write {}: {}", source.display(), e))?;
+        updated.push(label);
+    }
+    Ok(updated)
+}
+
+// Files that sit next to their own encrypted copy, i.e. plaintext that must
+// not be committed
+pub fn plaintext_copies(paths: &[String]) -> Vec<String> {
+    paths
+        .iter()
+        .filter(|path| paths.contains(&format!("{}{}", path, EXTENSION)))
+        .cloned()
+        .collect()
+}
+
+// The passphrase typed in this session, with the file keys of the archives it
+// wrapped or unwrapped so far. scrypt takes about a second to derive the key
+// of one file, and a restore opens the archive several times. Clones share
+// the keys, a new passphrase starts without any.
+#[derive(Clone)]
+pub struct SessionPassphrase {
+    secret: SecretString,
+    file_keys: Arc<Mutex<HashMap<StanzaId, SecretBox<[u8; 16]>>>>,
+}
+
+// Arguments and body of an scrypt stanza
+type StanzaId = (Vec<String>, Vec<u8>);
+
+impl SessionPassphrase {
+    pub fn new(secret: SecretString) -> Self {
+        Self {
+            secret,
+            file_keys: Arc::default(),
+        }
+    }
+
+    // An scrypt stanza is unique to its file through the random salt
+    fn remember(&self, stanza: &Stanza, file_key: &FileKey) {
+        if let Ok(mut file_keys) = self.file_keys.lock() {
+            file_keys.insert(
+                (stanza.args.clone(), stanza.body.clone()),
+                SecretBox::new(Box::new(*file_key.expose_secret())),
+            );
+        }
+    }
+
+    fn recall(&self, stanza: &Stanza) -> Option<FileKey> {
+        let file_keys = self.file_keys.lock().ok()?;
+        let file_key = file_keys.get(&(stanza.args.clone(), stanza.body.clone()))?;
+        Some(FileKey::new(Box::new(*file_key.expose_secret())))
+    }
+}
+
+impl age::Identity for SessionPassphrase {
+    fn unwrap_stanza(&self, stanza: &Stanza) -> Option<Result<FileKey, age::DecryptError>> {
+        if let Some(file_key) = self.recall(stanza) {
+            return Some(Ok(file_key));
+        }
+        let unwrapped = age::scrypt::Identity::new(self.secret.clone()).unwrap_stanza(stanza)?;
+        if let Ok(file_key) = &unwrapped {
+            self.remember(stanza, file_key);
+        }
+        Some(unwrapped)
+    }
+}
+
+impl age::Recipient for SessionPassphrase {
+    fn wrap_file_key(
+        &self,
+        file_key: &FileKey,
+    ) -> Result<(Vec<Stanza>, HashSet<String>), age::EncryptError> {
+        let (stanzas, labels) =
+            age::scrypt::Recipient::new(self.secret.clone()).wrap_file_key(file_key)?;
+        for stanza in &stanzas {
+            self.remember(stanza, file_key);
+        }
+        Ok((stanzas, labels))
+    }
+}
+
+// Keys backup archives are encrypted and decrypted with: the identity and
+// recipients of the repository, and the passphrase typed in this session
+pub struct BackupKeys<'a> {
+    pub config: &'a EncryptionConfig,
+    pub passphrase: Option<&'a SessionPassphrase>,
+}
+
+// Encryptor for a new archive, None when archives are not encrypted
+pub fn archive_encryptor(
+    encryption: &BackupEncryption,
+    keys: &BackupKeys,
+) -> Result<Option<age::Encryptor>, String> {
+    match encryption.mode {
+        BackupEncryptionMode::None => Ok(None),
+        BackupEncryptionMode::Passphrase => {
+            let passphrase = keys
+                .passphrase
+                .ok_or("No passphrase was entered to encrypt the archive with")?;
+            age::Encryptor::with_recipients(iter::once(passphrase as &dyn age::Recipient))
+                .map(Some)
+                .map_err(|e| e.to_string())
+        }
+        BackupEncryptionMode::Recipients => {
+            let recipients = if encryption.recipients.is_empty() {
+                load_keys(keys.config)?.recipients
+            } else {
+                parse_recipients(&encryption.recipients)?
+            };
+            age::Encryptor::with_recipients(
+                recipients
+                    .iter()
+                    .map(|recipient| recipient as &dyn age::Recipient),
+            )
+            .map(Some)
+            .map_err(|e| e.to_string())
+        }
+    }
+}
+
+// Encrypt a small file in memory, like the manifest of an encrypted archive
+pub fn encrypt_bytes(encryptor: age::Encryptor, plaintext: &[u8]) -> io::Result<Vec<u8>> {
+    let mut writer = encryptor.wrap_output(Vec::new())?;
+    writer.write_all(plaintext)?;
+    writer.finish()
+}
+
+// The file an archive is written to, through age when it is encrypted
+pub enum Sink {
+    Plain(File),
+    Encrypted(StreamWriter<File>),
+}
+
+impl Sink {
+    pub fn new(file: File, encryptor: Option<age::Encryptor>) -> io::Result<Self> {
+        Ok(match encryptor {
+            Some(encryptor) => Sink::Encrypted(encryptor.wrap_output(file)?),
+            None => Sink::Plain(file),
+        })
+    }
+
+    // Write the last encrypted chunk and hand back the file
+    pub fn finish(self) -> io::Result<File> {
+        match self {
+            Sink::Plain(file) => Ok(file),
+            Sink::Encrypted(writer) => writer.finish(),
+        }
+    }
+}
+
+impl Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Sink::Plain(file) => file.write(buf),
+            Sink::Encrypted(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Sink::Plain(file) => file.flush(),
+            Sink::Encrypted(writer) => writer.flush(),
+        }
+    }
+}
+
+fn decryptor<R: BufRead>(mut reader: R) -> io::Result<Option<age::Decryptor<R>>> {
+    if !reader.fill_buf()?.starts_with(MAGIC) {
+        return Ok(None);
+    }
+    age::Decryptor::new(reader)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn open_decryptor(path: &Path) -> io::Result<Option<age::Decryptor<BufReader<File>>>> {
+    decryptor(BufReader::new(File::open(path)?))
+}
+
+// Whether an archive is encrypted with a passphrase
+pub fn needs_passphrase(path: &Path) -> bool {
+    open_decryptor(path).is_ok_and(|decryptor| decryptor.is_some_and(|d| d.is_scrypt()))
+}
+
+// Whether an archive read from a stream, like a member of a bundle, is
+// encrypted with a passphrase that the given one does not open
+pub fn locked<R: BufRead>(reader: R, passphrase: Option<&SessionPassphrase>) -> io::Result<bool> {
+    Ok(match decryptor(reader)? {
+        Some(decryptor) if decryptor.is_scrypt() => !passphrase.is_some_and(|passphrase| {
+            decryptor
+                .decrypt(iter::once(passphrase as &dyn age::Identity))
+                .is_ok()
+        }),
+        _ => false,
+    })
+}
+
+// Check a passphrase against an archive before it is used for a whole restore
+pub fn check_passphrase(path: &Path, passphrase: &SessionPassphrase) -> Result<(), String> {
+    let decryptor = open_decryptor(path)
+        .map_err(|e| e.to_string())?
+        .ok_or_else(|| format!("{} is not encrypted", path.display()))?;
+    decryptor
+        .decrypt(iter::once(passphrase as &dyn age::Identity))
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+// Contents of an archive, decrypted when it is encrypted
+pub fn archive_reader(path: &Path, keys: &BackupKeys) -> io::Result<Box<dyn Read>> {
+    let Some(decryptor) = open_decryptor(path)? else {
+        return Ok(Box::new(BufReader::new(File::open(path)?)));
+    };
+    let denied = |message: String| io::Error::new(io::ErrorKind::PermissionDenied, message);
+    let reader = if decryptor.is_scrypt() {
+        let passphrase = keys
+            .passphrase
+            .ok_or_else(|| denied(String::from("the archive is encrypted with a passphrase")))?;
+        decryptor.decrypt(iter::once(passphrase as &dyn age::Identity))
+    } else {
+        let identities = load_keys(keys.config).map_err(denied)?.identities;
+        decryptor.decrypt(
+            identities
+                .iter()
+                .map(|identity| identity as &dyn age::Identity),
+        )
+    };
+    Ok(Box::new(reader.map_err(|e| denied(e.to_string()))?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+    use tempfile::TempDir;
+
+    struct Setup {
+        _dir: TempDir,
+        dotfiles: PathBuf,
+        home: PathBuf,
+        config: EncryptionConfig,
+        keys: Keys,
+    }
+
+    // A dotfiles repository and $HOME with an age identity of their own
+    fn setup() -> Setup {
+        let dir = TempDir::new().unwrap();
+        let dotfiles = dir.path().join("dotfiles");
+        let home = dir.path().join("home");
+        fs::create_dir_all(&dotfiles).unwrap();
+        fs::create_dir_all(&home).unwrap();
+        let identity = Identity::generate();
+        let identity_file = dir.path().join("identity.txt");
+        fs::write(&identity_file, identity.to_string().expose_secret()).unwrap();
+        let config = EncryptionConfig {
+            identity: Some(identity_file.to_string_lossy().to_string()),
+            recipients: Vec::new(),
+        };
+        let keys = load_keys(&config).unwrap();
+        Setup {
+            _dir: dir,
+            dotfiles,
+            home,
+            config,
+            keys,
+        }
+    }
+
+    impl Setup {
+        fn add(&self, path: &str, plaintext: &str) {
+            let file = self.dotfiles.join(path);
+            fs::create_dir_all(file.parent().unwrap()).unwrap();
+            fs::write(file, encrypt(&self.keys, plaintext.as_bytes()).unwrap()).unwrap();
+        }
+
+        fn stored(&self, path: &str) -> String {
+            let ciphertext = fs::read(self.dotfiles.join(path)).unwrap();
+            String::from_utf8(decrypt(&self.keys, &ciphertext).unwrap()).unwrap()
+        }
+
+        fn decrypt(&self) -> String {
+            decrypt_packages(
+                &self.dotfiles,
+                &self.home,
+                &[String::from("git")],
+                &self.config,
+            )
+        }
+    }
+
+    #[test]
+    fn decrypts_into_private_files() {
+        let setup = setup();
+        setup.add("git/.config/git/token.age", "secret\n");
+
+        let report = setup.decrypt();
+
+        let target = setup.home.join(".config/git/token");
+        assert!(report.contains("decrypted to"));
+        assert_eq!(fs::read_to_string(&target).unwrap(), "secret\n");
+        let mode = fs::metadata(&target).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[test]
+    fn keeps_local_edits() {
+        let setup = setup();
+        setup.add("git/.token.age", "secret\n");
+        fs::write(setup.home.join(".token"), "edited\n").unwrap();
+
+        assert!(setup.decrypt().contains("has local edits"));
+        assert_eq!(
+            fs::read_to_string(setup.home.join(".token")).unwrap(),
+            "edited\n"
+        );
+    }
+
+    #[test]
+    fn refuses_to_decrypt_into_a_folded_link() {
+        let setup = setup();
+        setup.add("git/.config/git/token.age", "secret\n");
+        symlink(
+            setup.dotfiles.join("git/.config"),
+            setup.home.join(".config"),
+        )
+        .unwrap();
+
+        assert!(setup.decrypt().contains("inside a folded stow link"));
+        assert!(!setup.dotfiles.join("git/.config/git/token").exists());
+    }
+
+    #[test]
+    fn reencrypts_edited_plaintext() {
+        let setup = setup();
+        setup.add("git/.token.age", "secret\n");
+        setup.add("git/.other.age", "other\n");
+        setup.decrypt();
+        fs::write(setup.home.join(".token"), "edited\n").unwrap();
+        let other = fs::read(setup.dotfiles.join("git/.other.age")).unwrap();
+
+        let updated = reencrypt(&setup.dotfiles, &setup.home, &setup.config).unwrap();
+
+        assert_eq!(updated, vec![String::from("git/.token.age")]);
+        assert_eq!(setup.stored("git/.token.age"), "edited\n");
+        assert_eq!(
+            fs::read(setup.dotfiles.join("git/.other.age")).unwrap(),
+            other
+        );
+    }
+
+    #[test]
+    fn finds_plaintext_next_to_its_encrypted_copy() {
+        let paths = [
+            String::from("git/.token"),
+            String::from("git/.token.age"),
+            String::from("git/.other.age"),
+            String::from("zsh/.zshrc"),
+        ];
+        assert_eq!(plaintext_copies(&paths), vec![String::from("git/.token")]);
+    }
+}
@@ -0,0 +1,381 @@
+use crate::sparse;
+use chrono::DateTime;
+use git2::build::CheckoutBuilder;
+use git2::{Commit, DiffFormat, Index, Oid, Repository, ResetType, Tree};
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+
+// Number of commits shown in the history view
+const HISTORY_LIMIT: usize = 200;
+
+pub fn list_commits(repo: &Repository) -> Result<Vec<(Oid, String)>, git2::Error> {
+    let mut revwalk = repo.revwalk()?;
+    revwalk.push_head()?;
+    revwalk.set_sorting(git2::Sort::TIME)?;
+
+    let mut commits = Vec::new();
+    for oid in revwalk.take(HISTORY_LIMIT) {
+        let oid = oid?;
+        commits.push((oid, commit_line(&repo.find_commit(oid)?)));
+    }
+    Ok(commits)
+}
+
+// `<short id> <date> <summary>`, as commits are listed in pickers
+pub fn commit_line(commit: &Commit) -> String {
+    let date = DateTime::from_timestamp(commit.time().seconds(), 0)
+        .map(|date| date.format("%Y-%m-%d").to_string())
+        .unwrap_or_default();
+    format!(
+        "{} {} {}",
+        short_id(commit.id()),
+        date,
+        commit.summary().unwrap_or("<no message>")
+    )
+}
+
+pub fn short_id(oid: Oid) -> String {
+    oid.to_string()[..7].to_string()
+}
+
+// Render a commit header and its patch against the first parent
+pub fn commit_diff(repo: &Repository, oid: Oid) -> Result<String, git2::Error> {
+    let commit = repo.find_commit(oid)?;
+    let tree = commit.tree()?;
+    let parent_tree = match commit.parent_count() {
+        0 => None,
+        _ => Some(commit.parent(0)?.tree()?),
+    };
+    let diff = repo.diff_tree_to_tree(parent_tree.as_ref(), Some(&tree), None)?;
+
+    let author = commit.author();
+    let mut text = format!(
+        "commit {}\nAuthor: {} <{}>\n\n    {}\n\n",
+        oid,
+        author.name().unwrap_or(""),
+        author.email().unwrap_or(""),
+        commit.message().unwrap_or("").trim()
+    );
+    diff.print(DiffFormat::Patch, |_, _, line| {
+        if matches!(line.origin(), '+' | '-' | ' ') {
+            text.push(line.origin());
+        }
+        text.push_str(&String::from_utf8_lossy(line.content()));
+        true
+    })?;
+    Ok(text)
+}
+
+// Stow packages are the top-level directories of the repository, so every
+// path changed between two trees maps back to the package that owns it.
+fn changed_packages(
+    repo: &Repository,
+    old_tree: &Tree,
+    new_tree: &Tree,
+) -> Result<Vec<String>, git2::Error> {
+    let diff = repo.diff_tree_to_tree(Some(old_tree), Some(new_tree), None)?;
+    let mut packages: Vec<String> = Vec::new();
+    for delta in diff.deltas() {
+        for file in [delta.old_file(), delta.new_file()] {
+            let Some(path) = file.path() else { continue };
+            let mut components = path.components();
+            let (Some(first), Some(_)) = (components.next(), components.next()) else {
+                continue;
+            };
+            let package = first.as_os_str().to_string_lossy().to_string();
+            if !package.starts_with('.') && !packages.contains(&package) {
+                packages.push(package);
+            }
+        }
+    }
+    Ok(packages)
+}
+
+fn head_tree(repo: &Repository) -> Result<Tree<'_>, git2::Error> {
+    repo.head()?.peel_to_tree()
+}
+
+// Create a `safety/<id>-<timestamp>` branch at the commit and switch to it,
+// leaving the branch that was checked out untouched.
+pub fn checkout_safety_branch(
+    repo: &Repository,
+    oid: Oid,
+) -> Result<(String, Vec<String>), git2::Error> {
+    let commit = repo.find_commit(oid)?;
+    let old_tree = head_tree(repo)?;
+    let branch_name = format!(
+        "safety/{}-{}",
+        short_id(oid),
+        chrono::Local::now().format("%Y%m%d%H%M%S")
+    );
+
+    // The branch is only kept once the working tree is switched to it
+    let mut branch = repo.branch(&branch_name, &commit, false)?;
+    let switched = sparse::checkout_commit(repo, &commit)
+        .and_then(|_| repo.set_head(&format!("refs/heads/{}", branch_name)));
+    if let Err(e) = switched {
+        let _ = branch.delete();
+        return Err(e);
+    }
+
+    let packages = changed_packages(repo, &old_tree, &commit.tree()?)?;
+    Ok((branch_name, packages))
+}
+
+pub fn revert_commit(repo: &Repository, oid: Oid) -> Result<Vec<String>, git2::Error> {
+    let commit = repo.find_commit(oid)?;
+    let old_tree = head_tree(repo)?;
+    let head_commit: Commit = repo.head()?.peel_to_commit()?;
+    let mainline = if commit.parent_count() > 1 { 1 } else { 0 };
+
+    // A conflicted revert is undone path by path, which cannot tell staged
+    // changes apart from the revert's own
+    let staged = repo.diff_tree_to_index(Some(&old_tree), None, None)?;
+    if staged.deltas().len() > 0 {
+        return Err(git2::Error::from_str(
+            "The index has staged changes. Commit or unstage them before reverting.",
+        ));
+    }
+
+    let signature = repo.signature()?;
+    let message = format!(
+        "Revert \"{}\"\n\nThis reverts commit {}.",
+        commit.summary().unwrap_or(""),
+        oid
+    );
+
+    // libgit2 sees packages left out of a sparse checkout as deleted and
+    // refuses to revert over them, so revert in memory there instead
+    if sparse::packages(repo).is_some() {
+        let mut index = repo.revert_commit(&commit, &head_commit, mainline, None)?;
+        if index.has_conflicts() {
+            return Err(git2::Error::from_str(
+                "Revert produced conflicts. Sparse checkouts cannot hold conflicts; \
+                 revert it in a full checkout.",
+            ));
+        }
+        let tree = repo.find_tree(index.write_tree_to(repo)?)?;
+        let revert_oid = repo.commit(
+            None,
+            &signature,
+            &signature,
+            &message,
+            &tree,
+            &[&head_commit],
+        )?;
+        sparse::checkout_commit(repo, &repo.find_commit(revert_oid)?)?;
+        repo.head()?.set_target(revert_oid, "revert")?;
+        return changed_packages(repo, &old_tree, &tree);
+    }
+
+    let mut revert_options = git2::RevertOptions::new();
+    revert_options.mainline(mainline);
+    repo.revert(&commit, Some(&mut revert_options))?;
+
+    let mut index = repo.index()?;
+    if index.has_conflicts() {
+        abort_revert(repo, &commit, &head_commit, &index)?;
+        return Err(git2::Error::from_str(
+            "Revert produced conflicts, the working tree and index were reset to HEAD. \
+             Revert it with git to resolve them.",
+        ));
+    }
+
+    let tree = repo.find_tree(index.write_tree()?)?;
+    repo.commit(
+        Some("HEAD"),
+        &signature,
+        &signature,
+        &message,
+        &tree,
+        &[&head_commit],
+    )?;
+    repo.cleanup_state()?;
+
+    changed_packages(repo, &old_tree, &tree)
+}
+
+// Put every path the reverted commit touched and every conflicted path back
+// to HEAD, in the index and the working tree, and leave the revert state.
+// Local changes to other paths are kept.
+fn abort_revert(
+    repo: &Repository,
+    commit: &Commit,
+    head_commit: &Commit,
+    index: &Index,
+) -> Result<(), git2::Error> {
+    let parent_tree = match commit.parent(0) {
+        Ok(parent) => Some(parent.tree()?),
+        Err(_) => None,
+    };
+    let diff = repo.diff_tree_to_tree(parent_tree.as_ref(), Some(&commit.tree()?), None)?;
+    let mut paths: Vec<PathBuf> = Vec::new();
+    for delta in diff.deltas() {
+        for file in [delta.old_file(), delta.new_file()] {
+            if let Some(path) = file.path() {
+                paths.push(path.to_path_buf());
+            }
+        }
+    }
+    for conflict in index.conflicts()? {
+        let conflict = conflict?;
+        for entry in [conflict.ancestor, conflict.our, conflict.their]
+            .into_iter()
+            .flatten()
+        {
+            paths.push(PathBuf::from(OsStr::from_bytes(&entry.path)));
+        }
+    }
+    paths.sort();
+    paths.dedup();
+
+    if !paths.is_empty() {
+        repo.reset_default(Some(head_commit.as_object()), &paths)?;
+        let mut checkout = CheckoutBuilder::new();
+        checkout.force();
+        for path in &paths {
+            checkout.path(path);
+        }
+        repo.checkout_head(Some(&mut checkout))?;
+    }
+    repo.cleanup_state()
+}
+
+pub fn hard_reset(repo: &Repository, oid: Oid) -> Result<Vec<String>, git2::Error> {
+    let commit = repo.find_commit(oid)?;
+    let old_tree = head_tree(repo)?;
+    let tree = commit.tree()?;
+    let mut checkout = sparse::checkout_builder(repo, Some(&tree));
+    checkout.force();
+    repo.reset(commit.as_object(), ResetType::Hard, Some(&mut checkout))?;
+    sparse::refresh(repo)?;
+    changed_packages(repo, &old_tree, &tree)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use std::fs;
+    use tempfile::TempDir;
+
+    // `nvim/init.lua` is added and changed, then `zsh/.zshrc` is added
+    fn history() -> (TempDir, Repository, [Oid; 3]) {
+        let (dir, repo) = testing::repo();
+        testing::stage(&repo, "nvim/init.lua", "one\n");
+        let first = testing::commit(&repo, "Add nvim");
+        testing::stage(&repo, "nvim/init.lua", "two\n");
+        let second = testing::commit(&repo, "Change nvim");
+        testing::stage(&repo, "zsh/.zshrc", "zsh\n");
+        let third = testing::commit(&repo, "Add zsh");
+        (dir, repo, [first, second, third])
+    }
+
+    fn read(repo: &Repository, path: &str) -> String {
+        fs::read_to_string(repo.workdir().unwrap().join(path)).unwrap()
+    }
+
+    fn safety_branches(repo: &Repository) -> usize {
+        repo.branches(Some(git2::BranchType::Local))
+            .unwrap()
+            .filter(|branch| {
+                let (branch, _) = branch.as_ref().unwrap();
+                branch.name().unwrap().unwrap().starts_with("safety/")
+            })
+            .count()
+    }
+
+    #[test]
+    fn lists_commits_newest_first() {
+        let (_dir, repo, [first, _, third]) = history();
+        let commits = list_commits(&repo).unwrap();
+        assert_eq!(commits.len(), 3);
+        assert_eq!(commits[0].0, third);
+        assert_eq!(commits[2].0, first);
+        assert!(commits[0].1.ends_with("Add zsh"));
+    }
+
+    #[test]
+    fn checks_out_a_safety_branch() {
+        let (_dir, repo, [first, _, third]) = history();
+        let (branch, packages) = checkout_safety_branch(&repo, first).unwrap();
+        assert!(branch.starts_with(&format!("safety/{}-", short_id(first))));
+        assert_eq!(repo.head().unwrap().shorthand(), Some(branch.as_str()));
+        assert_eq!(read(&repo, "nvim/init.lua"), "one\n");
+        assert_eq!(packages, ["nvim", "zsh"]);
+        let master = repo.find_branch("master", git2::BranchType::Local).unwrap();
+        assert_eq!(master.get().target(), Some(third));
+    }
+
+    #[test]
+    fn drops_the_safety_branch_when_checkout_fails() {
+        let (_dir, repo, [first, _, third]) = history();
+        testing::write(&repo, "nvim/init.lua", "local edit\n");
+        assert!(checkout_safety_branch(&repo, first).is_err());
+        assert_eq!(safety_branches(&repo), 0);
+        assert_eq!(repo.head().unwrap().target(), Some(third));
+        assert_eq!(read(&repo, "nvim/init.lua"), "local edit\n");
+    }
+
+    #[test]
+    fn reverts_a_commit() {
+        let (_dir, repo, [_, second, third]) = history();
+        let packages = revert_commit(&repo, second).unwrap();
+        assert_eq!(packages, ["nvim"]);
+        let head = repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(head.parent_id(0).unwrap(), third);
+        assert!(head
+            .summary()
+            .unwrap()
+            .starts_with("Revert \"Change nvim\""));
+        assert_eq!(read(&repo, "nvim/init.lua"), "one\n");
+        assert_eq!(repo.state(), git2::RepositoryState::Clean);
+    }
+
+    #[test]
+    fn resets_a_conflicting_revert() {
+        let (_dir, repo, [_, second, third]) = history();
+        testing::stage(&repo, "nvim/init.lua", "three\n");
+        testing::commit(&repo, "Change nvim again");
+        testing::write(&repo, "zsh/.zshrc", "local edit\n");
+        assert!(revert_commit(&repo, second).is_err());
+        assert_eq!(repo.state(), git2::RepositoryState::Clean);
+        assert!(!repo.index().unwrap().has_conflicts());
+        assert_eq!(read(&repo, "nvim/init.lua"), "three\n");
+        assert_eq!(read(&repo, "zsh/.zshrc"), "local edit\n");
+        let head = repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(head.parent_id(0).unwrap(), third);
+    }
+
+    #[test]
+    fn keeps_staged_changes_when_a_revert_conflicts() {
+        let (_dir, repo, [_, second, _]) = history();
+        testing::stage(&repo, "nvim/init.lua", "three\n");
+        let fourth = testing::commit(&repo, "Change nvim again");
+        testing::stage(&repo, "zsh/.zshrc", "staged\n");
+
+        let error = revert_commit(&repo, second).unwrap_err();
+        assert!(error.message().contains("staged changes"));
+        assert_eq!(repo.state(), git2::RepositoryState::Clean);
+        assert_eq!(repo.head().unwrap().target(), Some(fourth));
+        assert_eq!(read(&repo, "zsh/.zshrc"), "staged\n");
+        let staged = repo
+            .index()
+            .unwrap()
+            .get_path(std::path::Path::new("zsh/.zshrc"), 0)
+            .unwrap();
+        let blob = Oid::hash_object(git2::ObjectType::Blob, b"staged\n").unwrap();
+        assert_eq!(staged.id, blob);
+    }
+
+    #[test]
+    fn hard_resets_to_a_commit() {
+        let (_dir, repo, [first, _, _]) = history();
+        let packages = hard_reset(&repo, first).unwrap();
+        assert_eq!(packages, ["nvim", "zsh"]);
+        assert_eq!(repo.head().unwrap().target(), Some(first));
+        assert_eq!(read(&repo, "nvim/init.lua"), "one\n");
+        assert!(!repo.workdir().unwrap().join("zsh/.zshrc").exists());
+    }
+}
@@ -0,0 +1,351 @@
+use crate::config::{self, Config, LayerConfig};
+use crate::remote::RemoteSettings;
+use crate::sparse;
+use std::collections::BTreeMap;
+use std::fs;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+
+// Name of the main ~/.dotfiles repository, the bottom layer
+const MAIN_LAYER: &str = "main";
+
+// A checkout contributing packages, in increasing order of precedence
+pub struct Layer {
+    pub name: String,
+    pub path: PathBuf,
+}
+
+// Layer repositories are cloned next to each other in ~/.dotfiles.d
+pub fn layer_path(layer: &LayerConfig) -> PathBuf {
+    config::expand_home("~/.dotfiles.d").join(&layer.name)
+}
+
+pub fn remote_settings(layer: &LayerConfig, config: &Config) -> RemoteSettings {
+    RemoteSettings {
+        url: layer.url.clone(),
+        branch: layer.branch.clone(),
+        ssh_key: config.ssh_key.clone(),
+        sparse_packages: None,
+        bare: false,
+    }
+}
+
+// The main repository followed by the configured layers
+pub fn layers(main: &Path, config: &Config) -> Vec<Layer> {
+    let mut layers = vec![Layer {
+        name: String::from(MAIN_LAYER),
+        path: main.to_path_buf(),
+    }];
+    layers.extend(config.layers.iter().map(|layer| Layer {
+        name: layer.name.clone(),
+        path: layer_path(layer),
+    }));
+    layers
+}
+
+fn collect_files(dir: &Path, prefix: &Path, files: &mut Vec<PathBuf>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+        let name = entry.file_name();
+        let relative = prefix.join(&name);
+        if file_type.is_dir() {
+            if name != ".git" {
+                collect_files(&entry.path(), &relative, files);
+            }
+        } else if !name.to_string_lossy().ends_with(".age") {
+            // Encrypted files are decrypted into place, never linked
+            files.push(relative);
+        }
+    }
+}
+
+// Every file the packages deploy, relative to $HOME, with the layers that
+// provide it. The last provider wins.
+pub fn resolve(layers: &[Layer], packages: &[String]) -> BTreeMap<PathBuf, Vec<(usize, PathBuf)>> {
+    let mut files: BTreeMap<PathBuf, Vec<(usize, PathBuf)>> = BTreeMap::new();
+    for (index, layer) in layers.iter().enumerate() {
+        for package in packages {
+            let package_dir = layer.path.join(package);
+            let mut relative_files = Vec::new();
+            collect_files(&package_dir, Path::new(""), &mut relative_files);
+            for relative in relative_files {
+                let source = package_dir.join(&relative);
+                files.entry(relative).or_default().push((index, source));
+            }
+        }
+    }
+    files
+}
+
+fn resolved(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.to_path_buf())
+}
+
+// Where a link into one of the layers points, following relative links such
+// as the ones stow creates
+fn points_into_layers(target: &Path, layers: &[Layer]) -> Option<PathBuf> {
+    let link = fs::read_link(target).ok()?;
+    let absolute = match target.parent() {
+        Some(parent) if link.is_relative() => parent.join(&link),
+        _ => link,
+    };
+    let destination = resolved(&absolute);
+    layers
+        .iter()
+        .any(|layer| destination.starts_with(resolved(&layer.path)))
+        .then_some(destination)
+}
+
+// A parent directory that is a stow-folded link into a checkout would make
+// new links land inside the repository
+fn inside_layers(target: &Path, layers: &[Layer]) -> bool {
+    let Some(parent) = target
+        .parent()
+        .and_then(|parent| parent.canonicalize().ok())
+    else {
+        return false;
+    };
+    layers
+        .iter()
+        .any(|layer| parent.starts_with(resolved(&layer.path)))
+}
+
+// Link every file of the packages from the highest layer providing it.
+// Links into a layer are replaced, anything else in the way is reported.
+pub fn link(layers: &[Layer], home: &Path, packages: &[String]) -> String {
+    let mut linked = 0;
+    let mut unchanged = 0;
+    let mut conflicts = Vec::new();
+    for (relative, providers) in resolve(layers, packages) {
+        let Some((_, source)) = providers.last() else {
+            continue;
+        };
+        let target = home.join(&relative);
+        if fs::symlink_metadata(&target).is_ok() {
+            match points_into_layers(&target, layers) {
+                Some(link) if link == resolved(source) => {
+                    unchanged += 1;
+                    continue;
+                }
+                Some(_) => {
+                    if let Err(e) = fs::remove_file(&target) {
+                        conflicts.push(format!("{}: {}", relative.display(), e));
+                        continue;
+                    }
+                }
+                None => {
+                    conflicts.push(format!("{}: existing file", relative.display()));
+                    continue;
+                }
+            }
+        } else if inside_layers(&target, layers) {
+            conflicts.push(format!(
+                "{}: parent directory is a link into a repository",
+                relative.display()
+            ));
+            continue;
+        }
+
+        let result = match target.parent() {
+            Some(parent) => fs::create_dir_all(parent),
+            None => Ok(()),
+        }
+        .and_then(|_| symlink(source, &target));
+        match result {
+            Ok(()) => linked += 1,
+            Err(e) => conflicts.push(format!("{}: {}", relative.display(), e)),
+        }
+    }
+
+    let mut report = format!(
+        "Linked {} files from {} layers, {} already linked.",
+        linked,
+        layers.len(),
+        unchanged
+    );
+    if !conflicts.is_empty() {
+        report.push_str("\n\nNot linked:");
+        for conflict in conflicts {
+            report.push_str(&format!("\n- {}", conflict));
+        }
+    }
+    report
+}
+
+// Remove the links of the packages that point into any layer
+pub fn unlink(layers: &[Layer], home: &Path, packages: &[String]) -> String {
+    let mut removed = 0;
+    for relative in resolve(layers, packages).keys() {
+        let target = home.join(relative);
+        if points_into_layers(&target, layers).is_some() && fs::remove_file(&target).is_ok() {
+            removed += 1;
+            sparse::remove_empty_parents(&target, home);
+        }
+    }
+    format!("Removed {} links.", removed)
+}
+
+// Which layer each deployed file of the packages comes from
+pub fn describe(layers: &[Layer], home: &Path, packages: &[String]) -> String {
+    let files = resolve(layers, packages);
+    if files.is_empty() {
+        return String::from("No files to deploy.");
+    }
+    let mut report = format!(
+        "Layers, lowest precedence first: {}\n",
+        layers
+            .iter()
+            .map(|layer| layer.name.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    for (relative, providers) in files {
+        let Some(((winner, source), overridden)) = providers.split_last() else {
+            continue;
+        };
+        let target = home.join(&relative);
+        let state = match points_into_layers(&target, layers) {
+            Some(link) if link == resolved(source) => "",
+            Some(_) => " (linked to another layer, relink)",
+            None if fs::symlink_metadata(&target).is_ok() => " (blocked by an existing file)",
+            None => " (not linked)",
+        };
+        report.push_str(&format!(
+            "\n~/{} <- {}{}",
+            relative.display(),
+            layers[*winner].name,
+            state
+        ));
+        if !overridden.is_empty() {
+            let names: Vec<&str> = overridden
+                .iter()
+                .map(|(index, _)| layers[*index].name.as_str())
+                .collect();
+            report.push_str(&format!(", overrides {}", names.join(", ")));
+        }
+    }
+    report
+}
+
+// Packages found in any layer
+pub fn packages(layers: &[Layer]) -> Vec<String> {
+    let mut packages: Vec<String> = layers
+        .iter()
+        .filter_map(|layer| fs::read_dir(&layer.path).ok())
+        .flat_map(|entries| entries.flatten())
+        .filter(|entry| entry.file_type().map(|t| t.is_dir()).unwrap_or(false))
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .filter(|name| !name.starts_with('.'))
+        .collect();
+    packages.sort();
+    packages.dedup();
+    packages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    // The main repository and a `work` layer over it, with $HOME next to them
+    fn setup() -> (TempDir, Vec<Layer>, PathBuf) {
+        let dir = TempDir::new().unwrap();
+        let layers = vec![
+            Layer {
+                name: String::from(MAIN_LAYER),
+                path: dir.path().join("dotfiles"),
+            },
+            Layer {
+                name: String::from("work"),
+                path: dir.path().join("work"),
+            },
+        ];
+        let home = dir.path().join("home");
+        fs::create_dir_all(&home).unwrap();
+        (dir, layers, home)
+    }
+
+    fn write(layer: &Layer, path: &str) {
+        let file = layer.path.join(path);
+        fs::create_dir_all(file.parent().unwrap()).unwrap();
+        fs::write(file, path).unwrap();
+    }
+
+    fn packages() -> Vec<String> {
+        vec![String::from("git")]
+    }
+
+    #[test]
+    fn resolves_files_to_every_layer_providing_them() {
+        let (_dir, layers, _) = setup();
+        write(&layers[0], "git/.gitconfig");
+        write(&layers[0], "git/.config/git/ignore");
+        write(&layers[1], "git/.gitconfig");
+        write(&layers[1], "git/.token.age");
+
+        let files = resolve(&layers, &packages());
+
+        let paths: Vec<&Path> = files.keys().map(PathBuf::as_path).collect();
+        assert_eq!(
+            paths,
+            [Path::new(".config/git/ignore"), Path::new(".gitconfig")]
+        );
+        let providers: Vec<usize> = files[Path::new(".gitconfig")]
+            .iter()
+            .map(|(index, _)| *index)
+            .collect();
+        assert_eq!(providers, [0, 1]);
+    }
+
+    #[test]
+    fn links_the_highest_layer() {
+        let (_dir, layers, home) = setup();
+        write(&layers[0], "git/.gitconfig");
+        write(&layers[0], "git/.config/git/ignore");
+        write(&layers[1], "git/.gitconfig");
+
+        link(&layers, &home, &packages());
+
+        assert_eq!(
+            fs::read_link(home.join(".gitconfig")).unwrap(),
+            layers[1].path.join("git/.gitconfig")
+        );
+        assert_eq!(
+            fs::read_link(home.join(".config/git/ignore")).unwrap(),
+            layers[0].path.join("git/.config/git/ignore")
+        );
+    }
+
+    #[test]
+    fn replaces_links_into_a_lower_layer() {
+        let (_dir, layers, home) = setup();
+        write(&layers[0], "git/.gitconfig");
+        link(&layers, &home, &packages());
+        write(&layers[1], "git/.gitconfig");
+
+        let report = link(&layers, &home, &packages());
+
+        assert!(report.starts_with("Linked 1 files"));
+        assert_eq!(
+            fs::read_link(home.join(".gitconfig")).unwrap(),
+            layers[1].path.join("git/.gitconfig")
+        );
+    }
+
+    #[test]
+    fn leaves_other_files_alone() {
+        let (_dir, layers, home) = setup();
+        write(&layers[0], "git/.gitconfig");
+        fs::write(home.join(".gitconfig"), "mine").unwrap();
+
+        let report = link(&layers, &home, &packages());
+
+        assert!(report.contains(".gitconfig: existing file"));
+        assert_eq!(fs::read_to_string(home.join(".gitconfig")).unwrap(), "mine");
+    }
+}
@@ -1,5 +1,30 @@
+mod backup;
+mod bare;
+mod branches;
+mod bundle;
+mod commit;
+mod compare;
+mod compression;
+mod config;
+mod encryption;
+mod history;
+mod layers;
+mod manifest;
+mod nvim;
+mod remote;
+mod retention;
+mod secrets;
+mod sparse;
+mod state;
+mod store;
+mod submodules;
+mod sync;
+mod task;
 mod terminal_loop;
+#[cfg(test)]
+mod testing;
 mod utils;
+mod verify;
 use crossterm::{
     execute,
     terminal::{self},
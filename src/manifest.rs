@@ -0,0 +1,356 @@
+use crate::backup;
+use crate::encryption::{self, BackupKeys};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+// Written as `<archive>.manifest` next to every archive, encrypted like the
+// archive so the paths and checksums of its files stay private
+const EXTENSION: &str = ".manifest";
+const HEADER: &str = "# type\tsize\tmtime\tsha256\tpath (escaped)";
+// Manifests written before paths were escaped
+const RAW_HEADER: &str = "# type\tsize\tmtime\tsha256\tpath";
+
+// What one archive entry held when it was written. Symlinks are hashed by
+// their target, directories have no hash.
+#[derive(Clone, PartialEq)]
+pub struct ManifestEntry {
+    pub kind: char,
+    pub size: u64,
+    pub mtime: u64,
+    pub sha256: String,
+}
+
+impl ManifestEntry {
+    pub fn directory(mtime: u64) -> Self {
+        Self {
+            kind: 'd',
+            size: 0,
+            mtime,
+            sha256: String::from("-"),
+        }
+    }
+
+    pub fn symlink(target: &[u8], mtime: u64) -> Self {
+        Self {
+            kind: 'l',
+            size: target.len() as u64,
+            mtime,
+            sha256: sha256(target),
+        }
+    }
+}
+
+pub type Manifest = BTreeMap<String, ManifestEntry>;
+
+pub fn manifest_path(archive: &Path) -> PathBuf {
+    let mut path = archive.as_os_str().to_owned();
+    path.push(EXTENSION);
+    PathBuf::from(path)
+}
+
+// Hashes everything read through it
+pub struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R: Read> HashingReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    pub fn finish(self) -> String {
+        format!("{:x}", self.hasher.finalize())
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+pub fn sha256(bytes: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(bytes))
+}
+
+// File names may hold any byte but `/` and NUL, so the separators of a manifest
+// line and the escape character itself are written as `\t`, `\n`, `\r`, `\\`
+fn escape(path: &str) -> String {
+    let mut escaped = String::with_capacity(path.len());
+    for c in path.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\t' => escaped.push_str("\\t"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn unescape(text: &str) -> Option<String> {
+    let mut path = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            path.push(c);
+            continue;
+        }
+        path.push(match chars.next()? {
+            '\\' => '\\',
+            't' => '\t',
+            'n' => '\n',
+            'r' => '\r',
+            _ => return None,
+        });
+    }
+    Some(path)
+}
+
+pub fn write(
+    archive: &Path,
+    manifest: &Manifest,
+    encryptor: Option<age::Encryptor>,
+) -> io::Result<()> {
+    let mut content = format!("{}\n", HEADER);
+    for (path, entry) in manifest {
+        content.push_str(&format!(
+            "{}\t{}\t{}\t{}\t{}\n",
+            entry.kind,
+            entry.size,
+            entry.mtime,
+            entry.sha256,
+            escape(path)
+        ));
+    }
+    let content = match encryptor {
+        Some(encryptor) => encryption::encrypt_bytes(encryptor, content.as_bytes())?,
+        None => content.into_bytes(),
+    };
+    backup::write_atomic(&manifest_path(archive), &content)
+}
+
+// The manifest of an archive, None for archives written without one
+pub fn read(archive: &Path, keys: &BackupKeys) -> Result<Option<Manifest>, String> {
+    let path = manifest_path(archive);
+    let mut content = String::new();
+    let read = encryption::archive_reader(&path, keys)
+        .and_then(|mut reader| reader.read_to_string(&mut content));
+    match read {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e)),
+    }
+    let escaped = !content.starts_with(&format!("{}\n", RAW_HEADER));
+    let mut manifest = Manifest::new();
+    for (number, line) in content.lines().enumerate() {
+        if line.starts_with('#') || line.is_empty() {
+            continue;
+        }
+        let invalid = || format!("{}:{}: invalid manifest line", path.display(), number + 1);
+        let fields: Vec<&str> = line.splitn(5, '\t').collect();
+        let [kind, size, mtime, sha256, entry_path] = fields[..] else {
+            return Err(invalid());
+        };
+        let entry_path = if escaped {
+            unescape(entry_path).ok_or_else(invalid)?
+        } else {
+            entry_path.to_string()
+        };
+        manifest.insert(
+            entry_path,
+            ManifestEntry {
+                kind: kind.chars().next().ok_or_else(invalid)?,
+                size: size.parse().map_err(|_| invalid())?,
+                mtime: mtime.parse().map_err(|_| invalid())?,
+                sha256: sha256.to_string(),
+            },
+        );
+    }
+    Ok(Some(manifest))
+}
+
+// Remove manifests whose archive was pruned or deleted
+pub fn remove_orphaned(dir: &Path) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let name = entry.file_name().to_string_lossy().to_string();
+        if let Some(archive) = name.strip_suffix(EXTENSION) {
+            if !dir.join(archive).exists() {
+                let _ = fs::remove_file(&path);
+            }
+        }
+    }
+}
+
+// Differences between the manifest and what an archive actually holds
+pub fn compare(expected: &Manifest, actual: &Manifest) -> Vec<String> {
+    let mut problems = Vec::new();
+    for (path, entry) in expected {
+        match actual.get(path) {
+            None => problems.push(format!("{}: missing from the archive", path)),
+            Some(found) if found.kind != entry.kind => {
+                problems.push(format!("{}: type changed", path))
+            }
+            Some(found) if found.size != entry.size => problems.push(format!(
+                "{}: size {} instead of {}",
+                path, found.size, entry.size
+            )),
+            Some(found) if found.sha256 != entry.sha256 => {
+                problems.push(format!("{}: checksum mismatch", path))
+            }
+            Some(found) if found.mtime != entry.mtime => {
+                problems.push(format!("{}: modification time changed", path))
+            }
+            Some(_) => {}
+        }
+    }
+    for path in actual.keys() {
+        if !expected.contains_key(path) {
+            problems.push(format!("{}: not in the manifest", path));
+        }
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::EncryptionConfig;
+    use age::secrecy::ExposeSecret;
+    use std::iter;
+    use tempfile::TempDir;
+
+    fn odd_paths() -> Manifest {
+        let mut manifest = Manifest::new();
+        for (index, path) in [
+            "plain/file.txt",
+            "tab\there",
+            "new\nline",
+            "carriage\rreturn",
+            "back\\slash",
+            "literal \\t and \\n",
+            "trailing\\",
+            "ünïcödé/名前",
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            manifest.insert(
+                path.to_string(),
+                ManifestEntry {
+                    kind: 'f',
+                    size: index as u64,
+                    mtime: 1_700_000_000 + index as u64,
+                    sha256: sha256(path.as_bytes()),
+                },
+            );
+        }
+        manifest.insert(String::from("plain"), ManifestEntry::directory(1));
+        manifest.insert(
+            String::from("link\twith tab"),
+            ManifestEntry::symlink(b"target\nwith newline", 2),
+        );
+        manifest
+    }
+
+    fn plain_keys(config: &EncryptionConfig) -> BackupKeys<'_> {
+        BackupKeys {
+            config,
+            passphrase: None,
+        }
+    }
+
+    #[test]
+    fn round_trips_odd_paths() {
+        let dir = TempDir::new().unwrap();
+        let archive = dir.path().join("backup.tar.gz");
+        let manifest = odd_paths();
+        write(&archive, &manifest, None).unwrap();
+        let config = EncryptionConfig::default();
+        let read = read(&archive, &plain_keys(&config)).unwrap().unwrap();
+        assert!(compare(&manifest, &read).is_empty());
+        assert_eq!(read.len(), manifest.len());
+        // Every entry stays on one line of five fields
+        let content = fs::read_to_string(manifest_path(&archive)).unwrap();
+        assert_eq!(content.lines().count(), manifest.len() + 1);
+        assert!(content
+            .lines()
+            .skip(1)
+            .all(|line| line.split('\t').count() == 5));
+    }
+
+    #[test]
+    fn round_trips_encrypted() {
+        let dir = TempDir::new().unwrap();
+        let archive = dir.path().join("backup.tar.gz.age");
+        let identity = age::x25519::Identity::generate();
+        let identity_file = dir.path().join("identity.txt");
+        fs::write(&identity_file, identity.to_string().expose_secret()).unwrap();
+        let encryptor = age::Encryptor::with_recipients(iter::once(
+            &identity.to_public() as &dyn age::Recipient
+        ))
+        .unwrap();
+        let manifest = odd_paths();
+        write(&archive, &manifest, Some(encryptor)).unwrap();
+
+        let content = fs::read(manifest_path(&archive)).unwrap();
+        assert!(!content
+            .windows(HEADER.len())
+            .any(|w| w == HEADER.as_bytes()));
+        let config = EncryptionConfig {
+            identity: Some(identity_file.to_string_lossy().to_string()),
+            recipients: Vec::new(),
+        };
+        let read = read(&archive, &plain_keys(&config)).unwrap().unwrap();
+        assert!(compare(&manifest, &read).is_empty());
+    }
+
+    #[test]
+    fn reads_manifests_written_before_escaping() {
+        let dir = TempDir::new().unwrap();
+        let archive = dir.path().join("backup.tar.gz");
+        fs::write(
+            manifest_path(&archive),
+            format!("{}\nf\t3\t5\tabc\tback\\slash\n", RAW_HEADER),
+        )
+        .unwrap();
+        let config = EncryptionConfig::default();
+        let read = read(&archive, &plain_keys(&config)).unwrap().unwrap();
+        assert_eq!(read.keys().collect::<Vec<_>>(), ["back\\slash"]);
+    }
+
+    #[test]
+    fn rejects_unknown_escapes() {
+        let dir = TempDir::new().unwrap();
+        let archive = dir.path().join("backup.tar.gz");
+        fs::write(
+            manifest_path(&archive),
+            format!("{}\nf\t3\t5\tabc\tbad\\x\n", HEADER),
+        )
+        .unwrap();
+        let config = EncryptionConfig::default();
+        assert!(read(&archive, &plain_keys(&config)).is_err());
+    }
+
+    #[test]
+    fn archives_without_a_manifest_read_as_none() {
+        let dir = TempDir::new().unwrap();
+        let config = EncryptionConfig::default();
+        let read = read(&dir.path().join("backup.tar.gz"), &plain_keys(&config)).unwrap();
+        assert!(read.is_none());
+    }
+}
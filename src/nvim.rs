@@ -0,0 +1,291 @@
+use crate::history;
+use crate::task::{self, TaskEvent};
+use git2::{Oid, Repository};
+use similar::TextDiff;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::mpsc::Sender;
+
+// lazy.nvim pins the commit of every plugin here, next to init.lua
+pub const LOCKFILE: &str = "lazy-lock.json";
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum PluginManager {
+    Lazy,
+    Packer,
+}
+
+impl PluginManager {
+    pub fn name(self) -> &'static str {
+        match self {
+            PluginManager::Lazy => "lazy.nvim",
+            PluginManager::Packer => "packer.nvim",
+        }
+    }
+}
+
+fn xdg_dir(variable: &str, fallback: &str) -> PathBuf {
+    let dir = match env::var(variable) {
+        Ok(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var("HOME").unwrap_or_default()).join(fallback),
+    };
+    dir.join("nvim")
+}
+
+pub fn lockfile_path() -> PathBuf {
+    xdg_dir("XDG_CONFIG_HOME", ".config").join(LOCKFILE)
+}
+
+// Whichever plugin manager NeoVim bootstrapped into its data directory
+pub fn detect() -> Option<PluginManager> {
+    let data = xdg_dir("XDG_DATA_HOME", ".local/share");
+    if data.join("lazy/lazy.nvim").exists() || lockfile_path().exists() {
+        return Some(PluginManager::Lazy);
+    }
+    let packer = data.join("site/pack/packer");
+    if packer.join("start/packer.nvim").exists() || packer.join("opt/packer.nvim").exists() {
+        return Some(PluginManager::Packer);
+    }
+    None
+}
+
+// Run NeoVim without a UI, returning what it printed
+fn headless(args: &[&str]) -> Result<String, String> {
+    let output = Command::new("nvim")
+        .arg("--headless")
+        .args(args)
+        .output()
+        .map_err(|e| format!("Failed to run nvim: {}", e))?;
+    let printed = format!(
+        "{}{}",
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    )
+    .trim()
+    .to_string();
+    if !output.status.success() {
+        return Err(format!("nvim exited with {}: {}", output.status, printed));
+    }
+    Ok(printed)
+}
+
+// Plugins of a lockfile, which lazy.nvim writes one per line as
+// `"name": { "branch": ..., "commit": ... },`
+fn lock_entries(content: &str) -> BTreeMap<&str, &str> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let (name, pin) = line.trim().split_once("\":")?;
+            Some((name.strip_prefix('"')?, pin.trim().trim_end_matches(',')))
+        })
+        .collect()
+}
+
+// Plugins updated, added and removed between two lockfiles, then the diff
+pub fn lockfile_diff(before: &str, after: &str) -> String {
+    if before == after {
+        return format!("{} is unchanged.", LOCKFILE);
+    }
+    let old = lock_entries(before);
+    let new = lock_entries(after);
+    let list = |names: Vec<&str>| {
+        if names.is_empty() {
+            String::from("none")
+        } else {
+            names.join(", ")
+        }
+    };
+    let updated = new
+        .iter()
+        .filter(|(name, pin)| old.get(*name).is_some_and(|old_pin| old_pin != *pin))
+        .map(|(name, _)| *name)
+        .collect();
+    let added = new
+        .keys()
+        .filter(|name| !old.contains_key(*name))
+        .copied()
+        .collect();
+    let removed = old
+        .keys()
+        .filter(|name| !new.contains_key(*name))
+        .copied()
+        .collect();
+    format!(
+        "Updated: {}\nAdded: {}\nRemoved: {}\n\n{}",
+        list(updated),
+        list(added),
+        list(removed),
+        TextDiff::from_lines(before, after)
+            .unified_diff()
+            .header(&format!("a/{}", LOCKFILE), &format!("b/{}", LOCKFILE))
+    )
+}
+
+// Update every plugin to its latest version and report how the lockfile
+// changed. Runs as a background task, NeoVim can take a while.
+pub fn sync(sender: &Sender<TaskEvent>) -> String {
+    let Some(manager) = detect() else {
+        return String::from("Neither lazy.nvim nor packer.nvim is installed for NeoVim.");
+    };
+    let lockfile = lockfile_path();
+    let before = fs::read_to_string(&lockfile).unwrap_or_default();
+    task::progress(
+        sender,
+        format!("Syncing plugins with {}...", manager.name()),
+        0,
+        1,
+    );
+    let synced = match manager {
+        PluginManager::Lazy => headless(&["+Lazy! sync", "+qa"]),
+        PluginManager::Packer => headless(&[
+            "-c",
+            "autocmd User PackerComplete quitall",
+            "-c",
+            "PackerSync",
+        ]),
+    };
+    let printed = match synced {
+        Ok(printed) => printed,
+        Err(e) => return format!("Failed to sync plugins: {}", e),
+    };
+    let mut report = format!("Plugins synced with {}.", manager.name());
+    if manager == PluginManager::Lazy {
+        let after = fs::read_to_string(&lockfile).unwrap_or_default();
+        report.push_str(&format!("\n\n{}", lockfile_diff(&before, &after)));
+    }
+    if !printed.is_empty() {
+        report.push_str(&format!("\n\nNeoVim output:\n{}", printed));
+    }
+    report
+}
+
+// Where the lockfile lives inside the repository. Stow links it from the
+// package, a bare repository tracks it in $HOME directly.
+pub fn lockfile_in_repo(repo: &Repository) -> Result<PathBuf, String> {
+    let lockfile = lockfile_path();
+    let resolved = fs::canonicalize(&lockfile)
+        .map_err(|e| format!("Failed to resolve {}: {}", lockfile.display(), e))?;
+    let workdir = repo
+        .workdir()
+        .and_then(|dir| fs::canonicalize(dir).ok())
+        .ok_or("The dotfiles repository has no work tree")?;
+    resolved
+        .strip_prefix(&workdir)
+        .map(Path::to_path_buf)
+        .map_err(|_| format!("{} is not in the dotfiles repository.", lockfile.display()))
+}
+
+// Commits that changed the lockfile, newest first
+pub fn lockfile_history(repo: &Repository, path: &Path) -> Result<Vec<(Oid, String)>, git2::Error> {
+    let mut revwalk = repo.revwalk()?;
+    revwalk.push_head()?;
+    revwalk.set_sorting(git2::Sort::TIME)?;
+    let mut commits = Vec::new();
+    for oid in revwalk {
+        let commit = repo.find_commit(oid?)?;
+        let blob = commit.tree()?.get_path(path).ok().map(|entry| entry.id());
+        let parent_blob = match commit.parent(0) {
+            Ok(parent) => parent.tree()?.get_path(path).ok().map(|entry| entry.id()),
+            Err(_) => None,
+        };
+        if blob.is_some() && blob != parent_blob {
+            commits.push((commit.id(), history::commit_line(&commit)));
+        }
+    }
+    Ok(commits)
+}
+
+// Write the lockfile as it was in a commit, returning how it changed
+pub fn restore_lockfile(repo: &Repository, oid: Oid, path: &Path) -> Result<String, String> {
+    let content = repo
+        .find_commit(oid)
+        .and_then(|commit| commit.tree()?.get_path(path))
+        .and_then(|entry| repo.find_blob(entry.id()))
+        .map_err(|e| format!("Failed to read {} from {}: {}", LOCKFILE, oid, e))?;
+    let restored = String::from_utf8_lossy(content.content()).to_string();
+    let lockfile = lockfile_path();
+    let current = fs::read_to_string(&lockfile).unwrap_or_default();
+    // Writing through the stow symlink updates the file in the repository
+    fs::write(&lockfile, &restored)
+        .map_err(|e| format!("Failed to write {}: {}", lockfile.display(), e))?;
+    Ok(lockfile_diff(&current, &restored))
+}
+
+// Check out the plugin commits the lockfile pins
+pub fn restore_plugins() -> String {
+    match headless(&["+Lazy! restore", "+qa"]) {
+        Ok(printed) if printed.is_empty() => String::from("Plugins restored to the lockfile."),
+        Ok(printed) => format!(
+            "Plugins restored to the lockfile.\n\nNeoVim output:\n{}",
+            printed
+        ),
+        Err(e) => format!("Failed to restore plugins: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    const BEFORE: &str = r#"{
+  "lazy.nvim": { "branch": "main", "commit": "aaa" },
+  "telescope.nvim": { "branch": "master", "commit": "bbb" },
+  "nvim-cmp": { "branch": "main", "commit": "ccc" }
+}
+"#;
+
+    const AFTER: &str = r#"{
+  "lazy.nvim": { "branch": "main", "commit": "aaa" },
+  "telescope.nvim": { "branch": "master", "commit": "ddd" },
+  "oil.nvim": { "branch": "master", "commit": "eee" }
+}
+"#;
+
+    #[test]
+    fn reads_one_plugin_per_line() {
+        let entries = lock_entries(BEFORE);
+        assert_eq!(entries.len(), 3);
+        assert_eq!(
+            entries["telescope.nvim"],
+            r#"{ "branch": "master", "commit": "bbb" }"#
+        );
+    }
+
+    #[test]
+    fn reports_updated_added_and_removed_plugins() {
+        let diff = lockfile_diff(BEFORE, AFTER);
+        assert!(diff.starts_with("Updated: telescope.nvim\nAdded: oil.nvim\nRemoved: nvim-cmp\n\n"));
+        assert!(diff.contains(r#"+  "oil.nvim": { "branch": "master", "commit": "eee" }"#));
+    }
+
+    #[test]
+    fn reports_an_unchanged_lockfile() {
+        assert_eq!(
+            lockfile_diff(BEFORE, BEFORE),
+            format!("{} is unchanged.", LOCKFILE)
+        );
+    }
+
+    #[test]
+    fn lists_commits_that_changed_the_lockfile() {
+        let (_dir, repo) = testing::repo();
+        let path = Path::new("nvim/.config/nvim/lazy-lock.json");
+        testing::stage(&repo, "nvim/.config/nvim/lazy-lock.json", BEFORE);
+        let first = testing::commit(&repo, "Add lockfile");
+        testing::stage(&repo, "zsh/.zshrc", "");
+        testing::commit(&repo, "Add zsh");
+        testing::stage(&repo, "nvim/.config/nvim/lazy-lock.json", AFTER);
+        let second = testing::commit(&repo, "Update plugins");
+
+        let commits: Vec<Oid> = lockfile_history(&repo, path)
+            .unwrap()
+            .into_iter()
+            .map(|(oid, _)| oid)
+            .collect();
+        assert_eq!(commits, [second, first]);
+    }
+}
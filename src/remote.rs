@@ -0,0 +1,218 @@
+use crate::bare;
+use crate::config::{self, Config};
+use crate::sparse;
+use crate::submodules;
+use crate::task::{self, TaskEvent};
+use git2::build::{CheckoutBuilder, RepoBuilder};
+use git2::{Cred, CredentialType, FetchOptions, PushOptions, RemoteCallbacks, Repository};
+use std::cell::RefCell;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Sender;
+
+// Private keys tried in order when no agent identity is accepted
+const SSH_KEY_FILES: [&str; 3] = ["id_ed25519", "id_ecdsa", "id_rsa"];
+
+// Remote settings needed by clone and fetch, owned so they can move into a task
+#[derive(Clone)]
+pub struct RemoteSettings {
+    pub url: String,
+    pub branch: Option<String>,
+    pub ssh_key: Option<String>,
+    // Packages to materialise when cloning as a sparse checkout
+    pub sparse_packages: Option<Vec<String>>,
+    // Clone without a working tree, for the `--work-tree=$HOME` layout
+    pub bare: bool,
+}
+
+impl RemoteSettings {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            url: config.repo_url.clone(),
+            branch: config.branch.clone(),
+            ssh_key: config.ssh_key.clone(),
+            sparse_packages: None,
+            bare: config.bare,
+        }
+    }
+}
+
+fn ssh_key_candidates(ssh_key: Option<&str>) -> Vec<PathBuf> {
+    let ssh_dir = PathBuf::from(env::var("HOME").unwrap_or_default()).join(".ssh");
+    match ssh_key {
+        Some(key) => vec![config::expand_home(key)],
+        None => SSH_KEY_FILES
+            .iter()
+            .map(|name| ssh_dir.join(name))
+            .filter(|path| path.exists())
+            .collect(),
+    }
+}
+
+// Authenticate with the ssh-agent, then key files, then git credential helpers.
+// libgit2 calls back again after every rejected credential, so each source is
+// only offered once to avoid looping forever.
+pub fn callbacks<'a>(ssh_key: Option<&str>) -> RemoteCallbacks<'a> {
+    let mut keys = ssh_key_candidates(ssh_key).into_iter();
+    let mut tried_agent = false;
+    let mut tried_helper = false;
+
+    let mut callbacks = RemoteCallbacks::new();
+    callbacks.credentials(move |url, username, allowed| {
+        let user = username.unwrap_or("git");
+        if allowed.contains(CredentialType::USERNAME) {
+            return Cred::username(user);
+        }
+        if allowed.contains(CredentialType::SSH_KEY) {
+            if !tried_agent {
+                tried_agent = true;
+                return Cred::ssh_key_from_agent(user);
+            }
+            if let Some(key) = keys.next() {
+                return Cred::ssh_key(user, None, &key, None);
+            }
+        }
+        if allowed.contains(CredentialType::USER_PASS_PLAINTEXT) && !tried_helper {
+            tried_helper = true;
+            let config = git2::Config::open_default()?;
+            return Cred::credential_helper(&config, url, username);
+        }
+        if allowed.contains(CredentialType::DEFAULT) {
+            return Cred::default();
+        }
+        Err(git2::Error::from_str(
+            "No usable credentials (tried ssh-agent, ~/.ssh keys and git credential helpers)",
+        ))
+    });
+    callbacks
+}
+
+pub fn fetch_options<'a>(ssh_key: Option<&str>) -> FetchOptions<'a> {
+    let mut fetch_options = FetchOptions::new();
+    fetch_options.remote_callbacks(callbacks(ssh_key));
+    fetch_options
+}
+
+// Push a local branch to the branch of the same name on origin. The server
+// reports rejected updates through a callback rather than as an error.
+pub fn push_branch(repo: &Repository, branch: &str, ssh_key: Option<&str>) -> Result<(), String> {
+    let mut remote = repo
+        .find_remote("origin")
+        .map_err(|e| format!("Failed to find remote 'origin': {}", e))?;
+    let rejection = RefCell::new(None);
+
+    let mut remote_callbacks = callbacks(ssh_key);
+    remote_callbacks.push_update_reference(|refname, status| {
+        if let Some(status) = status {
+            *rejection.borrow_mut() = Some(format!("{} rejected: {}", refname, status));
+        }
+        Ok(())
+    });
+    let mut push_options = PushOptions::new();
+    push_options.remote_callbacks(remote_callbacks);
+
+    let refspec = format!("refs/heads/{0}:refs/heads/{0}", branch);
+    remote
+        .push(&[refspec], Some(&mut push_options))
+        .map_err(|e| format!("Failed to push: {}", e))?;
+    drop(push_options);
+
+    match rejection.into_inner() {
+        Some(reason) => Err(format!("Push rejected by origin: {}", reason)),
+        None => Ok(()),
+    }
+}
+
+pub fn format_bytes(bytes: usize) -> String {
+    match bytes {
+        b if b >= 1 << 30 => format!("{:.1} GiB", b as f64 / (1 << 30) as f64),
+        b if b >= 1 << 20 => format!("{:.1} MiB", b as f64 / (1 << 20) as f64),
+        b if b >= 1 << 10 => format!("{:.1} KiB", b as f64 / (1 << 10) as f64),
+        b => format!("{} B", b),
+    }
+}
+
+pub fn clone(settings: &RemoteSettings, path: &Path, sender: &Sender<TaskEvent>) -> String {
+    let mut remote_callbacks = callbacks(settings.ssh_key.as_deref());
+    remote_callbacks.transfer_progress(|stats| {
+        if stats.received_objects() < stats.total_objects() {
+            task::progress(
+                sender,
+                format!(
+                    "Receiving objects {}/{} ({})",
+                    stats.received_objects(),
+                    stats.total_objects(),
+                    format_bytes(stats.received_bytes())
+                ),
+                stats.received_objects(),
+                stats.total_objects(),
+            );
+        } else {
+            task::progress(
+                sender,
+                format!(
+                    "Indexing deltas {}/{} ({})",
+                    stats.indexed_deltas(),
+                    stats.total_deltas(),
+                    format_bytes(stats.received_bytes())
+                ),
+                stats.indexed_deltas(),
+                stats.total_deltas(),
+            );
+        }
+        true
+    });
+
+    let mut fetch_options = FetchOptions::new();
+    fetch_options.remote_callbacks(remote_callbacks);
+
+    let mut checkout = CheckoutBuilder::new();
+    if let Some(packages) = &settings.sparse_packages {
+        for pathspec in sparse::pathspecs(packages) {
+            checkout.path(pathspec);
+        }
+    }
+    checkout.progress(|_, current, total| {
+        task::progress(
+            sender,
+            format!("Checking out files {}/{}", current, total),
+            current,
+            total,
+        );
+    });
+
+    let mut builder = RepoBuilder::new();
+    builder
+        .fetch_options(fetch_options)
+        .with_checkout(checkout)
+        .bare(settings.bare);
+    if let Some(branch) = &settings.branch {
+        builder.branch(branch);
+    }
+
+    let repo = match builder.clone(&settings.url, path) {
+        Ok(repo) => repo,
+        Err(e) => return format!("Failed to clone {}: {}", settings.url, e),
+    };
+
+    let mut output = format!("Repository cloned successfully from {}!", settings.url);
+    if settings.bare {
+        if let Err(e) = bare::configure(&repo) {
+            output.push_str(&format!("\nFailed to configure bare repository: {}", e));
+        }
+        output.push_str("\nLink Dotfiles checks out the tracked files into $HOME.");
+        return output;
+    }
+    if let Some(packages) = &settings.sparse_packages {
+        match sparse::apply(&repo, packages) {
+            Ok(_) => output.push_str(&format!("\nSparse checkout of: {}", packages.join(", "))),
+            Err(e) => output.push_str(&format!("\nFailed to set up sparse checkout: {}", e)),
+        }
+    }
+    task::progress(sender, String::from("Updating submodules..."), 1, 1);
+    output.push_str(&submodules::update_submodules(
+        &repo,
+        settings.ssh_key.as_deref(),
+    ));
+    output
+}
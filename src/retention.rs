@@ -0,0 +1,196 @@
+use crate::config::RetentionConfig;
+use chrono::{Datelike, NaiveDateTime};
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Format of the timestamp at the end of every archive name. Milliseconds keep
+// two backups of the same second apart; they are optional when parsing, so
+// archives named before they were added still count.
+pub const TIMESTAMP_FORMAT: &str = "%Y%m%d-%H%M%S%.3f";
+
+// Archives named `<prefix><timestamp><suffix>` in a directory, newest first
+pub fn timestamped_archives(
+    dir: &Path,
+    prefix: &str,
+    suffixes: &[&str],
+) -> Vec<(NaiveDateTime, PathBuf)> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut archives: Vec<(NaiveDateTime, PathBuf)> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let rest = name.strip_prefix(prefix)?;
+            let timestamp = suffixes
+                .iter()
+                .find_map(|suffix| rest.strip_suffix(suffix))?;
+            let time = NaiveDateTime::parse_from_str(timestamp, TIMESTAMP_FORMAT).ok()?;
+            Some((time, entry.path()))
+        })
+        .collect();
+    archives.sort_by_key(|(time, _)| std::cmp::Reverse(*time));
+    archives
+}
+
+// Split archives, newest first, into the ones the policy keeps and the ones
+// to prune. The newest N are kept, then the newest archive of each of the
+// last days and weeks that have one.
+pub fn select(
+    archives: &[(NaiveDateTime, PathBuf)],
+    policy: &RetentionConfig,
+) -> (Vec<PathBuf>, Vec<PathBuf>) {
+    let mut days = HashSet::new();
+    let mut weeks = HashSet::new();
+    let mut kept = Vec::new();
+    let mut pruned = Vec::new();
+    for (index, (time, path)) in archives.iter().enumerate() {
+        let day = time.date();
+        let week = (time.iso_week().year(), time.iso_week().week());
+        let mut keep = index < policy.keep_last.max(1);
+        if days.len() < policy.keep_daily && !days.contains(&day) {
+            days.insert(day);
+            keep = true;
+        }
+        if weeks.len() < policy.keep_weekly && !weeks.contains(&week) {
+            weeks.insert(week);
+            keep = true;
+        }
+        if keep {
+            kept.push(path.clone());
+        } else {
+            pruned.push(path.clone());
+        }
+    }
+    (kept, pruned)
+}
+
+// Delete the archives the policy does not keep and report what happened
+pub fn prune(archives: &[(NaiveDateTime, PathBuf)], policy: &RetentionConfig) -> String {
+    let (kept, pruned) = select(archives, policy);
+    let mut report = format!(
+        "\n\nRetention: keeping {} archives, pruning {}.",
+        kept.len(),
+        pruned.len()
+    );
+    for path in pruned {
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        match fs::remove_file(&path) {
+            Ok(()) => report.push_str(&format!("\n- pruned {}", name)),
+            Err(e) => report.push_str(&format!("\n- failed to prune {}: {}", name, e)),
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn archives(times: &[&str]) -> Vec<(NaiveDateTime, PathBuf)> {
+        times
+            .iter()
+            .map(|time| {
+                (
+                    NaiveDateTime::parse_from_str(time, TIMESTAMP_FORMAT).unwrap(),
+                    PathBuf::from(time),
+                )
+            })
+            .collect()
+    }
+
+    fn policy(keep_last: usize, keep_daily: usize, keep_weekly: usize) -> RetentionConfig {
+        RetentionConfig {
+            keep_last,
+            keep_daily,
+            keep_weekly,
+        }
+    }
+
+    fn names(paths: &[PathBuf]) -> Vec<&str> {
+        paths.iter().map(|path| path.to_str().unwrap()).collect()
+    }
+
+    #[test]
+    fn keeps_the_newest_archives() {
+        let archives = archives(&[
+            "20240110-120000",
+            "20240110-110000",
+            "20240110-100000",
+            "20240110-090000",
+        ]);
+        let (kept, pruned) = select(&archives, &policy(2, 0, 0));
+        assert_eq!(names(&kept), ["20240110-120000", "20240110-110000"]);
+        assert_eq!(names(&pruned), ["20240110-100000", "20240110-090000"]);
+    }
+
+    #[test]
+    fn lists_archives_with_and_without_milliseconds() {
+        let dir = tempfile::TempDir::new().unwrap();
+        for name in [
+            "nvim-20240110-120000.tar.gz",
+            "nvim-20240110-120000.250.tar.gz",
+            "nvim-20240110-120000.500.tar.zst.age",
+            "nvim-20240110-120000.500.tar.gz.manifest",
+            "zsh-20240110-130000.tar.gz",
+        ] {
+            fs::write(dir.path().join(name), "").unwrap();
+        }
+        let listed: Vec<String> =
+            timestamped_archives(dir.path(), "nvim-", &[".tar.gz", ".tar.zst.age"])
+                .into_iter()
+                .map(|(_, path)| path.file_name().unwrap().to_string_lossy().to_string())
+                .collect();
+        assert_eq!(
+            listed,
+            [
+                "nvim-20240110-120000.500.tar.zst.age",
+                "nvim-20240110-120000.250.tar.gz",
+                "nvim-20240110-120000.tar.gz",
+            ]
+        );
+    }
+
+    #[test]
+    fn always_keeps_the_newest_archive() {
+        let archives = archives(&["20240110-120000", "20240109-120000"]);
+        let (kept, pruned) = select(&archives, &policy(0, 0, 0));
+        assert_eq!(names(&kept), ["20240110-120000"]);
+        assert_eq!(names(&pruned), ["20240109-120000"]);
+    }
+
+    #[test]
+    fn keeps_the_newest_archive_of_each_day() {
+        let archives = archives(&[
+            "20240110-100000",
+            "20240110-090000",
+            "20240109-120000",
+            "20240108-120000",
+        ]);
+        let (kept, pruned) = select(&archives, &policy(1, 2, 0));
+        assert_eq!(names(&kept), ["20240110-100000", "20240109-120000"]);
+        assert_eq!(names(&pruned), ["20240110-090000", "20240108-120000"]);
+    }
+
+    #[test]
+    fn keeps_the_newest_archive_of_each_week() {
+        // ISO weeks 3, 2, 2, 1 of 2024 and week 51 of 2023
+        let archives = archives(&[
+            "20240115-120000",
+            "20240110-120000",
+            "20240109-120000",
+            "20240102-120000",
+            "20231220-120000",
+        ]);
+        let (kept, pruned) = select(&archives, &policy(1, 0, 3));
+        assert_eq!(
+            names(&kept),
+            ["20240115-120000", "20240110-120000", "20240102-120000"]
+        );
+        assert_eq!(names(&pruned), ["20240109-120000", "20231220-120000"]);
+    }
+}
@@ -0,0 +1,335 @@
+use git2::build::CheckoutBuilder;
+use git2::{Commit, Index, IndexEntry, Repository, Status, Tree};
+use std::ffi::OsStr;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+// GIT_INDEX_ENTRY_SKIP_WORKTREE, set on entries outside the sparse set so the
+// git CLI does not report them as deleted. libgit2 ignores the flag, which is
+// why every checkout and status call here is limited to the selected packages.
+const SKIP_WORKTREE: u16 = 1 << 14;
+
+fn sparse_file(repo: &Repository) -> std::path::PathBuf {
+    repo.path().join("info").join("sparse-checkout")
+}
+
+// Packages materialised by a sparse checkout, or None for a full checkout
+pub fn packages(repo: &Repository) -> Option<Vec<String>> {
+    let enabled = repo
+        .config()
+        .and_then(|config| config.get_bool("core.sparseCheckout"))
+        .unwrap_or(false);
+    if !enabled {
+        return None;
+    }
+    let content = fs::read_to_string(sparse_file(repo)).ok()?;
+    Some(
+        content
+            .lines()
+            .filter_map(|line| line.trim().strip_prefix('/')?.strip_suffix('/'))
+            .map(String::from)
+            .collect(),
+    )
+}
+
+// Every stow package in HEAD, whether or not it is materialised
+pub fn list_packages(repo: &Repository) -> Result<Vec<String>, git2::Error> {
+    let tree = repo.head()?.peel_to_tree()?;
+    Ok(tree
+        .iter()
+        .filter(|entry| entry.kind() == Some(git2::ObjectType::Tree))
+        .filter_map(|entry| entry.name().map(String::from))
+        .filter(|name| !name.starts_with('.'))
+        .collect())
+}
+
+// Pathspecs covering the given packages, one directory each
+pub fn pathspecs(packages: &[String]) -> Vec<String> {
+    packages
+        .iter()
+        .map(|package| format!("{}/", package))
+        .collect()
+}
+
+// Files at the top of the repository, in the index and in the given tree.
+// They belong to no package, like .gitmodules, and are always materialised.
+pub fn root_files(repo: &Repository, tree: Option<&Tree>) -> Vec<String> {
+    let mut files: Vec<String> = repo
+        .index()
+        .map(|index| {
+            index
+                .iter()
+                .filter(|entry| !entry.path.contains(&b'/'))
+                .map(|entry| String::from_utf8_lossy(&entry.path).to_string())
+                .collect()
+        })
+        .unwrap_or_default();
+    if let Some(tree) = tree {
+        files.extend(
+            tree.iter()
+                .filter(|entry| entry.kind() == Some(git2::ObjectType::Blob))
+                .filter_map(|entry| entry.name().map(String::from)),
+        );
+    }
+    files.sort();
+    files.dedup();
+    files
+}
+
+fn in_set(path: &[u8], packages: &[String]) -> bool {
+    let path = String::from_utf8_lossy(path);
+    match path.split_once('/') {
+        Some((package, _)) => packages.iter().any(|p| p == package),
+        None => true,
+    }
+}
+
+// A checkout limited to the materialised packages of a sparse repository,
+// including the top-level files of the tree about to be checked out
+pub fn checkout_builder<'a>(repo: &Repository, tree: Option<&Tree>) -> CheckoutBuilder<'a> {
+    let mut checkout = CheckoutBuilder::new();
+    checkout.safe();
+    if let Some(packages) = packages(repo) {
+        for pathspec in pathspecs(&packages) {
+            checkout.path(pathspec);
+        }
+        for file in root_files(repo, tree) {
+            checkout.path(file);
+        }
+    }
+    checkout
+}
+
+// Make exactly the given packages present in the working tree: write git's
+// sparse-checkout file, check out newly selected packages, delete unmodified
+// files of deselected ones and update the skip-worktree bits to match.
+// Returns the paths that were kept because they have local modifications.
+pub fn apply(repo: &Repository, packages: &[String]) -> Result<Vec<String>, git2::Error> {
+    let workdir = repo
+        .workdir()
+        .ok_or_else(|| git2::Error::from_str("Sparse checkout needs a working tree"))?
+        .to_path_buf();
+
+    let info_dir = repo.path().join("info");
+    // The top-level files first, the way git writes a cone-mode set
+    let patterns: String = std::iter::once(String::from("/*\n!/*/\n"))
+        .chain(packages.iter().map(|p| format!("/{}/\n", p)))
+        .collect();
+    fs::create_dir_all(&info_dir)
+        .and_then(|_| fs::write(sparse_file(repo), patterns))
+        .map_err(|e| git2::Error::from_str(&format!("Failed to write sparse-checkout: {}", e)))?;
+    repo.config()?.set_bool("core.sparseCheckout", true)?;
+
+    if !packages.is_empty() {
+        // Files present already, like newly staged ones, are skipped rather
+        // than failing the whole checkout as conflicts
+        let mut checkout = CheckoutBuilder::new();
+        checkout.safe().recreate_missing(true).allow_conflicts(true);
+        for pathspec in pathspecs(packages) {
+            checkout.path(pathspec);
+        }
+        for file in root_files(repo, None) {
+            checkout.path(file);
+        }
+        repo.checkout_index(None, Some(&mut checkout))?;
+    }
+
+    let mut index = repo.index()?;
+    let entries: Vec<IndexEntry> = index.iter().collect();
+    let mut kept = Vec::new();
+    for mut entry in entries {
+        let path = String::from_utf8_lossy(&entry.path).to_string();
+        let skip = if in_set(&entry.path, packages) {
+            false
+        } else {
+            let file = workdir.join(&path);
+            if file.symlink_metadata().is_err() {
+                true
+            } else if repo.status_file(Path::new(&path))? == Status::CURRENT {
+                let _ = fs::remove_file(&file);
+                remove_empty_parents(&file, &workdir);
+                true
+            } else {
+                kept.push(path);
+                false
+            }
+        };
+
+        if skip != (entry.flags_extended & SKIP_WORKTREE != 0) {
+            entry.flags_extended ^= SKIP_WORKTREE;
+            index.add(&entry)?;
+        }
+    }
+    index.write()?;
+    Ok(kept)
+}
+
+// Check out a commit's tree without moving HEAD. In a sparse repository only
+// the materialised packages are written, and libgit2 only updates the index
+// entries of the paths it checked out, so the entries outside the sparse set
+// are reset to the tree. Changes staged inside it are left alone.
+pub fn checkout_commit(repo: &Repository, commit: &Commit) -> Result<(), git2::Error> {
+    let tree = commit.tree()?;
+    repo.checkout_tree(
+        tree.as_object(),
+        Some(&mut checkout_builder(repo, Some(&tree))),
+    )?;
+    let Some(packages) = packages(repo) else {
+        return Ok(());
+    };
+    let mut committed = Index::new()?;
+    committed.read_tree(&tree)?;
+    let mut index = repo.index()?;
+    let outside: Vec<Vec<u8>> = index
+        .iter()
+        .map(|entry| entry.path)
+        .filter(|path| !in_set(path, &packages))
+        .collect();
+    for path in outside {
+        index.remove_path(Path::new(OsStr::from_bytes(&path)))?;
+    }
+    for entry in committed.iter() {
+        if !in_set(&entry.path, &packages) {
+            index.add(&entry)?;
+        }
+    }
+    index.write()?;
+    refresh(repo)
+}
+
+// Keep the skip-worktree bits in line with the sparse set after git2
+// operations that rewrite the index (merge, reset, checkout)
+pub fn refresh(repo: &Repository) -> Result<(), git2::Error> {
+    match packages(repo) {
+        Some(packages) => apply(repo, &packages).map(|_| ()),
+        None => Ok(()),
+    }
+}
+
+pub fn remove_empty_parents(file: &Path, workdir: &Path) {
+    let mut dir = file.parent();
+    while let Some(current) = dir {
+        if current == workdir || fs::remove_dir(current).is_err() {
+            break;
+        }
+        dir = current.parent();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use git2::{Oid, ResetType};
+    use tempfile::TempDir;
+
+    fn repo() -> (TempDir, Repository, Oid) {
+        let (dir, repo) = testing::repo();
+        testing::stage(&repo, "nvim/init.lua", "one\n");
+        testing::stage(&repo, "zsh/.zshrc", "one\n");
+        let oid = testing::commit(&repo, "Add nvim and zsh");
+        (dir, repo, oid)
+    }
+
+    fn exists(repo: &Repository, path: &str) -> bool {
+        repo.workdir().unwrap().join(path).exists()
+    }
+
+    fn read(repo: &Repository, path: &str) -> String {
+        fs::read_to_string(repo.workdir().unwrap().join(path)).unwrap()
+    }
+
+    fn entry(repo: &Repository, path: &str) -> IndexEntry {
+        repo.index().unwrap().get_path(Path::new(path), 0).unwrap()
+    }
+
+    fn skipped(repo: &Repository, path: &str) -> bool {
+        entry(repo, path).flags_extended & SKIP_WORKTREE != 0
+    }
+
+    #[test]
+    fn removes_deselected_packages() {
+        let (_dir, repo, _) = repo();
+        let kept = apply(&repo, &[String::from("nvim")]).unwrap();
+
+        assert!(kept.is_empty());
+        assert_eq!(packages(&repo), Some(vec![String::from("nvim")]));
+        assert!(exists(&repo, "nvim/init.lua"));
+        assert!(!exists(&repo, "zsh"));
+        assert!(!skipped(&repo, "nvim/init.lua"));
+        assert!(skipped(&repo, "zsh/.zshrc"));
+    }
+
+    #[test]
+    fn keeps_top_level_files() {
+        let (_dir, repo, _) = repo();
+        testing::stage(&repo, ".gitmodules", "");
+        testing::commit(&repo, "Add .gitmodules");
+        apply(&repo, &[String::from("nvim")]).unwrap();
+
+        assert!(exists(&repo, ".gitmodules"));
+        assert!(!skipped(&repo, ".gitmodules"));
+    }
+
+    #[test]
+    fn checks_out_new_top_level_files() {
+        let (_dir, repo, first) = repo();
+        testing::stage(&repo, ".dotutils-secrets-ignore", "");
+        let second = testing::commit(&repo, "Add secrets ignore list");
+        let first = repo.find_commit(first).unwrap();
+        repo.reset(first.as_object(), ResetType::Hard, None)
+            .unwrap();
+        apply(&repo, &[String::from("nvim")]).unwrap();
+
+        checkout_commit(&repo, &repo.find_commit(second).unwrap()).unwrap();
+
+        assert!(exists(&repo, ".dotutils-secrets-ignore"));
+        assert!(!skipped(&repo, ".dotutils-secrets-ignore"));
+    }
+
+    #[test]
+    fn keeps_modified_files_of_deselected_packages() {
+        let (_dir, repo, _) = repo();
+        testing::write(&repo, "zsh/.zshrc", "local\n");
+        let kept = apply(&repo, &[String::from("nvim")]).unwrap();
+
+        assert_eq!(kept, vec![String::from("zsh/.zshrc")]);
+        assert_eq!(read(&repo, "zsh/.zshrc"), "local\n");
+        assert!(!skipped(&repo, "zsh/.zshrc"));
+    }
+
+    #[test]
+    fn brings_back_reselected_packages() {
+        let (_dir, repo, _) = repo();
+        apply(&repo, &[String::from("nvim")]).unwrap();
+        apply(&repo, &[String::from("nvim"), String::from("zsh")]).unwrap();
+
+        assert_eq!(read(&repo, "zsh/.zshrc"), "one\n");
+        assert!(!skipped(&repo, "zsh/.zshrc"));
+    }
+
+    #[test]
+    fn checks_out_a_commit_within_the_set() {
+        let (_dir, repo, first) = repo();
+        testing::stage(&repo, "nvim/init.lua", "two\n");
+        testing::stage(&repo, "zsh/.zshrc", "two\n");
+        let second = testing::commit(&repo, "Change nvim and zsh");
+        let first = repo.find_commit(first).unwrap();
+        repo.reset(first.as_object(), ResetType::Hard, None)
+            .unwrap();
+        apply(&repo, &[String::from("nvim")]).unwrap();
+        testing::stage(&repo, "nvim/extra.lua", "staged\n");
+
+        let second = repo.find_commit(second).unwrap();
+        checkout_commit(&repo, &second).unwrap();
+
+        assert_eq!(read(&repo, "nvim/init.lua"), "two\n");
+        assert!(!exists(&repo, "zsh"));
+        let zshrc = second.tree().unwrap().get_path(Path::new("zsh/.zshrc"));
+        assert_eq!(entry(&repo, "zsh/.zshrc").id, zshrc.unwrap().id());
+        assert!(skipped(&repo, "zsh/.zshrc"));
+        assert_eq!(read(&repo, "nvim/extra.lua"), "staged\n");
+        assert!(!skipped(&repo, "nvim/extra.lua"));
+    }
+}
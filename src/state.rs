@@ -0,0 +1,57 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::env;
+use std::path::PathBuf;
+
+// Choices made in the TUI that should survive restarts, kept apart from the
+// hand-edited config in $XDG_STATE_HOME/dot-utils/state.toml
+#[derive(Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct State {
+    // Dotfiles branch chosen on each machine, keyed by hostname
+    pub branches: BTreeMap<String, String>,
+    // Stow packages enabled on each machine, keyed by hostname
+    pub packages: BTreeMap<String, Vec<String>>,
+}
+
+fn state_path() -> PathBuf {
+    let dir = match env::var("XDG_STATE_HOME") {
+        Ok(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var("HOME").unwrap_or_default()).join(".local/state"),
+    };
+    dir.join("dot-utils/state.toml")
+}
+
+pub fn hostname() -> String {
+    gethostname::gethostname().to_string_lossy().to_string()
+}
+
+impl State {
+    // A missing or unreadable state file simply starts from scratch
+    pub fn load() -> Self {
+        std::fs::read_to_string(state_path())
+            .ok()
+            .and_then(|content| toml::from_str(&content).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        let path = state_path();
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .map_err(|e| format!("Failed to create {}: {}", parent.display(), e))?;
+        }
+        let content =
+            toml::to_string(self).map_err(|e| format!("Failed to encode state: {}", e))?;
+        std::fs::write(&path, content)
+            .map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+    }
+
+    pub fn branch_for_host(&self) -> Option<String> {
+        self.branches.get(&hostname()).cloned()
+    }
+
+    pub fn packages_for_host(&self) -> Option<Vec<String>> {
+        self.packages.get(&hostname()).cloned()
+    }
+}
@@ -0,0 +1,782 @@
+use crate::backup::{self, Progress, ProgressReader};
+use crate::config::{BackupConfig, BackupEncryptionMode, BackupProfile};
+use crate::manifest::{self, Manifest, ManifestEntry};
+use crate::remote;
+use crate::retention;
+use fastcdc::v2020::StreamCDC;
+use ignore::gitignore::Gitignore;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions, Permissions};
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::{self as unix_fs, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+// Snapshots are named like archives, so the same retention policy applies
+const SUFFIX: &str = ".snapshot";
+const HEADER: &str = "# type\tmode\tuid\tgid\tmtime\tsize\tdata\thex path";
+// Snapshots written before paths were hex-encoded like symlink targets
+const RAW_HEADER: &str = "# type\tmode\tuid\tgid\tmtime\tsize\tdata\tpath";
+
+// Content-defined chunk sizes. An edit only changes the chunks around it,
+// the rest of the file is shared with earlier snapshots.
+const MIN_CHUNK: u32 = 16 * 1024;
+const AVG_CHUNK: u32 = 64 * 1024;
+const MAX_CHUNK: u32 = 256 * 1024;
+
+// Chunks are always zstd compressed, whatever archives are written with
+const CHUNK_LEVEL: i32 = 3;
+
+// One path of a snapshot. Files list the chunks of their content in order,
+// symlinks keep their target. Both paths and targets are written hex-encoded,
+// as they may hold any byte but NUL.
+pub struct SnapshotEntry {
+    pub kind: char,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub mtime: u64,
+    pub size: u64,
+    pub chunks: Vec<String>,
+    pub target: PathBuf,
+    pub path: PathBuf,
+}
+
+impl SnapshotEntry {
+    fn line(&self) -> String {
+        let data = match self.kind {
+            'f' if !self.chunks.is_empty() => self.chunks.join(","),
+            'l' => to_hex(self.target.as_os_str().as_bytes()),
+            _ => String::from("-"),
+        };
+        format!(
+            "{}\t{:o}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+            self.kind,
+            self.mode,
+            self.uid,
+            self.gid,
+            self.mtime,
+            self.size,
+            data,
+            to_hex(self.path.as_os_str().as_bytes())
+        )
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    // from_str_radix alone would take a sign like `+1` as a digit pair
+    if !text.len().is_multiple_of(2) || !text.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(text.get(index..index + 2)?, 16).ok())
+        .collect()
+}
+
+fn is_chunk_id(id: &str) -> bool {
+    id.len() == 64
+        && id
+            .bytes()
+            .all(|byte| matches!(byte, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+// The repository lives next to the archives, shared by every store profile
+// with the same destination
+pub fn store_dir(config: &BackupConfig, profile: &BackupProfile) -> PathBuf {
+    backup::profile_dir(config, profile).join("store")
+}
+
+fn snapshots_dir(store: &Path) -> PathBuf {
+    store.join("snapshots")
+}
+
+fn chunks_dir(store: &Path) -> PathBuf {
+    store.join("chunks")
+}
+
+// `chunks/<first two digits>/<sha256>`, keeping directories small
+fn chunk_path(store: &Path, id: &str) -> PathBuf {
+    chunks_dir(store).join(&id[..2]).join(id)
+}
+
+// Snapshots sit two levels below the store
+fn store_of(snapshot: &Path) -> PathBuf {
+    snapshot
+        .parent()
+        .and_then(Path::parent)
+        .map(Path::to_path_buf)
+        .unwrap_or_default()
+}
+
+// Held while a backup or garbage collection changes the store, so one never
+// deletes chunks the other is about to reference
+struct Lock(PathBuf);
+
+impl Lock {
+    fn acquire(store: &Path) -> Result<Self, String> {
+        fs::create_dir_all(store)
+            .map_err(|e| format!("Failed to create {}: {}", store.display(), e))?;
+        let path = store.join("lock");
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(mut file) => {
+                let _ = writeln!(file, "{}", std::process::id());
+                Ok(Self(path))
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Err(format!(
+                "{} is in use by another backup. Remove {} if none is running.",
+                store.display(),
+                path.display()
+            )),
+            Err(e) => Err(format!("Failed to lock {}: {}", store.display(), e)),
+        }
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+// Store a chunk unless it is there already. Returns its id and the bytes
+// written to disk.
+fn put_chunk(store: &Path, data: &[u8]) -> io::Result<(String, u64)> {
+    let id = manifest::sha256(data);
+    let path = chunk_path(store, &id);
+    if path.exists() {
+        return Ok((id, 0));
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let compressed = zstd::encode_all(data, CHUNK_LEVEL)?;
+    backup::write_atomic(&path, &compressed)?;
+    Ok((id, compressed.len() as u64))
+}
+
+// Content of a chunk, checked against its id
+fn get_chunk(store: &Path, id: &str) -> io::Result<Vec<u8>> {
+    let data = File::open(chunk_path(store, id))
+        .and_then(zstd::decode_all)
+        .map_err(|e| io::Error::new(e.kind(), format!("chunk {}: {}", id, e)))?;
+    if manifest::sha256(&data) != id {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("chunk {} does not match its checksum", id),
+        ));
+    }
+    Ok(data)
+}
+
+// What a backup added to the store
+#[derive(Default)]
+struct Stats {
+    files: usize,
+    directories: usize,
+    symlinks: usize,
+    bytes: u64,
+    chunks: usize,
+    new_chunks: usize,
+    new_bytes: u64,
+    stored_bytes: u64,
+}
+
+// Chunk the files of a profile into the store and record them in a new
+// snapshot, then prune old snapshots and the chunks no longer used
+pub fn back_up(
+    config: &BackupConfig,
+    profile: &BackupProfile,
+    home: &Path,
+    progress: &mut Progress,
+) -> Result<String, String> {
+    // Chunks are named by the checksum of their plaintext, so they can not
+    // be encrypted the way archives are
+    if config.encryption.mode != BackupEncryptionMode::None {
+        return Err(String::from(
+            "Snapshots are not encrypted. Back up to archives to use [backups.encryption].",
+        ));
+    }
+    let sources = backup::profile_sources(profile, home)?;
+    let store = backup::prepare_dir(config, profile)?.join("store");
+    let _lock = Lock::acquire(&store)?;
+    let dir = snapshots_dir(&store);
+    fs::create_dir_all(&dir).map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+    let latest = list_snapshots(config, profile).into_iter().next();
+    backup::check_free_space(&store, changed_size(&sources, home, latest.as_deref())?)?;
+    progress.start(backup::source_size(&sources, home)?);
+
+    let started = Instant::now();
+    let mut stats = Stats::default();
+    let mut content = format!("{}\n", HEADER);
+    backup::walk_sources(&sources, home, &mut |path, rel_path, metadata| {
+        let file_type = metadata.file_type();
+        let mut entry = SnapshotEntry {
+            kind: 'd',
+            mode: metadata.mode() & 0o7777,
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            mtime: metadata.mtime().max(0) as u64,
+            size: 0,
+            chunks: Vec::new(),
+            target: PathBuf::new(),
+            path: rel_path.to_path_buf(),
+        };
+        if file_type.is_file() {
+            entry.kind = 'f';
+            stats.files += 1;
+            progress.file()?;
+            // Stop at the size it had, even if the file grows while it is read
+            let file = File::open(path)?;
+            let length = file.metadata()?.len();
+            let reader = ProgressReader::new(file.take(length), progress);
+            for chunk in StreamCDC::new(reader, MIN_CHUNK, AVG_CHUNK, MAX_CHUNK) {
+                let chunk = chunk.map_err(io::Error::from)?;
+                let (id, written) = put_chunk(&store, &chunk.data)?;
+                if written > 0 {
+                    stats.new_chunks += 1;
+                    stats.new_bytes += chunk.length as u64;
+                    stats.stored_bytes += written;
+                }
+                stats.chunks += 1;
+                entry.size += chunk.length as u64;
+                entry.chunks.push(id);
+            }
+            stats.bytes += entry.size;
+        } else if file_type.is_symlink() {
+            entry.kind = 'l';
+            stats.symlinks += 1;
+            entry.target = fs::read_link(path)?;
+        } else {
+            stats.directories += 1;
+        }
+        content.push_str(&entry.line());
+        Ok(())
+    })
+    .map_err(|e| {
+        if progress.is_cancelled() {
+            String::from(
+                "Cancelled. Chunks written so far are removed by the next garbage collection.",
+            )
+        } else {
+            e.to_string()
+        }
+    })?;
+
+    let snapshot = dir.join(format!(
+        "{}{}{}",
+        backup::archive_prefix(&profile.name),
+        chrono::Local::now().format(retention::TIMESTAMP_FORMAT),
+        SUFFIX
+    ));
+    backup::write_atomic(&snapshot, content.as_bytes())
+        .map_err(|e| format!("Failed to write {}: {}", snapshot.display(), e))?;
+
+    let mut report = format!(
+        "Snapshot: {}\n{} files, {} directories and {} symlinks, {} in {} chunks\n\
+         New in the store: {} chunks, {} stored as {}\n\
+         Already stored: {} chunks\nTook {:.1}s",
+        snapshot.display(),
+        stats.files,
+        stats.directories,
+        stats.symlinks,
+        remote::format_bytes(stats.bytes as usize),
+        stats.chunks,
+        stats.new_chunks,
+        remote::format_bytes(stats.new_bytes as usize),
+        remote::format_bytes(stats.stored_bytes as usize),
+        stats.chunks - stats.new_chunks,
+        started.elapsed().as_secs_f64()
+    );
+    let snapshots =
+        retention::timestamped_archives(&dir, &backup::archive_prefix(&profile.name), &[SUFFIX]);
+    report.push_str(&retention::prune(&snapshots, &config.retention));
+    report.push_str("\n\n");
+    report.push_str(&collect(&store)?);
+    Ok(report)
+}
+
+// Size of the files that changed since the latest snapshot, which is at
+// most what a new snapshot adds to the store
+fn changed_size(
+    sources: &[(PathBuf, Gitignore)],
+    home: &Path,
+    latest: Option<&Path>,
+) -> Result<u64, String> {
+    let Some(latest) = latest else {
+        return backup::source_size(sources, home);
+    };
+    let stored: HashMap<PathBuf, (u64, u64)> = read_snapshot(latest)?
+        .into_iter()
+        .map(|entry| (entry.path, (entry.size, entry.mtime)))
+        .collect();
+    let mut size = 0;
+    backup::walk_sources(sources, home, &mut |_, rel_path, metadata| {
+        let current = (metadata.len(), metadata.mtime().max(0) as u64);
+        if metadata.is_file() && stored.get(rel_path) != Some(&current) {
+            size += metadata.len();
+        }
+        Ok(())
+    })
+    .map_err(|e| e.to_string())?;
+    Ok(size)
+}
+
+// Snapshots of a backup made on this host, newest first
+pub fn list_snapshots(config: &BackupConfig, profile: &BackupProfile) -> Vec<PathBuf> {
+    retention::timestamped_archives(
+        &snapshots_dir(&store_dir(config, profile)),
+        &backup::archive_prefix(&profile.name),
+        &[SUFFIX],
+    )
+    .into_iter()
+    .map(|(_, path)| path)
+    .collect()
+}
+
+pub fn read_snapshot(snapshot: &Path) -> Result<Vec<SnapshotEntry>, String> {
+    let content = fs::read_to_string(snapshot)
+        .map_err(|e| format!("Failed to read {}: {}", snapshot.display(), e))?;
+    let hex_paths = !content.starts_with(&format!("{}\n", RAW_HEADER));
+    let mut entries = Vec::new();
+    for (number, line) in content.lines().enumerate() {
+        if line.starts_with('#') || line.is_empty() {
+            continue;
+        }
+        let invalid = || {
+            format!(
+                "{}:{}: invalid snapshot line",
+                snapshot.display(),
+                number + 1
+            )
+        };
+        let fields: Vec<&str> = line.splitn(8, '\t').collect();
+        let [kind, mode, uid, gid, mtime, size, data, path] = fields[..] else {
+            return Err(invalid());
+        };
+        let kind = kind.chars().next().ok_or_else(invalid)?;
+        let path = if hex_paths {
+            PathBuf::from(OsString::from_vec(from_hex(path).ok_or_else(invalid)?))
+        } else {
+            PathBuf::from(path)
+        };
+        let mut entry = SnapshotEntry {
+            kind,
+            mode: u32::from_str_radix(mode, 8).map_err(|_| invalid())?,
+            uid: uid.parse().map_err(|_| invalid())?,
+            gid: gid.parse().map_err(|_| invalid())?,
+            mtime: mtime.parse().map_err(|_| invalid())?,
+            size: size.parse().map_err(|_| invalid())?,
+            chunks: Vec::new(),
+            target: PathBuf::new(),
+            path,
+        };
+        match kind {
+            'f' if data != "-" => {
+                entry.chunks = data.split(',').map(String::from).collect();
+                if !entry.chunks.iter().all(|id| is_chunk_id(id)) {
+                    return Err(invalid());
+                }
+            }
+            'f' | 'd' => {}
+            'l' => {
+                let target = from_hex(data).ok_or_else(invalid)?;
+                entry.target = PathBuf::from(OsString::from_vec(target));
+            }
+            _ => return Err(invalid()),
+        }
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+// Size of the files in a snapshot and the paths it holds
+pub fn describe(snapshot: &Path) -> Result<String, String> {
+    let entries = read_snapshot(snapshot)?;
+    let total: u64 = entries.iter().map(|entry| entry.size).sum();
+    let chunks: HashSet<&String> = entries.iter().flat_map(|entry| &entry.chunks).collect();
+    let lines: Vec<String> = entries
+        .iter()
+        .take(backup::SUMMARY_LIMIT)
+        .map(|entry| {
+            format!(
+                "{:>10}  {}",
+                remote::format_bytes(entry.size as usize),
+                entry.path.display()
+            )
+        })
+        .collect();
+    let mut report = format!(
+        "{}\n{} in {} entries, {} distinct chunks\n\n{}",
+        snapshot.display(),
+        remote::format_bytes(total as usize),
+        entries.len(),
+        chunks.len(),
+        lines.join("\n")
+    );
+    if entries.len() > backup::SUMMARY_LIMIT {
+        report.push_str(&format!(
+            "\n... and {} more",
+            entries.len() - backup::SUMMARY_LIMIT
+        ));
+    }
+    Ok(report)
+}
+
+// A snapshot in the form of an archive manifest, hashing the content of
+// every file from its chunks
+pub fn manifest(snapshot: &Path) -> Result<Manifest, String> {
+    let store = store_of(snapshot);
+    let mut manifest = Manifest::new();
+    for entry in read_snapshot(snapshot)? {
+        let manifest_entry =
+            match entry.kind {
+                'd' => ManifestEntry::directory(entry.mtime),
+                'l' => ManifestEntry::symlink(entry.target.as_os_str().as_bytes(), entry.mtime),
+                _ => {
+                    let mut hasher = Sha256::new();
+                    for id in &entry.chunks {
+                        hasher.update(get_chunk(&store, id).map_err(|e| {
+                            format!("Failed to read {}: {}", entry.path.display(), e)
+                        })?);
+                    }
+                    ManifestEntry {
+                        kind: 'f',
+                        size: entry.size,
+                        mtime: entry.mtime,
+                        sha256: format!("{:x}", hasher.finalize()),
+                    }
+                }
+            };
+        manifest.insert(entry.path.to_string_lossy().to_string(), manifest_entry);
+    }
+    Ok(manifest)
+}
+
+// Content of the given files of a snapshot
+pub fn read_files(
+    snapshot: &Path,
+    wanted: &BTreeSet<String>,
+) -> Result<BTreeMap<String, Vec<u8>>, String> {
+    let store = store_of(snapshot);
+    let mut files = BTreeMap::new();
+    for entry in read_snapshot(snapshot)? {
+        let key = entry.path.to_string_lossy().to_string();
+        if entry.kind != 'f' || !wanted.contains(&key) {
+            continue;
+        }
+        let mut content = Vec::new();
+        for id in &entry.chunks {
+            content.extend(
+                get_chunk(&store, id)
+                    .map_err(|e| format!("Failed to read {}: {}", entry.path.display(), e))?,
+            );
+        }
+        files.insert(key, content);
+    }
+    Ok(files)
+}
+
+// Problems that would make restoring a snapshot unsafe or incomplete: paths
+// escaping the restore root, paths inside a symlink, and chunks that are
+// missing or do not match their id
+fn check(snapshot: &Path, entries: &[SnapshotEntry]) -> Vec<String> {
+    let store = store_of(snapshot);
+    let mut problems = Vec::new();
+    let mut symlinks: Vec<&Path> = Vec::new();
+    for entry in entries {
+        if !backup::stays_inside(&entry.path) || entry.path.as_os_str().is_empty() {
+            problems.push(format!("{}: unsafe path", entry.path.display()));
+        }
+        if let Some(link) = symlinks
+            .iter()
+            .find(|link| entry.path.starts_with(link) && entry.path != **link)
+        {
+            problems.push(format!(
+                "{}: inside the symlink {}",
+                entry.path.display(),
+                link.display()
+            ));
+        }
+        if entry.kind == 'l' {
+            symlinks.push(&entry.path);
+        }
+    }
+
+    let mut sizes: HashMap<&str, Result<u64, String>> = HashMap::new();
+    for entry in entries {
+        let mut size = Some(0);
+        for id in &entry.chunks {
+            let length = sizes.entry(id).or_insert_with(|| {
+                get_chunk(&store, id)
+                    .map(|data| data.len() as u64)
+                    .map_err(|e| e.to_string())
+            });
+            match length {
+                Ok(length) => size = size.map(|size| size + *length),
+                Err(e) => {
+                    problems.push(format!("{}: {}", entry.path.display(), e));
+                    size = None;
+                }
+            }
+        }
+        match size {
+            Some(size) if entry.kind == 'f' && size != entry.size => problems.push(format!(
+                "{}: size {} instead of {}",
+                entry.path.display(),
+                size,
+                entry.size
+            )),
+            _ => {}
+        }
+    }
+    problems
+}
+
+// Read every chunk a snapshot references back and check it
+pub fn verify(snapshot: &Path) -> Result<String, String> {
+    let entries = read_snapshot(snapshot)?;
+    let problems = check(snapshot, &entries);
+    if problems.is_empty() {
+        let chunks: HashSet<&String> = entries.iter().flat_map(|entry| &entry.chunks).collect();
+        return Ok(format!(
+            "{} verified, all {} entries and {} chunks are intact.",
+            snapshot.display(),
+            entries.len(),
+            chunks.len()
+        ));
+    }
+    let mut report = format!(
+        "{} can not be restored, {} problems:",
+        snapshot.display(),
+        problems.len()
+    );
+    for problem in problems {
+        report.push_str(&format!("\n- {}", problem));
+    }
+    Err(report)
+}
+
+// Recreate the entries of a snapshot under `root`. Directories get their
+// mode and time last, deepest first, so the entries inside do not undo them.
+fn write_entries(store: &Path, entries: &[SnapshotEntry], root: &Path) -> io::Result<()> {
+    // Only root can hand files to other users
+    let owners = unsafe { libc::geteuid() } == 0;
+    let mut directories = Vec::new();
+    for entry in entries {
+        let dest = root.join(&entry.path);
+        if entry.kind == 'd' {
+            fs::create_dir_all(&dest)?;
+            directories.push((dest, entry));
+            continue;
+        }
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        if entry.kind == 'l' {
+            unix_fs::symlink(&entry.target, &dest)?;
+        } else {
+            let mut file = File::create(&dest)?;
+            for id in &entry.chunks {
+                file.write_all(&get_chunk(store, id)?)?;
+            }
+            file.set_permissions(Permissions::from_mode(entry.mode))?;
+            file.set_modified(UNIX_EPOCH + Duration::from_secs(entry.mtime))?;
+        }
+        if owners {
+            unix_fs::lchown(&dest, Some(entry.uid), Some(entry.gid))?;
+        }
+    }
+    directories.sort_by(|a, b| b.0.cmp(&a.0));
+    for (dest, entry) in directories {
+        if owners {
+            unix_fs::lchown(&dest, Some(entry.uid), Some(entry.gid))?;
+        }
+        fs::set_permissions(&dest, Permissions::from_mode(entry.mode))?;
+        // A directory whose mode forbids reading keeps the time of restoring
+        if let Ok(dir) = File::open(&dest) {
+            let _ = dir.set_modified(UNIX_EPOCH + Duration::from_secs(entry.mtime));
+        }
+    }
+    Ok(())
+}
+
+// Restore a snapshot into the source paths of its profile. It is checked in
+// full first, and existing files are moved aside as for archives.
+pub fn restore(snapshot: &Path, profile: &BackupProfile, home: &Path) -> Result<String, String> {
+    verify(snapshot).map_err(|e| format!("Refusing to restore: {}", e))?;
+    let entries = read_snapshot(snapshot)?;
+    let paths: Vec<PathBuf> = entries.iter().map(|entry| entry.path.clone()).collect();
+    let targets = backup::rooted_targets(profile, home, &paths)?;
+    if let Some(entry) = paths.iter().find(|path| {
+        !targets
+            .iter()
+            .any(|target| home.join(path).starts_with(target))
+    }) {
+        return Err(format!(
+            "Refusing to restore {}: {} is not in the paths of {}",
+            snapshot.display(),
+            entry.display(),
+            profile.name
+        ));
+    }
+
+    let store = store_of(snapshot);
+    let moved = backup::replace_targets(&targets, || write_entries(&store, &entries, home))
+        .map_err(|e| format!("Failed to restore {}: {}", snapshot.display(), e))?;
+    Ok(backup::restore_report(snapshot, &targets, &moved))
+}
+
+// Delete the chunks no snapshot of any profile or host references, along
+// with temporary files an interrupted backup left behind
+fn collect(store: &Path) -> Result<String, String> {
+    let mut referenced = HashSet::new();
+    let dir = snapshots_dir(store);
+    if let Ok(snapshots) = fs::read_dir(&dir) {
+        for snapshot in snapshots.flatten() {
+            let path = snapshot.path();
+            let name = snapshot.file_name().to_string_lossy().to_string();
+            if name.ends_with(SUFFIX) {
+                // A snapshot that can not be read might use any chunk
+                for entry in read_snapshot(&path)
+                    .map_err(|e| format!("Garbage collection stopped: {}", e))?
+                {
+                    referenced.extend(entry.chunks);
+                }
+            } else if name.ends_with(backup::PARTIAL) {
+                let _ = fs::remove_file(&path);
+            }
+        }
+    }
+
+    let (mut removed, mut freed, mut kept, mut used) = (0, 0, 0, 0);
+    let prefixes = fs::read_dir(chunks_dir(store))
+        .into_iter()
+        .flatten()
+        .flatten();
+    for chunk in
+        prefixes.flat_map(|prefix| fs::read_dir(prefix.path()).into_iter().flatten().flatten())
+    {
+        let name = chunk.file_name().to_string_lossy().to_string();
+        let size = chunk.metadata().map(|metadata| metadata.len()).unwrap_or(0);
+        if referenced.contains(&name) {
+            kept += 1;
+            used += size;
+        } else if fs::remove_file(chunk.path()).is_ok() {
+            removed += 1;
+            freed += size;
+        }
+    }
+    Ok(format!(
+        "Garbage collection: removed {} unused chunks, freeing {}. {} chunks ({}) are in use.",
+        removed,
+        remote::format_bytes(freed as usize),
+        kept,
+        remote::format_bytes(used as usize)
+    ))
+}
+
+pub fn gc(config: &BackupConfig, profile: &BackupProfile) -> Result<String, String> {
+    let store = store_dir(config, profile);
+    let _lock = Lock::acquire(&store)?;
+    collect(&store)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    // A snapshot file with the given content
+    fn snapshot(content: &str) -> (TempDir, PathBuf) {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("snapshot");
+        fs::write(&path, content).unwrap();
+        (dir, path)
+    }
+
+    fn entry(kind: char, path: &[u8]) -> SnapshotEntry {
+        SnapshotEntry {
+            kind,
+            mode: 0o644,
+            uid: 1000,
+            gid: 1000,
+            mtime: 1_700_000_000,
+            size: 0,
+            chunks: Vec::new(),
+            target: PathBuf::new(),
+            path: PathBuf::from(OsString::from_vec(path.to_vec())),
+        }
+    }
+
+    #[test]
+    fn hex_round_trips_any_byte() {
+        let bytes: Vec<u8> = (0..=255).collect();
+        assert_eq!(from_hex(&to_hex(&bytes)), Some(bytes));
+        assert_eq!(from_hex(""), Some(Vec::new()));
+    }
+
+    #[test]
+    fn hex_rejects_invalid_text() {
+        assert_eq!(from_hex("abc"), None);
+        assert_eq!(from_hex("zz"), None);
+        assert_eq!(from_hex("+1"), None);
+        assert_eq!(from_hex("é1"), None);
+    }
+
+    #[test]
+    fn reads_back_odd_paths() {
+        let mut file = entry('f', b"tab\there/new\nline");
+        file.size = 5;
+        file.chunks = vec!["a".repeat(64), "b".repeat(64)];
+        let mut link = entry('l', b"back\\slash \xff\xfe");
+        link.target = PathBuf::from(OsString::from_vec(b"../tar\tget\n".to_vec()));
+        let entries = [entry('d', b"tab\there"), file, link, entry('f', b"empty")];
+        let mut content = format!("{}\n", HEADER);
+        for entry in &entries {
+            content.push_str(&entry.line());
+        }
+        let (_dir, snapshot) = snapshot(&content);
+
+        let read = read_snapshot(&snapshot).unwrap();
+        assert_eq!(read.len(), entries.len());
+        for (read, written) in read.iter().zip(&entries) {
+            assert_eq!(read.kind, written.kind);
+            assert_eq!(read.path, written.path);
+            assert_eq!(read.target, written.target);
+            assert_eq!(read.chunks, written.chunks);
+            assert_eq!(read.size, written.size);
+            assert_eq!(read.mode, written.mode);
+        }
+    }
+
+    #[test]
+    fn reads_snapshots_written_before_hex_paths() {
+        let (_dir, snapshot) = snapshot(&format!(
+            "{}\nd\t755\t0\t0\t1\t0\t-\tplain dir\n",
+            RAW_HEADER
+        ));
+        let read = read_snapshot(&snapshot).unwrap();
+        assert_eq!(read[0].path, Path::new("plain dir"));
+    }
+
+    #[test]
+    fn rejects_invalid_lines() {
+        for line in [
+            "d\t755\t0\t0\t1\t0\t-\tnot hex",
+            "f\t644\t0\t0\t1\t3\tnot-a-chunk\t61",
+            "x\t644\t0\t0\t1\t0\t-\t61",
+            "d\t755\t0\t0\t1",
+        ] {
+            let (_dir, snapshot) = snapshot(&format!("{}\n{}\n", HEADER, line));
+            assert!(read_snapshot(&snapshot).is_err(), "{}", line);
+        }
+    }
+}
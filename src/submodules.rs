@@ -0,0 +1,83 @@
+use crate::history;
+use crate::remote;
+use crate::sparse;
+use git2::{Repository, SubmoduleIgnore, SubmoduleStatus, SubmoduleUpdateOptions};
+
+// Initialise and update every submodule recursively, returning one status
+// line per submodule. Submodules with local edits are left untouched.
+pub fn update_submodules(repo: &Repository, ssh_key: Option<&str>) -> String {
+    let mut report = Vec::new();
+    update_recursive(repo, ssh_key, "", &mut report);
+    if report.is_empty() {
+        return String::new();
+    }
+
+    let mut output = String::from("\n\nSubmodules:");
+    for line in report {
+        output.push_str(&format!("\n- {}", line));
+    }
+    output
+}
+
+fn update_recursive(
+    repo: &Repository,
+    ssh_key: Option<&str>,
+    prefix: &str,
+    report: &mut Vec<String>,
+) {
+    let submodules = match repo.submodules() {
+        Ok(submodules) => submodules,
+        Err(e) => {
+            report.push(format!("{}: failed to list submodules: {}", prefix, e));
+            return;
+        }
+    };
+
+    // Submodules inside packages left out of a sparse checkout stay absent
+    let sparse_packages = sparse::packages(repo);
+    for mut submodule in submodules {
+        let name = format!("{}{}", prefix, submodule.path().display());
+        if let Some(packages) = &sparse_packages {
+            let package = submodule.path().components().next();
+            if !package.is_some_and(|p| packages.iter().any(|name| p.as_os_str() == name.as_str()))
+            {
+                continue;
+            }
+        }
+        let status =
+            match repo.submodule_status(submodule.name().unwrap_or(""), SubmoduleIgnore::None) {
+                Ok(status) => status,
+                Err(e) => {
+                    report.push(format!("{}: failed to read status: {}", name, e));
+                    continue;
+                }
+            };
+
+        if status.intersects(SubmoduleStatus::WD_INDEX_MODIFIED | SubmoduleStatus::WD_WD_MODIFIED) {
+            report.push(format!("{}: has local changes, not updated", name));
+            continue;
+        }
+
+        let mut options = SubmoduleUpdateOptions::new();
+        options.fetch(remote::fetch_options(ssh_key));
+        if let Err(e) = submodule.update(true, Some(&mut options)) {
+            report.push(format!("{}: failed to update: {}", name, e));
+            continue;
+        }
+
+        let _ = submodule.reload(true);
+        let mut line = match submodule.workdir_id() {
+            Some(oid) => format!("{}: up to date at {}", name, history::short_id(oid)),
+            None => format!("{}: updated", name),
+        };
+        if status.contains(SubmoduleStatus::WD_UNTRACKED) {
+            line.push_str(" (has untracked files)");
+        }
+        report.push(line);
+
+        match submodule.open() {
+            Ok(sub_repo) => update_recursive(&sub_repo, ssh_key, &format!("{}/", name), report),
+            Err(e) => report.push(format!("{}: failed to open: {}", name, e)),
+        }
+    }
+}
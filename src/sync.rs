@@ -0,0 +1,417 @@
+use crate::config::Config;
+use crate::remote;
+use crate::sparse;
+use crate::submodules;
+use crate::verify;
+use git2::{
+    Commit, Repository, Signature, StashApplyOptions, StashFlags, StashSaveOptions, Status,
+    StatusOptions,
+};
+
+const STASH_MESSAGE: &str = "dot-utils: auto-stash before sync";
+
+// Stash local modifications, merge origin, update submodules and re-apply
+// the stash, returning a report of what happened
+pub fn sync_repository(repo: &mut Repository, config: &Config) -> String {
+    // Move local modifications out of the way so the merge sees a clean tree
+    let stashed = match stash_local_changes(repo) {
+        Ok(stashed) => stashed,
+        Err(e) => return format!("Failed to stash local changes: {}", e),
+    };
+
+    let mut output = match merge_origin(repo, config) {
+        Ok(()) => {
+            let mut output = String::from("Dotfiles repository updated successfully!");
+            output.push_str(&submodules::update_submodules(
+                repo,
+                config.ssh_key.as_deref(),
+            ));
+            output
+        }
+        Err(e) => e,
+    };
+
+    if stashed {
+        match restore_stash(repo) {
+            Ok(()) => output.push_str("\nLocal changes were stashed and re-applied."),
+            Err(e) => output.push_str(&format!("\n{}", e)),
+        }
+    }
+    output
+}
+
+// Fetch origin and merge the remote counterpart of the current branch
+pub fn merge_origin(repo: &Repository, config: &Config) -> Result<(), String> {
+    // Get the remote
+    let mut remote = repo
+        .find_remote("origin")
+        .map_err(|e| format!("Failed to find remote 'origin': {}", e))?;
+
+    // Fetch updates
+    remote
+        .fetch(
+            &["refs/heads/*:refs/remotes/origin/*"],
+            Some(&mut remote::fetch_options(config.ssh_key.as_deref())),
+            None,
+        )
+        .map_err(|e| format!("Failed to fetch from remote: {}", e))?;
+
+    // Get default branch reference
+    let head = repo
+        .head()
+        .map_err(|e| format!("Failed to get HEAD reference: {}", e))?;
+
+    // Get current branch name
+    let branch_name = head
+        .shorthand()
+        .ok_or_else(|| String::from("Failed to determine current branch name"))?;
+
+    // Lookup the remote branch we want to merge from
+    let remote_branch_name = format!("origin/{}", branch_name);
+    let remote_ref = repo
+        .find_reference(&format!("refs/remotes/{}", remote_branch_name))
+        .map_err(|e| format!("Failed to find remote reference: {}", e))?;
+
+    // Convert the reference to an annotated commit
+    let annotated_commit = repo
+        .reference_to_annotated_commit(&remote_ref)
+        .map_err(|e| format!("Failed to get annotated commit: {}", e))?;
+
+    let fetch_commit = remote_ref
+        .peel_to_commit()
+        .map_err(|e| format!("Failed to get remote commit: {}", e))?;
+
+    // Refuse incoming commits that are not signed by a trusted key
+    if config.verify_signatures.enabled {
+        let head_oid = head
+            .target()
+            .ok_or_else(|| String::from("Failed to resolve HEAD commit"))?;
+        verify::verify_incoming(repo, head_oid, fetch_commit.id(), &config.verify_signatures)?;
+    }
+
+    if sparse::packages(repo).is_some() {
+        return merge_sparse(repo, &fetch_commit);
+    }
+
+    // Perform the merge/pull
+    let mut index = repo
+        .index()
+        .map_err(|e| format!("Failed to get repository index: {}", e))?;
+
+    // Create merge options
+    let mut merge_options = git2::MergeOptions::new();
+    merge_options.file_favor(git2::FileFavor::Normal);
+
+    // Merge the remote branch into the current branch
+    repo.merge(&[&annotated_commit], Some(&mut merge_options), None)
+        .map_err(|e| format!("Failed to merge updates: {}", e))?;
+
+    // Check for merge conflicts
+    if index.has_conflicts() {
+        return Err(String::from(
+            "Merge conflicts detected. Please resolve them manually.",
+        ));
+    }
+
+    // Commit the merge if necessary
+    if repo.state() == git2::RepositoryState::Merge {
+        // We need to commit the merge
+        let signature = repo
+            .signature()
+            .map_err(|e| format!("Failed to create signature: {}", e))?;
+
+        index
+            .write()
+            .map_err(|e| format!("Failed to write index: {}", e))?;
+
+        let tree_oid = index
+            .write_tree()
+            .map_err(|e| format!("Failed to write tree: {}", e))?;
+
+        let tree = repo
+            .find_tree(tree_oid)
+            .map_err(|e| format!("Failed to find tree: {}", e))?;
+
+        let head_commit = repo
+            .head()
+            .and_then(|head| head.peel_to_commit())
+            .map_err(|e| format!("Failed to get HEAD commit: {}", e))?;
+
+        repo.commit(
+            Some("HEAD"),
+            &signature,
+            &signature,
+            "Merge remote changes",
+            &tree,
+            &[&head_commit, &fetch_commit],
+        )
+        .map_err(|e| format!("Failed to commit merge: {}", e))?;
+
+        // Clean up the repository state
+        repo.cleanup_state()
+            .map_err(|e| format!("Failed to cleanup repository state: {}", e))?;
+    }
+
+    Ok(())
+}
+
+// libgit2 treats packages left out of a sparse checkout as local deletions and
+// refuses to merge over them, so merge in memory and only check out the
+// materialised packages
+fn merge_sparse(repo: &Repository, fetch_commit: &Commit) -> Result<(), String> {
+    let head_commit = repo
+        .head()
+        .and_then(|head| head.peel_to_commit())
+        .map_err(|e| format!("Failed to get HEAD commit: {}", e))?;
+    if head_commit.id() == fetch_commit.id()
+        || repo
+            .graph_descendant_of(head_commit.id(), fetch_commit.id())
+            .unwrap_or(false)
+    {
+        return Ok(());
+    }
+
+    let mut index = repo
+        .merge_commits(&head_commit, fetch_commit, None)
+        .map_err(|e| format!("Failed to merge updates: {}", e))?;
+    if index.has_conflicts() {
+        return Err(String::from(
+            "Merge conflicts detected. Sparse checkouts cannot hold conflicts; \
+             resolve them in a full checkout.",
+        ));
+    }
+
+    let tree = index
+        .write_tree_to(repo)
+        .and_then(|oid| repo.find_tree(oid))
+        .map_err(|e| format!("Failed to write tree: {}", e))?;
+    let signature = repo
+        .signature()
+        .map_err(|e| format!("Failed to create signature: {}", e))?;
+    let merge_oid = repo
+        .commit(
+            None,
+            &signature,
+            &signature,
+            "Merge remote changes",
+            &tree,
+            &[&head_commit, fetch_commit],
+        )
+        .map_err(|e| format!("Failed to commit merge: {}", e))?;
+
+    repo.find_commit(merge_oid)
+        .and_then(|commit| sparse::checkout_commit(repo, &commit))
+        .and_then(|_| {
+            repo.head()?
+                .set_target(merge_oid, "merge: Merge remote changes")
+        })
+        .map(|_| ())
+        .map_err(|e| format!("Failed to check out merge: {}", e))
+}
+
+// Files outside a sparse checkout are missing on purpose, so only the
+// materialised packages and the top-level files are considered
+pub fn sparse_pathspecs(repo: &Repository) -> Vec<String> {
+    let Some(packages) = sparse::packages(repo) else {
+        return Vec::new();
+    };
+    let mut pathspecs = sparse::pathspecs(&packages);
+    pathspecs.extend(sparse::root_files(repo, None));
+    pathspecs
+}
+
+fn local_changes(repo: &Repository) -> Result<Vec<String>, git2::Error> {
+    let mut options = StatusOptions::new();
+    for pathspec in sparse_pathspecs(repo) {
+        options.pathspec(pathspec);
+    }
+    // Submodules are updated separately and cannot be stashed
+    options
+        .include_untracked(false)
+        .include_ignored(false)
+        .exclude_submodules(true);
+    let statuses = repo.statuses(Some(&mut options))?;
+    Ok(statuses
+        .iter()
+        .filter_map(|entry| entry.path().map(String::from))
+        .collect())
+}
+
+// Two-letter status of a file, index then working tree, as `git status -s`
+fn short_status(status: Status) -> String {
+    if status.is_wt_new() {
+        return String::from("??");
+    }
+    if status.is_conflicted() {
+        return String::from("UU");
+    }
+    let index = if status.is_index_new() {
+        'A'
+    } else if status.is_index_modified() {
+        'M'
+    } else if status.is_index_deleted() {
+        'D'
+    } else if status.is_index_renamed() {
+        'R'
+    } else if status.is_index_typechange() {
+        'T'
+    } else {
+        ' '
+    };
+    let worktree = if status.is_wt_modified() {
+        'M'
+    } else if status.is_wt_deleted() {
+        'D'
+    } else if status.is_wt_renamed() {
+        'R'
+    } else if status.is_wt_typechange() {
+        'T'
+    } else {
+        ' '
+    };
+    format!("{}{}", index, worktree)
+}
+
+// Changed files of the checkout. Untracked files are left out for bare
+// repositories, where the work tree is all of $HOME.
+pub fn status_lines(repo: &Repository, untracked: bool) -> Result<Vec<String>, git2::Error> {
+    let mut options = StatusOptions::new();
+    for pathspec in sparse_pathspecs(repo) {
+        options.pathspec(pathspec);
+    }
+    options
+        .include_untracked(untracked)
+        .recurse_untracked_dirs(untracked)
+        .include_ignored(false);
+    let statuses = repo.statuses(Some(&mut options))?;
+    Ok(statuses
+        .iter()
+        .map(|entry| {
+            format!(
+                "{} {}",
+                short_status(entry.status()),
+                entry.path().unwrap_or("")
+            )
+        })
+        .collect())
+}
+
+// Stash uncommitted changes to tracked files. Returns whether a stash was made.
+pub fn stash_local_changes(repo: &mut Repository) -> Result<bool, git2::Error> {
+    let changes = local_changes(repo)?;
+    if changes.is_empty() {
+        return Ok(false);
+    }
+    let signature = repo
+        .signature()
+        .or_else(|_| Signature::now("dot-utils", "dot-utils@localhost"))?;
+    if sparse::packages(repo).is_none() {
+        repo.stash_save(&signature, STASH_MESSAGE, Some(StashFlags::DEFAULT))?;
+    } else {
+        // Stashing the whole tree would bring back every deselected package,
+        // so stash exactly the changed files. git2 cannot attach a message to
+        // a path-limited stash.
+        let mut options = StashSaveOptions::new(signature);
+        options.flags(Some(StashFlags::DEFAULT));
+        for path in changes {
+            options.pathspec(path);
+        }
+        repo.stash_save_ext(Some(&mut options))?;
+        // libgit2 resets the whole working tree after stashing, drop the
+        // packages that are not part of the sparse set again
+        sparse::refresh(repo)?;
+    }
+    Ok(true)
+}
+
+// Re-apply the stash made before syncing and drop it only once it applied
+// cleanly, so conflicting changes are never lost.
+pub fn restore_stash(repo: &mut Repository) -> Result<(), String> {
+    let mut options = StashApplyOptions::new();
+    let applied = repo.stash_apply(0, Some(&mut options));
+    let conflicted = repo
+        .index()
+        .map(|index| index.has_conflicts())
+        .unwrap_or(false);
+
+    match applied {
+        Ok(()) if !conflicted => repo.stash_drop(0).map_err(|e| {
+            format!(
+                "Local changes restored but the stash could not be dropped: {}",
+                e
+            )
+        }),
+        Ok(()) => Err(String::from(
+            "Re-applying local changes produced conflicts. Resolve them manually; \
+             the stash 'stash@{0}' was kept.",
+        )),
+        Err(e) => Err(format!(
+            "Failed to re-apply local changes ({}). They are kept in 'stash@{{0}}'.",
+            e.message()
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use std::fs;
+
+    fn repo() -> (tempfile::TempDir, Repository) {
+        let (dir, repo) = testing::repo();
+        testing::stage(&repo, "zsh/.zshrc", "one\n");
+        testing::commit(&repo, "Add zsh");
+        (dir, repo)
+    }
+
+    fn read(repo: &Repository, path: &str) -> String {
+        fs::read_to_string(repo.workdir().unwrap().join(path)).unwrap()
+    }
+
+    fn stashes(repo: &mut Repository) -> Vec<String> {
+        let mut messages = Vec::new();
+        repo.stash_foreach(|_, message, _| {
+            messages.push(message.to_string());
+            true
+        })
+        .unwrap();
+        messages
+    }
+
+    #[test]
+    fn leaves_a_clean_tree_alone() {
+        let (_dir, mut repo) = repo();
+        assert!(!stash_local_changes(&mut repo).unwrap());
+        assert!(stashes(&mut repo).is_empty());
+    }
+
+    #[test]
+    fn stashes_and_restores_local_changes() {
+        let (_dir, mut repo) = repo();
+        testing::write(&repo, "zsh/.zshrc", "local\n");
+
+        assert!(stash_local_changes(&mut repo).unwrap());
+        assert_eq!(read(&repo, "zsh/.zshrc"), "one\n");
+        let messages = stashes(&mut repo);
+        assert_eq!(messages.len(), 1);
+        assert!(messages[0].ends_with(STASH_MESSAGE));
+
+        restore_stash(&mut repo).unwrap();
+        assert_eq!(read(&repo, "zsh/.zshrc"), "local\n");
+        assert!(stashes(&mut repo).is_empty());
+    }
+
+    #[test]
+    fn keeps_the_stash_when_restoring_conflicts() {
+        let (_dir, mut repo) = repo();
+        testing::write(&repo, "zsh/.zshrc", "local\n");
+        assert!(stash_local_changes(&mut repo).unwrap());
+        // What a merge from origin would bring in
+        testing::stage(&repo, "zsh/.zshrc", "remote\n");
+        testing::commit(&repo, "Change zsh");
+
+        assert!(restore_stash(&mut repo).is_err());
+        assert_eq!(stashes(&mut repo).len(), 1);
+    }
+}
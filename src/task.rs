@@ -0,0 +1,86 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::sync::Arc;
+use std::thread;
+
+pub enum TaskEvent {
+    Progress { label: String, ratio: f64 },
+    Finished(String),
+}
+
+// Long running work executed off the UI thread. The worker reports progress
+// through a channel that the terminal loop drains between redraws.
+pub struct Task {
+    pub title: String,
+    pub label: String,
+    pub ratio: f64,
+    receiver: Receiver<TaskEvent>,
+    // Set for work that can be cancelled
+    cancelled: Option<Arc<AtomicBool>>,
+}
+
+impl Task {
+    pub fn spawn<F>(title: &str, work: F) -> Self
+    where
+        F: FnOnce(&Sender<TaskEvent>) -> String + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let output = work(&sender);
+            let _ = sender.send(TaskEvent::Finished(output));
+        });
+        Self {
+            title: String::from(title),
+            label: String::from("Starting..."),
+            ratio: 0.0,
+            receiver,
+            cancelled: None,
+        }
+    }
+
+    // Like spawn, for work that checks the flag and stops early once it is set
+    pub fn spawn_cancellable<F>(title: &str, work: F) -> Self
+    where
+        F: FnOnce(&Sender<TaskEvent>, &AtomicBool) -> String + Send + 'static,
+    {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let flag = Arc::clone(&cancelled);
+        let mut task = Self::spawn(title, move |sender| work(sender, &flag));
+        task.cancelled = Some(cancelled);
+        task
+    }
+
+    // Ask the work to stop, it still reports its output when it does
+    pub fn cancel(&mut self) {
+        if let Some(cancelled) = &self.cancelled {
+            cancelled.store(true, Ordering::Relaxed);
+            self.label = String::from("Cancelling...");
+        }
+    }
+
+    // Apply pending progress updates, returning the output once finished
+    pub fn poll(&mut self) -> Option<String> {
+        loop {
+            match self.receiver.try_recv() {
+                Ok(TaskEvent::Progress { label, ratio }) => {
+                    self.label = label;
+                    self.ratio = ratio.clamp(0.0, 1.0);
+                }
+                Ok(TaskEvent::Finished(output)) => return Some(output),
+                Err(TryRecvError::Empty) => return None,
+                Err(TryRecvError::Disconnected) => {
+                    return Some(format!("{} stopped unexpectedly.", self.title))
+                }
+            }
+        }
+    }
+}
+
+pub fn progress(sender: &Sender<TaskEvent>, label: String, done: usize, total: usize) {
+    let ratio = if total == 0 {
+        0.0
+    } else {
+        done as f64 / total as f64
+    };
+    let _ = sender.send(TaskEvent::Progress { label, ratio });
+}
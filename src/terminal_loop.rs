@@ -4,27 +4,33 @@ use crossterm::event::{self, Event, KeyCode};
 use ratatui::{
     layout::{Constraint, Direction, Layout},
     style::{Color, Modifier, Style},
-    text::{Line, Span},
-    widgets::{Block, Borders, List, ListItem, Paragraph},
+    text::{Line, Span, Text},
+    widgets::{Block, Borders, Gauge, List, ListItem, ListState, Paragraph, Wrap},
     Terminal,
 };
 use std::io;
+use std::time::Duration;
 
 const CATPUCCIN_MANTLE: Color = Color::Rgb(24, 24, 37);
 const CATPUCCIN_MUAVE: Color = Color::Rgb(203, 166, 247);
+// How long to wait for input before redrawing background task progress
+const TICK_RATE: Duration = Duration::from_millis(100);
+// Pickers can hold hundreds of entries, cap their height so output stays visible
+const MAX_PICKER_HEIGHT: u16 = 15;
 pub fn run_app<B: ratatui::backend::Backend>(
     terminal: &mut Terminal<B>,
     mut app: App,
 ) -> io::Result<()> {
     loop {
+        app.poll_task();
         terminal.draw(|f| {
             let main_chunks = Layout::default()
                 .direction(Direction::Vertical)
                 .margin(1)
                 .constraints(
                     [
-                        Constraint::Length(if app.is_in_neovim_menu {
-                            app.neovim_menu_items.len() as u16 + 2
+                        Constraint::Length(if let Some(picker) = &app.picker {
+                            (picker.items.len() as u16 + 2).min(MAX_PICKER_HEIGHT)
                         } else {
                             app.menu_items.len() as u16 + 2
                         }),
@@ -47,19 +53,22 @@ pub fn run_app<B: ratatui::backend::Backend>(
                 )
                 .split(main_chunks[0]);
 
-            let items: Vec<ListItem> = if app.is_in_neovim_menu {
-                // Render submenu items
-                app.neovim_menu_items
+            let mut list_state = ListState::default();
+            let items: Vec<ListItem> = if let Some(picker) = &app.picker {
+                // Render picker items
+                list_state.select(Some(picker.state));
+                picker
+                    .items
                     .iter()
                     .enumerate()
                     .map(|(i, (name, _))| {
-                        let style = if i == app.neovim_menu_state {
+                        let style = if i == picker.state {
                             Style::default().fg(CATPUCCIN_MANTLE).bg(CATPUCCIN_MUAVE)
                         } else {
                             Style::default().fg(CATPUCCIN_MUAVE)
                         };
 
-                        let content = Line::from(vec![Span::styled(name.to_string(), style)]);
+                        let content = Line::from(vec![Span::styled(name.clone(), style)]);
                         ListItem::new(content)
                     })
                     .collect()
@@ -81,8 +90,8 @@ pub fn run_app<B: ratatui::backend::Backend>(
                     .collect()
             };
 
-            let menu_title = if app.is_in_neovim_menu {
-                "Advanced Options"
+            let menu_title = if let Some(picker) = &app.picker {
+                picker.title.as_str()
             } else {
                 "Menu"
             };
@@ -96,42 +105,110 @@ pub fn run_app<B: ratatui::backend::Backend>(
                         .add_modifier(Modifier::BOLD),
                 );
 
-            let output = Paragraph::new(Line::from(app.output.as_str()))
+            let output = Paragraph::new(Text::from(app.output.as_str()))
                 .block(Block::default().title("Output").borders(Borders::ALL))
                 .style(Style::default().bg(CATPUCCIN_MANTLE).fg(CATPUCCIN_MUAVE))
                 .scroll(app.scroll);
 
-            f.render_widget(menu, menu_chunks[1]);
+            f.render_stateful_widget(menu, menu_chunks[1], &mut list_state);
             f.render_widget(output, main_chunks[2]);
+
+            if let Some(task) = &app.task {
+                let gauge = Gauge::default()
+                    .block(
+                        Block::default()
+                            .title(task.title.as_str())
+                            .borders(Borders::ALL),
+                    )
+                    .gauge_style(Style::default().fg(CATPUCCIN_MUAVE).bg(CATPUCCIN_MANTLE))
+                    .label(task.label.as_str())
+                    .ratio(task.ratio);
+                let gauge_area = Layout::default()
+                    .direction(Direction::Vertical)
+                    .constraints([Constraint::Length(3), Constraint::Min(0)].as_ref())
+                    .split(main_chunks[1])[0];
+                f.render_widget(gauge, gauge_area);
+            }
+
+            if let Some((message, _)) = &app.confirm {
+                let confirm = Paragraph::new(Text::from(vec![
+                    Line::from(message.as_str()),
+                    Line::from(""),
+                    Line::from("Press y to confirm, n to cancel."),
+                ]))
+                .block(Block::default().title("Confirm").borders(Borders::ALL))
+                .style(Style::default().bg(CATPUCCIN_MUAVE).fg(CATPUCCIN_MANTLE))
+                .wrap(Wrap { trim: true });
+                f.render_widget(confirm, main_chunks[1]);
+            }
+
+            if let Some(input) = &app.input {
+                let value = if input.masked {
+                    "*".repeat(input.value.chars().count())
+                } else {
+                    input.value.clone()
+                };
+                let prompt = Paragraph::new(Text::from(vec![
+                    Line::from(input.prompt.as_str()),
+                    Line::from(""),
+                    Line::from(format!("> {}_", value)),
+                    Line::from(""),
+                    Line::from("Press Enter to accept, Esc to cancel."),
+                ]))
+                .block(Block::default().title("Input").borders(Borders::ALL))
+                .style(Style::default().bg(CATPUCCIN_MUAVE).fg(CATPUCCIN_MANTLE))
+                .wrap(Wrap { trim: false });
+                f.render_widget(prompt, main_chunks[1]);
+            }
         })?;
 
+        if !event::poll(TICK_RATE)? {
+            continue;
+        }
         if let Event::Key(key) = event::read()? {
-            match key.code {
-                KeyCode::Char('q') => {
-                    if app.is_in_neovim_menu {
-                        app.is_in_neovim_menu = false;
-                        app.output = String::from("Returned to main menu. Select an option.");
-                    } else {
-                        return Ok(());
-                    }
+            // Input is ignored while a background task is running, apart
+            // from Esc for tasks that can be cancelled
+            if app.task.is_some() {
+                if key.code == KeyCode::Esc {
+                    app.cancel_task();
+                }
+                continue;
+            }
+            if app.input.is_some() {
+                match key.code {
+                    KeyCode::Enter => app.submit_input(),
+                    KeyCode::Esc => app.cancel_input(),
+                    KeyCode::Backspace => app.erase_input(),
+                    KeyCode::Char(character) => app.type_input(character),
+                    _ => {}
+                }
+                continue;
+            }
+            if app.confirm.is_some() {
+                match key.code {
+                    KeyCode::Char('y') => app.accept_confirm(),
+                    KeyCode::Char('n') | KeyCode::Esc => app.cancel_confirm(),
+                    _ => {}
                 }
+                continue;
+            }
+            match key.code {
+                KeyCode::Char('q') | KeyCode::Esc if app.picker.is_some() => app.close_picker(),
+                KeyCode::Char('/') if app.browser.is_some() => app.open_search(),
+                KeyCode::PageDown => app.scroll_output(10),
+                KeyCode::PageUp => app.scroll_output(-10),
+                KeyCode::Char('q') => return Ok(()),
                 KeyCode::Down | KeyCode::Char('j') => app.next(),
                 KeyCode::Up | KeyCode::Char('k') => app.previous(),
                 KeyCode::Enter => {
                     // If in main menu and user selects quit
-                    if !app.is_in_neovim_menu
+                    if app.picker.is_none()
                         && matches!(app.menu_items[app.menu_state].1, MenuItem::Quit)
                     {
                         return Ok(());
                     }
                     app.execute_current();
                 }
-                KeyCode::Esc => {
-                    if app.is_in_neovim_menu {
-                        app.is_in_neovim_menu = false;
-                        app.output = String::from("Returned to main menu. Select an option.");
-                    }
-                }
                 _ => {}
             }
         }
@@ -0,0 +1,53 @@
+// Fixtures shared by the unit tests. Everything lives in a temporary
+// directory that is removed when the returned TempDir is dropped.
+use git2::{Oid, Repository, Signature, Time};
+use std::fs;
+use std::path::Path;
+use tempfile::TempDir;
+
+// A repository with a work tree and a committer identity
+pub fn repo() -> (TempDir, Repository) {
+    let dir = TempDir::new().unwrap();
+    let repo = Repository::init(dir.path()).unwrap();
+    let mut config = repo.config().unwrap();
+    config.set_str("user.name", "Test").unwrap();
+    config.set_str("user.email", "test@example.com").unwrap();
+    (dir, repo)
+}
+
+// Write a file of the work tree, creating its directories
+pub fn write(repo: &Repository, path: &str, content: &str) {
+    let file = repo.workdir().unwrap().join(path);
+    fs::create_dir_all(file.parent().unwrap()).unwrap();
+    fs::write(file, content).unwrap();
+}
+
+// Write a file of the work tree and add it to the index
+pub fn stage(repo: &Repository, path: &str, content: &str) {
+    write(repo, path, content);
+    let mut index = repo.index().unwrap();
+    index.add_path(Path::new(path)).unwrap();
+    index.write().unwrap();
+}
+
+// Commit the index on top of HEAD, a second after its parent so commits
+// sort by time the way they were made
+pub fn commit(repo: &Repository, message: &str) -> Oid {
+    let mut index = repo.index().unwrap();
+    let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+    let parent = repo.head().ok().map(|head| head.peel_to_commit().unwrap());
+    let time = parent
+        .as_ref()
+        .map_or(1_700_000_000, |parent| parent.time().seconds() + 1);
+    let signature = Signature::new("Test", "test@example.com", &Time::new(time, 0)).unwrap();
+    let parents: Vec<&git2::Commit> = parent.iter().collect();
+    repo.commit(
+        Some("HEAD"),
+        &signature,
+        &signature,
+        message,
+        &tree,
+        &parents,
+    )
+    .unwrap()
+}
@@ -1,11 +1,28 @@
-use flate2::write::GzEncoder;
-use flate2::Compression;
-use git2::Repository;
+use crate::backup::{self, ArchiveEntry, Progress};
+use crate::bare;
+use crate::branches;
+use crate::bundle;
+use crate::commit;
+use crate::compare::{self, Side};
+use crate::config::{self, BackupEncryptionMode, BackupTarget, Config};
+use crate::encryption::{self, BackupKeys, SessionPassphrase};
+use crate::history;
+use crate::layers::{self, Layer};
+use crate::nvim;
+use crate::remote::{self, RemoteSettings};
+use crate::sparse;
+use crate::state::{self, State};
+use crate::store;
+use crate::sync;
+use crate::task::{self, Task};
+use age::secrecy::{ExposeSecret, SecretString};
+use git2::{BranchType, Oid, Repository};
+use std::collections::BTreeSet;
 use std::env;
-use std::fs::File;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::Command;
-use tar::Builder;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
 pub struct App {
     pub menu_state: usize,
@@ -13,10 +30,90 @@ pub struct App {
     pub output: String,
     packages: Vec<&'static str>,
     aur_packages: Vec<&'static str>,
-    pub neovim_menu_items: Vec<(&'static str, NeoVimItem)>,
-    pub neovim_menu_state: usize,
-    pub is_in_neovim_menu: bool,
+    stow_packages: Vec<&'static str>,
+    pub picker: Option<Picker>,
+    pub browser: Option<Browser>,
+    pub confirm: Option<(String, PickerItem)>,
+    pub input: Option<Input>,
+    pub task: Option<Task>,
     pub scroll: (u16, u16),
+    config: Config,
+    state: State,
+    // Typed once per session for passphrase encrypted archives
+    passphrase: Option<SessionPassphrase>,
+    // Set while a bundle is imported, once it is unpacked and its packages
+    // should be linked after the task finishes
+    imported: Option<Arc<AtomicBool>>,
+}
+
+// A dynamically built list screen shown in place of the menu
+pub struct Picker {
+    pub title: String,
+    pub items: Vec<(String, PickerItem)>,
+    pub state: usize,
+}
+
+// An archive being browsed, shown through the picker
+pub struct Browser {
+    archive: PathBuf,
+    profile: usize,
+    entries: Vec<ArchiveEntry>,
+    query: String,
+    selection: BTreeSet<PathBuf>,
+}
+
+// A line of text typed into a prompt shown over the output
+pub struct Input {
+    pub prompt: String,
+    pub value: String,
+    pub masked: bool,
+    action: InputAction,
+}
+
+enum InputAction {
+    SearchArchive,
+    ExtractTo,
+    // Encrypt new archives, asked twice, then carry on with the item
+    NewPassphrase(PickerItem),
+    ConfirmPassphrase(PickerItem, SecretString),
+    // Open an encrypted archive, then carry on with the item
+    Unlock(PathBuf, PickerItem),
+    // Open the backups of a bundle, then import it
+    UnlockBundle(PathBuf),
+}
+
+#[derive(Clone)]
+pub enum PickerItem {
+    Commit(Oid),
+    CheckoutSafetyBranch(Oid),
+    RevertCommit(Oid),
+    HardReset(Oid),
+    BackToHistory,
+    Branch(String, BranchType),
+    TogglePackage(String),
+    BackupProfile(usize),
+    BackUp(usize),
+    OpenRestore(usize),
+    RestoreArchive(PathBuf, usize),
+    OpenVerify(usize),
+    VerifyArchive(PathBuf, usize),
+    OpenBrowse(usize),
+    BrowseArchive(PathBuf, usize),
+    BrowserToggle(PathBuf),
+    BrowserSearch,
+    BrowserExtract,
+    BrowserExtractTo,
+    CollectGarbage(usize),
+    OpenCompare(usize),
+    CompareFrom(PathBuf, usize),
+    // Compare a backup with another one, or with the live files
+    CompareWith(PathBuf, Option<PathBuf>, usize),
+    BackToBackups,
+    ExportBundle,
+    ImportBundle(PathBuf),
+    SyncPlugins,
+    OpenLockfileHistory,
+    RestoreLockfile(Oid),
 }
 
 pub enum MenuItem {
@@ -27,19 +124,30 @@ pub enum MenuItem {
     LinkDotFiles,
     UnLinkDotFiles,
     SyncDotFiles,
-    NeoVimMenu,
+    Status,
+    CommitDotFiles,
+    History,
+    Branches,
+    Packages,
+    Layers,
+    NeoVim,
+    Backups,
+    Bundles,
     Quit,
 }
 
-pub enum NeoVimItem {
-    BackUpState,
-    BackUpShare,
-    BackupCache,
-    BackToMainMenu,
-}
-
 impl App {
     pub fn new() -> Self {
+        let (config, output) = match config::load() {
+            Ok(config) => (
+                config,
+                String::from("Welcome! Select an option and press Enter to execute."),
+            ),
+            Err(e) => (
+                Config::default(),
+                format!("{}\nUsing the default configuration.", e),
+            ),
+        };
         Self {
             menu_state: 0,
             menu_items: vec![
@@ -50,40 +158,61 @@ impl App {
                 ("Link Dotfiles", MenuItem::LinkDotFiles),
                 ("Unlink Dotfiles", MenuItem::UnLinkDotFiles),
                 ("Sync Dotfiles", MenuItem::SyncDotFiles),
-                ("NeoVim", MenuItem::NeoVimMenu),
+                ("Dotfiles Status", MenuItem::Status),
+                ("Commit & Push Dotfiles", MenuItem::CommitDotFiles),
+                ("Dotfiles History", MenuItem::History),
+                ("Switch Branch", MenuItem::Branches),
+                ("Select Packages", MenuItem::Packages),
+                ("Layered Files", MenuItem::Layers),
+                ("NeoVim", MenuItem::NeoVim),
+                ("Backups", MenuItem::Backups),
+                ("Bundles", MenuItem::Bundles),
                 ("Quit", MenuItem::Quit),
             ],
-            output: String::from("Welcome! Select an option and press Enter to execute."),
+            output,
             packages: vec![
                 "bash", "btop", "kitty", "neovim", "tmux", "vim", "zsh", "zoxide", "stow",
             ],
             aur_packages: vec!["bat", "fzf", "starship"],
-            neovim_menu_items: vec![
-                ("Back Up State", NeoVimItem::BackUpState),
-                ("Back Up Share", NeoVimItem::BackUpShare),
-                ("Back Up Cache", NeoVimItem::BackupCache),
-                ("Back To Main Menu", NeoVimItem::BackToMainMenu),
+            stow_packages: vec![
+                "bash",
+                "bat",
+                "btop",
+                "fastfetch",
+                "kitty",
+                "nvim",
+                "starship",
+                "tmux",
+                "vimrc",
+                "zsh",
             ],
-            neovim_menu_state: 0,
-            is_in_neovim_menu: false,
+            picker: None,
+            browser: None,
+            confirm: None,
+            input: None,
+            task: None,
             scroll: (0, 0),
+            config,
+            state: State::load(),
+            passphrase: None,
+            imported: None,
         }
     }
 
     pub fn next(&mut self) {
-        if self.is_in_neovim_menu {
-            self.neovim_menu_state = (self.neovim_menu_state + 1) % self.neovim_menu_items.len();
+        if let Some(picker) = &mut self.picker {
+            picker.state = (picker.state + 1) % picker.items.len();
         } else {
             self.menu_state = (self.menu_state + 1) % self.menu_items.len();
         }
     }
 
     pub fn previous(&mut self) {
-        if self.is_in_neovim_menu {
-            if self.neovim_menu_state > 0 {
-                self.neovim_menu_state -= 1;
+        if let Some(picker) = &mut self.picker {
+            if picker.state > 0 {
+                picker.state -= 1;
             } else {
-                self.neovim_menu_state = self.neovim_menu_items.len() - 1;
+                picker.state = picker.items.len() - 1;
             }
         } else {
             if self.menu_state > 0 {
@@ -95,13 +224,10 @@ impl App {
     }
 
     pub fn execute_current(&mut self) {
-        if self.is_in_neovim_menu {
-            match self.neovim_menu_items[self.neovim_menu_state].1 {
-                NeoVimItem::BackUpState => self.backup_state(),
-                NeoVimItem::BackUpShare => self.backup_share(),
-                NeoVimItem::BackupCache => self.backup_cache(),
-                NeoVimItem::BackToMainMenu => self.is_in_neovim_menu = false,
-            }
+        self.scroll = (0, 0);
+        if let Some(picker) = &self.picker {
+            let item = picker.items[picker.state].1.clone();
+            self.execute_picker_item(item);
         } else {
             match self.menu_items[self.menu_state].1 {
                 MenuItem::UpdatePackages => self.update_pkgs(),
@@ -111,23 +237,282 @@ impl App {
                 MenuItem::LinkDotFiles => self.link_dot_files(),
                 MenuItem::UnLinkDotFiles => self.unstow_dot_files(),
                 MenuItem::SyncDotFiles => self.update_dotfiles(),
+                MenuItem::Status => self.show_status(),
+                MenuItem::CommitDotFiles => self.commit_dotfiles(),
+                MenuItem::History => self.open_history(),
+                MenuItem::Branches => self.open_branches(),
+                MenuItem::Packages => self.open_packages(),
+                MenuItem::Layers => self.show_layers(),
+                MenuItem::NeoVim => self.open_neovim(),
+                MenuItem::Backups => self.open_backups(),
+                MenuItem::Bundles => self.open_bundles(),
                 MenuItem::Quit => {}
-                MenuItem::NeoVimMenu => {
-                    self.is_in_neovim_menu = true;
-                    self.output = String::from("Welcome to NeoVim Menu!");
+            }
+        }
+    }
+    fn execute_picker_item(&mut self, item: PickerItem) {
+        match item {
+            PickerItem::Commit(oid) => self.open_commit(oid),
+            PickerItem::CheckoutSafetyBranch(oid) => self.checkout_safety_branch(oid),
+            PickerItem::RevertCommit(oid) => self.revert_commit(oid),
+            PickerItem::HardReset(oid) => {
+                self.confirm = Some((
+                    format!(
+                        "Hard reset dotfiles to {}? Uncommitted changes will be lost.",
+                        history::short_id(oid)
+                    ),
+                    item,
+                ));
+            }
+            PickerItem::BackToHistory => self.open_history(),
+            PickerItem::Branch(name, branch_type) => self.switch_branch(&name, branch_type),
+            PickerItem::TogglePackage(package) => self.toggle_package(&package),
+            PickerItem::BackupProfile(profile) => self.open_backup_profile(profile),
+            PickerItem::BackUp(profile) => self.back_up(profile),
+            PickerItem::OpenRestore(profile) => self.open_restore(profile),
+            PickerItem::RestoreArchive(ref archive, profile) => {
+                if !self.unlock(archive, &item) {
+                    return;
+                }
+                let summary = match self.config.backups.profiles[profile].target {
+                    BackupTarget::Archive => {
+                        backup::describe(archive, &self.backup_keys()).map_err(|e| e.to_string())
+                    }
+                    BackupTarget::Store => store::describe(archive),
+                };
+                self.output = match summary {
+                    Ok(summary) => summary,
+                    Err(e) => format!("Failed to read {}: {}", archive.display(), e),
+                };
+                self.confirm = Some((
+                    format!(
+                        "Restore {} into {}? Existing files are moved aside first.",
+                        archive.display(),
+                        self.config.backups.profiles[profile].paths.join(", ")
+                    ),
+                    item.clone(),
+                ));
+            }
+            PickerItem::OpenVerify(profile) => self.open_verify(profile),
+            PickerItem::VerifyArchive(ref archive, profile) => {
+                if !self.unlock(archive, &item) {
+                    return;
+                }
+                let verified = match self.config.backups.profiles[profile].target {
+                    BackupTarget::Archive => backup::verify(archive, &self.backup_keys()),
+                    BackupTarget::Store => store::verify(archive),
+                };
+                self.output = match verified {
+                    Ok(report) => report,
+                    Err(e) => e,
+                };
+            }
+            PickerItem::OpenBrowse(profile) => self.open_browse(profile),
+            PickerItem::BrowseArchive(ref archive, profile) => {
+                if self.unlock(archive, &item) {
+                    self.browse_archive(archive.clone(), profile);
+                }
+            }
+            PickerItem::BrowserToggle(path) => self.toggle_browser_entry(path),
+            PickerItem::BrowserSearch => self.open_search(),
+            PickerItem::BrowserExtract => {
+                let Some(browser) = &self.browser else {
+                    return;
+                };
+                if browser.selection.is_empty() {
+                    self.output = String::from("Select files or directories to extract first.");
+                    return;
+                }
+                self.confirm = Some((
+                    format!(
+                        "Extract {} selected entries to their original location? \
+                         Existing files are moved aside first.",
+                        browser.selection.len()
+                    ),
+                    item,
+                ));
+            }
+            PickerItem::BrowserExtractTo => {
+                if self
+                    .browser
+                    .as_ref()
+                    .is_some_and(|browser| browser.selection.is_empty())
+                {
+                    self.output = String::from("Select files or directories to extract first.");
+                    return;
+                }
+                self.input = Some(Input {
+                    prompt: String::from("Extract the selected entries into directory:"),
+                    value: String::from("~/"),
+                    masked: false,
+                    action: InputAction::ExtractTo,
+                });
+            }
+            PickerItem::CollectGarbage(profile) => {
+                self.output =
+                    match store::gc(&self.config.backups, &self.config.backups.profiles[profile]) {
+                        Ok(report) => report,
+                        Err(e) => e,
+                    };
+            }
+            PickerItem::OpenCompare(profile) => self.open_compare(profile),
+            PickerItem::CompareFrom(ref archive, profile) => {
+                if self.unlock(archive, &item) {
+                    self.open_compare_with(archive.clone(), profile);
+                }
+            }
+            PickerItem::CompareWith(ref from, ref to, profile) => {
+                if !self.unlock(from, &item) {
+                    return;
+                }
+                if let Some(to) = to {
+                    if !self.unlock(to, &item) {
+                        return;
+                    }
+                }
+                self.compare_backups(from, to.as_deref(), profile);
+            }
+            PickerItem::BackToBackups => self.open_backups(),
+            PickerItem::SyncPlugins => self.sync_plugins(),
+            PickerItem::OpenLockfileHistory => self.open_lockfile_history(),
+            PickerItem::RestoreLockfile(oid) => {
+                self.confirm = Some((
+                    format!(
+                        "Restore {} from {}? lazy.nvim then checks out the plugin commits it pins.",
+                        nvim::LOCKFILE,
+                        history::short_id(oid)
+                    ),
+                    item,
+                ));
+            }
+            PickerItem::ExportBundle => self.export_bundle(),
+            PickerItem::ImportBundle(ref path) => {
+                let manifest = match bundle::read_manifest(path) {
+                    Ok(manifest) => manifest,
+                    Err(e) => {
+                        self.output = e;
+                        return;
+                    }
+                };
+                self.output = format!("{}\n{}", path.display(), bundle::describe(&manifest));
+                self.confirm = Some((
+                    format!(
+                        "Import {}? The dotfiles are cloned and linked, and backed up paths \
+                         are moved aside before their backups are restored.",
+                        path.file_name().unwrap_or_default().to_string_lossy()
+                    ),
+                    item.clone(),
+                ));
+            }
+        }
+    }
+
+    pub fn close_picker(&mut self) {
+        self.picker = None;
+        self.browser = None;
+        self.output = String::from("Returned to main menu. Select an option.");
+    }
+
+    pub fn accept_confirm(&mut self) {
+        self.scroll = (0, 0);
+        match self.confirm.take() {
+            Some((_, PickerItem::HardReset(oid))) => self.hard_reset(oid),
+            Some((_, PickerItem::RestoreArchive(archive, profile))) => {
+                self.restore_archive(&archive, profile)
+            }
+            Some((_, PickerItem::BrowserExtract)) => self.extract_selection(None),
+            Some((_, PickerItem::ImportBundle(path))) => self.import_bundle(&path),
+            Some((_, PickerItem::RestoreLockfile(oid))) => self.restore_lockfile(oid),
+            _ => {}
+        }
+    }
+
+    pub fn type_input(&mut self, character: char) {
+        if let Some(input) = &mut self.input {
+            input.value.push(character);
+        }
+    }
+
+    pub fn erase_input(&mut self) {
+        if let Some(input) = &mut self.input {
+            input.value.pop();
+        }
+    }
+
+    pub fn submit_input(&mut self) {
+        self.scroll = (0, 0);
+        let Some(input) = self.input.take() else {
+            return;
+        };
+        match input.action {
+            InputAction::SearchArchive => {
+                if let Some(browser) = &mut self.browser {
+                    browser.query = input.value.trim().to_string();
+                }
+                self.refresh_browser(Some(0));
+            }
+            InputAction::ExtractTo => {
+                let directory = config::expand_home(input.value.trim());
+                self.extract_selection(Some(directory));
+            }
+            InputAction::NewPassphrase(item) => {
+                if input.value.is_empty() {
+                    self.output = String::from("The passphrase can not be empty.");
+                    return;
+                }
+                self.input = Some(Input {
+                    prompt: String::from("Repeat the passphrase:"),
+                    value: String::new(),
+                    masked: true,
+                    action: InputAction::ConfirmPassphrase(item, SecretString::from(input.value)),
+                });
+            }
+            InputAction::ConfirmPassphrase(item, passphrase) => {
+                if passphrase.expose_secret() != input.value {
+                    self.output =
+                        String::from("The passphrases do not match, nothing was written.");
+                    return;
                 }
+                self.passphrase = Some(SessionPassphrase::new(passphrase));
+                self.execute_picker_item(item);
+            }
+            InputAction::Unlock(archive, item) => {
+                let passphrase = SessionPassphrase::new(SecretString::from(input.value));
+                match encryption::check_passphrase(&archive, &passphrase) {
+                    Ok(()) => {
+                        self.passphrase = Some(passphrase);
+                        self.execute_picker_item(item);
+                    }
+                    Err(e) => {
+                        self.output = format!("Failed to decrypt {}: {}", archive.display(), e)
+                    }
+                }
+            }
+            InputAction::UnlockBundle(bundle) => {
+                self.passphrase = Some(SessionPassphrase::new(SecretString::from(input.value)));
+                self.import_bundle(&bundle);
             }
         }
     }
+
+    pub fn cancel_input(&mut self) {
+        self.input = None;
+        self.output = String::from("Cancelled.");
+    }
+
+    pub fn cancel_confirm(&mut self) {
+        self.confirm = None;
+        self.output = String::from("Cancelled.");
+    }
+
+    pub fn scroll_output(&mut self, lines: i32) {
+        self.scroll.0 = (self.scroll.0 as i32 + lines).max(0) as u16;
+    }
+
     fn is_command_exist(&mut self, cmd: &str, checker: Option<&str>) -> bool {
         let checker = checker.unwrap_or("--version");
-        let output = Command::new(&cmd).arg(&checker).output();
-        let is_exist;
-        match output {
-            Ok(_) => is_exist = true,
-            _ => is_exist = false,
-        }
-        is_exist
+        let output = Command::new(cmd).arg(checker).output();
+        output.is_ok()
     }
 
     fn get_package_manager(&mut self) -> String {
@@ -154,69 +539,124 @@ impl App {
 
     fn update_pkgs(&mut self) {
         let package_manager = self.get_package_manager();
-        let output;
-        match package_manager.as_str() {
-            "apt" => {
-                output = Command::new("sudo")
-                    .arg("apt")
-                    .arg("update")
-                    .arg("-y")
-                    .arg("-qq")
-                    .output();
-            }
-            "yay" => {
-                output = Command::new("yay").arg("-Sy").output();
-            }
-            "pacman" => {
-                output = Command::new("sudo").arg("pacman").arg("-Sy").output();
-            }
+        let output = match package_manager.as_str() {
+            "apt" => Command::new("sudo")
+                .arg("apt")
+                .arg("update")
+                .arg("-y")
+                .arg("-qq")
+                .output(),
+            "yay" => Command::new("yay").arg("-Sy").output(),
+            "pacman" => Command::new("sudo").arg("pacman").arg("-Sy").output(),
             _ => panic!("No valid package manager avaliable!"),
-        }
+        };
         match output {
             Ok(output) => self.output = format!("{}", String::from_utf8_lossy(&output.stdout)),
             Err(e) => self.output = format!("{}", e),
         }
     }
     fn get_home_directory(&mut self) -> String {
-        let home_folder = env::var("HOME").expect("$HOME envirenment variable is not set!");
-        home_folder
+        env::var("HOME").expect("$HOME envirenment variable is not set!")
+    }
+    fn get_dotfiles_path(&mut self) -> String {
+        format!("{}/.dotfiles", self.get_home_directory())
+    }
+    fn open_dotfiles_repo(&mut self) -> Result<Repository, git2::Error> {
+        let repo = Repository::open(self.get_dotfiles_path())?;
+        // A bare dotfiles repository uses $HOME as its work tree
+        if self.config.bare {
+            repo.set_workdir(Path::new(&self.get_home_directory()), false)?;
+        }
+        Ok(repo)
     }
-    fn clone_repo(&mut self) -> String {
-        let repo_url = String::from("https://github.com/farukerdem34/dotfiles.git");
-        let home_folder = self.get_home_directory();
-        let clone_path = format!("{}/.dotfiles", &home_folder);
 
-        match Repository::clone(&repo_url, &clone_path) {
-            Ok(_) => String::from("Repository cloned successfully!"),
-            Err(e) => e.to_string(),
+    // Syncing or committing a bare repository whose files were unlinked
+    // would record all of them as deleted
+    fn refuse_unlinked(&mut self, repo: &Repository) -> bool {
+        if self.config.bare && bare::is_unlinked(repo) {
+            self.output = String::from("Dotfiles are unlinked from $HOME. Link them first.");
+            return true;
         }
+        false
+    }
+    // Layering replaces stow with per-file links across several checkouts
+    fn is_layered(&self) -> bool {
+        !self.config.layers.is_empty() && !self.config.bare
     }
+
+    fn dotfile_layers(&mut self) -> Vec<Layer> {
+        layers::layers(Path::new(&self.get_dotfiles_path()), &self.config)
+    }
+
     fn clone_repository(&mut self) {
-        self.output = String::from("Cloning repository...");
-        let output = self.clone_repo();
-        self.output = String::from(format!("{}", output));
+        let mut settings = RemoteSettings::from_config(&self.config);
+        // A branch chosen earlier on this machine wins over the configured one
+        if let Some(branch) = self.state.branch_for_host() {
+            settings.branch = Some(branch);
+        }
+        if self.config.sparse && !self.config.bare {
+            settings.sparse_packages = Some(self.selected_packages());
+        }
+        let clone_path = PathBuf::from(self.get_dotfiles_path());
+        let layer_clones: Vec<(String, RemoteSettings, PathBuf)> = self
+            .config
+            .layers
+            .iter()
+            .map(|layer| {
+                (
+                    layer.name.clone(),
+                    layers::remote_settings(layer, &self.config),
+                    layers::layer_path(layer),
+                )
+            })
+            .collect();
+        self.output = format!("Cloning {}...", settings.url);
+        self.task = Some(Task::spawn("Cloning repository", move |sender| {
+            let mut output = remote::clone(&settings, &clone_path, sender);
+            // Layers are independent repositories, clone the ones not present yet
+            for (name, settings, path) in layer_clones {
+                output.push_str(&format!("\n\nLayer {}: ", name));
+                if path.exists() {
+                    output.push_str("already cloned");
+                } else {
+                    output.push_str(&remote::clone(&settings, &path, sender));
+                }
+            }
+            output
+        }));
+    }
+
+    pub fn cancel_task(&mut self) {
+        if let Some(task) = &mut self.task {
+            task.cancel();
+        }
+    }
+
+    pub fn poll_task(&mut self) {
+        if let Some(output) = self.task.as_mut().and_then(|task| task.poll()) {
+            self.task = None;
+            self.output = output;
+            if let Some(unpacked) = self.imported.take() {
+                if unpacked.load(Ordering::Relaxed) {
+                    self.finish_import();
+                }
+            }
+        }
     }
 
     fn upgrade_packages(&mut self) {
         let package_manager = self.get_package_manager();
-        let output;
-        match package_manager.as_str() {
-            "apt" => {
-                output = Command::new("sudo")
-                    .arg("apt")
-                    .arg("upgrade")
-                    .arg("-qq")
-                    .arg("-y")
-                    .output();
-            }
-            "yay" => {
-                output = Command::new("yay").arg("-Syu").output();
-            }
-            "pacman" => {
-                output = Command::new("sudo").arg("pacman").arg("-Syu").output();
-            }
+        let output = match package_manager.as_str() {
+            "apt" => Command::new("sudo")
+                .arg("apt")
+                .arg("upgrade")
+                .arg("-qq")
+                .arg("-y")
+                .output(),
+            "yay" => Command::new("yay").arg("-Syu").output(),
+            "pacman" => Command::new("sudo").arg("pacman").arg("-Syu").output(),
             _ => panic!("No valid package manager avaliable!"),
-        }
+        };
         match output {
             Ok(output) => self.output = format!("{}", String::from_utf8_lossy(&output.stdout)),
             Err(e) => self.output = format!("{}", e),
@@ -224,8 +664,7 @@ impl App {
     }
     fn install_packages(&mut self) {
         let package_manager = self.get_package_manager();
-        let output;
-        match package_manager.as_str() {
+        let output = match package_manager.as_str() {
             "apt" => {
                 let mut command = Command::new("sudo");
                 command.arg("apt").arg("install").arg("-y").arg("-qq");
@@ -233,7 +672,7 @@ impl App {
                 for package in &self.packages {
                     command.arg(package);
                 }
-                output = command.output();
+                command.output()
             }
             "yay" => {
                 let mut command = Command::new("yay");
@@ -246,7 +685,7 @@ impl App {
                 for aur_package in &self.aur_packages {
                     command.arg(aur_package);
                 }
-                output = command.output();
+                command.output()
             }
             "pacman" => {
                 let mut command = Command::new("sudo");
@@ -258,14 +697,9 @@ impl App {
                 for aur_package in &self.aur_packages {
                     command.arg(aur_package);
                 }
-                output = command.output();
-            }
-            _ => {
-                output = Err(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    "No valid package manager found!",
-                ));
+                command.output()
             }
+            _ => Err(std::io::Error::other("No valid package manager found!")),
         };
         match output {
             Ok(output) => self.output = format!("{}", String::from_utf8_lossy(&output.stdout)),
@@ -273,46 +707,94 @@ impl App {
         }
     }
     fn link_dot_files(&mut self) {
+        if self.config.bare {
+            self.output = match self
+                .open_dotfiles_repo()
+                .and_then(|repo| bare::checkout_tracked(&repo))
+            {
+                Ok(report) => report,
+                Err(e) => format!("Failed to check out dotfiles: {}", e),
+            };
+            return;
+        }
+        if self.is_layered() {
+            let home = self.get_home_directory();
+            let dotfile_layers = self.dotfile_layers();
+            let packages = self.selected_packages();
+            self.output = layers::link(&dotfile_layers, Path::new(&home), &packages);
+            let dotfiles_path = self.get_dotfiles_path();
+            self.output.push_str(&encryption::decrypt_packages(
+                Path::new(&dotfiles_path),
+                Path::new(&home),
+                &packages,
+                &self.config.encryption,
+            ));
+            return;
+        }
         if self.is_command_exist("stow", Some("--version")) {
             let home = self.get_home_directory();
             let work_path;
             if Path::new(format!("{}/dotfiles", home).as_str()).is_dir() {
                 work_path = format!("{}/dotfiles", home);
-            } else if Path::new(format!("{}/dotfiles", home).as_str()).is_dir() {
+            } else if Path::new(format!("{}/.dotfiles", home).as_str()).is_dir() {
                 work_path = format!("{}/.dotfiles", home);
             } else {
                 self.output = String::from("Dotfiles directory not found in $HOME/.dotfiles");
                 panic!("Dotfiles directory not found in $HOME/.dotfiles");
             }
 
+            let packages = self.selected_packages();
             let mut cmd = Command::new("stow");
-            cmd.current_dir(work_path);
-            let packages = vec![
-                "bash",
-                "bat",
-                "btop",
-                "fastfetch",
-                "kitty",
-                "nvim",
-                "starship",
-                "tmux",
-                "vimrc",
-                "zsh",
-            ];
-            for pkg in packages {
+            cmd.current_dir(&work_path);
+            cmd.arg(encryption::STOW_IGNORE);
+            for pkg in &packages {
                 cmd.arg(pkg);
             }
             let output = cmd.output();
             match output {
                 Ok(output) => self.output = format!("{}", String::from_utf8_lossy(&output.stdout)),
-                Err(e) => self.output = format!("{}", e),
+                Err(e) => {
+                    self.output = format!("{}", e);
+                    return;
+                }
             }
+            self.output.push_str(&encryption::decrypt_packages(
+                Path::new(&work_path),
+                Path::new(&home),
+                &packages,
+                &self.config.encryption,
+            ));
         } else {
             self.output = String::from("Stow package doesn't exists.")
         }
     }
 
     fn unstow_dot_files(&mut self) {
+        if self.config.bare {
+            self.output = match self
+                .open_dotfiles_repo()
+                .and_then(|repo| bare::remove_tracked(&repo))
+            {
+                Ok(report) => report,
+                Err(e) => format!("Failed to remove dotfiles: {}", e),
+            };
+            return;
+        }
+        if self.is_layered() {
+            let home = self.get_home_directory();
+            let dotfile_layers = self.dotfile_layers();
+            let packages = layers::packages(&dotfile_layers);
+            self.output = layers::unlink(&dotfile_layers, Path::new(&home), &packages);
+            let dotfiles_path = self.get_dotfiles_path();
+            self.output.push_str(&encryption::remove_decrypted(
+                Path::new(&dotfiles_path),
+                Path::new(&home),
+                &packages,
+                &self.config.encryption,
+            ));
+            return;
+        }
+
         // First, check if stow is installed
         if !self.is_command_exist("stow", None) {
             self.output = String::from("stow is not installed. Install it first.");
@@ -341,46 +823,50 @@ impl App {
 
         let mut success_count = 0;
         let mut error_messages = Vec::new();
+        let mut removed = String::new();
 
-        for entry in dotfiles_contents {
-            if let Ok(entry) = entry {
-                if let Ok(file_type) = entry.file_type() {
-                    if file_type.is_dir() {
-                        let dir_name = entry.file_name();
-                        if let Some(dir_str) = dir_name.to_str() {
-                            // Skip .git directory and other hidden directories
-                            if dir_str.starts_with('.') {
-                                continue;
-                            }
+        for entry in dotfiles_contents.flatten() {
+            if let Ok(file_type) = entry.file_type() {
+                if file_type.is_dir() {
+                    let dir_name = entry.file_name();
+                    if let Some(dir_str) = dir_name.to_str() {
+                        // Skip .git directory and other hidden directories
+                        if dir_str.starts_with('.') {
+                            continue;
+                        }
 
-                            // Run stow -D command
-                            let output = std::process::Command::new("stow")
-                                .arg("-D") // Delete flag
-                                .arg("-v") // Verbose
-                                .arg("-d") // Directory
-                                .arg(&dotfiles_path) // Stow dir
-                                .arg("-t") // Target
-                                .arg(&home_folder) // Target dir
-                                .arg(dir_str) // Package name
-                                .output();
-
-                            match output {
-                                Ok(cmd_output) => {
-                                    if cmd_output.status.success() {
-                                        success_count += 1;
-                                    } else {
-                                        let error = String::from_utf8_lossy(&cmd_output.stderr);
-                                        error_messages.push(format!(
-                                            "Failed to unstow {}: {}",
-                                            dir_str, error
-                                        ));
-                                    }
-                                }
-                                Err(e) => {
+                        // Run stow -D command
+                        let output = std::process::Command::new("stow")
+                            .arg("-D") // Delete flag
+                            .arg("-v") // Verbose
+                            .arg(encryption::STOW_IGNORE)
+                            .arg("-d") // Directory
+                            .arg(&dotfiles_path) // Stow dir
+                            .arg("-t") // Target
+                            .arg(&home_folder) // Target dir
+                            .arg(dir_str) // Package name
+                            .output();
+
+                        match output {
+                            Ok(cmd_output) => {
+                                if cmd_output.status.success() {
+                                    success_count += 1;
+                                    removed.push_str(&encryption::remove_decrypted(
+                                        dotfiles_dir,
+                                        Path::new(&home_folder),
+                                        &[dir_str.to_string()],
+                                        &self.config.encryption,
+                                    ));
+                                } else {
+                                    let error = String::from_utf8_lossy(&cmd_output.stderr);
                                     error_messages
-                                        .push(format!("Command error for {}: {}", dir_str, e));
+                                        .push(format!("Failed to unstow {}: {}", dir_str, error));
                                 }
                             }
+                            Err(e) => {
+                                error_messages
+                                    .push(format!("Command error for {}: {}", dir_str, e));
+                            }
                         }
                     }
                 }
@@ -393,6 +879,10 @@ impl App {
                 "Successfully unstowed {} dotfile directories.",
                 success_count
             );
+            if !removed.is_empty() {
+                self.output.push_str("\n\nDecrypted files:");
+                self.output.push_str(&removed);
+            }
             if !error_messages.is_empty() {
                 self.output.push_str("\n\nErrors encountered:");
                 for msg in error_messages {
@@ -412,8 +902,7 @@ impl App {
     fn update_dotfiles(&mut self) {
         self.output = String::from("Updating dotfiles repository...");
 
-        let home_folder = self.get_home_directory();
-        let dotfiles_path = format!("{}/.dotfiles", &home_folder);
+        let dotfiles_path = self.get_dotfiles_path();
 
         // Check if the .dotfiles directory exists
         if !Path::new(&dotfiles_path).exists() {
@@ -423,254 +912,1306 @@ impl App {
         }
 
         // Open the repository
-        match Repository::open(&dotfiles_path) {
-            Ok(repo) => {
-                // Get the remote
-                match repo.find_remote("origin") {
-                    Ok(mut remote) => {
-                        // Fetch updates
-                        let fetch_result =
-                            remote.fetch(&["refs/heads/*:refs/remotes/origin/*"], None, None);
-                        if let Err(e) = fetch_result {
-                            self.output = format!("Failed to fetch from remote: {}", e);
-                            return;
-                        }
+        let mut repo = match self.open_dotfiles_repo() {
+            Ok(repo) => repo,
+            Err(e) => {
+                self.output = format!("Failed to open repository: {}", e);
+                return;
+            }
+        };
 
-                        // Get default branch reference
-                        let head = match repo.head() {
-                            Ok(head) => head,
-                            Err(e) => {
-                                self.output = format!("Failed to get HEAD reference: {}", e);
-                                return;
-                            }
-                        };
-
-                        // Get current branch name
-                        let branch_name = match head.shorthand() {
-                            Some(name) => name,
-                            _ => {
-                                self.output =
-                                    String::from("Failed to determine current branch name");
-                                return;
-                            }
-                        };
-
-                        // Lookup the remote branch we want to merge from
-                        let remote_branch_name = format!("origin/{}", branch_name);
-                        let remote_ref = match repo
-                            .find_reference(&format!("refs/remotes/{}", remote_branch_name))
-                        {
-                            Ok(reference) => reference,
-                            Err(e) => {
-                                self.output = format!("Failed to find remote reference: {}", e);
-                                return;
-                            }
-                        };
+        if self.refuse_unlinked(&repo) {
+            return;
+        }
 
-                        // Convert the reference to an annotated commit
-                        let annotated_commit = match repo.reference_to_annotated_commit(&remote_ref)
-                        {
-                            Ok(commit) => commit,
-                            Err(e) => {
-                                self.output = format!("Failed to get annotated commit: {}", e);
-                                return;
-                            }
-                        };
+        self.output = sync::sync_repository(&mut repo, &self.config);
 
-                        let fetch_commit = match remote_ref.peel_to_commit() {
-                            Ok(commit) => commit,
-                            Err(e) => {
-                                self.output = format!("Failed to get remote commit: {}", e);
-                                return;
-                            }
-                        };
+        if self.is_layered() {
+            for layer in &self.config.layers {
+                self.output.push_str(&format!("\n\nLayer {}: ", layer.name));
+                match Repository::open(layers::layer_path(layer)) {
+                    Ok(mut repo) => self
+                        .output
+                        .push_str(&sync::sync_repository(&mut repo, &self.config)),
+                    Err(e) => self
+                        .output
+                        .push_str(&format!("Failed to open repository: {}", e)),
+                }
+            }
+            // Files added or removed upstream need their own links
+            let packages = self.available_stow_packages();
+            let relinked = self.relink_packages(&packages);
+            self.output.push_str(&relinked);
+        }
+    }
 
-                        // Perform the merge/pull
-                        let mut index = match repo.index() {
-                            Ok(index) => index,
-                            Err(e) => {
-                                self.output = format!("Failed to get repository index: {}", e);
-                                return;
-                            }
-                        };
-
-                        // Create merge options
-                        let mut merge_options = git2::MergeOptions::new();
-                        merge_options.file_favor(git2::FileFavor::Normal);
-
-                        // Merge the remote branch into the current branch
-                        if let Err(e) =
-                            repo.merge(&[&annotated_commit], Some(&mut merge_options), None)
-                        {
-                            self.output = format!("Failed to merge updates: {}", e);
-                            return;
-                        }
+    fn show_status(&mut self) {
+        let untracked = !self.config.bare;
+        let lines = match self
+            .open_dotfiles_repo()
+            .and_then(|repo| sync::status_lines(&repo, untracked))
+        {
+            Ok(lines) => lines,
+            Err(e) => {
+                self.output = format!("Failed to read status: {}", e);
+                return;
+            }
+        };
+        self.output = if lines.is_empty() {
+            String::from("No local changes.")
+        } else {
+            format!("Local changes:\n{}", lines.join("\n"))
+        };
+    }
 
-                        // Check for merge conflicts
-                        if index.has_conflicts() {
-                            self.output = String::from(
-                                "Merge conflicts detected. Please resolve them manually.",
-                            );
-                            return;
-                        }
+    fn commit_dotfiles(&mut self) {
+        let repo = match self.open_dotfiles_repo() {
+            Ok(repo) => repo,
+            Err(e) => {
+                self.output = format!("Failed to open repository: {}", e);
+                return;
+            }
+        };
+        if self.refuse_unlinked(&repo) {
+            return;
+        }
+        self.output = match commit::commit_and_push(&repo, &self.config) {
+            Ok(output) => output,
+            Err(e) => e,
+        };
+    }
 
-                        // Commit the merge if necessary
-                        let result = repo.state();
-                        if result == git2::RepositoryState::Merge {
-                            // We need to commit the merge
-                            let signature = match repo.signature() {
-                                Ok(sig) => sig,
-                                Err(e) => {
-                                    self.output = format!("Failed to create signature: {}", e);
-                                    return;
-                                }
-                            };
+    // NeoVim Functions
+    fn open_neovim(&mut self) {
+        self.picker = Some(Picker {
+            title: String::from("NeoVim"),
+            items: vec![
+                (String::from("Sync Plugins"), PickerItem::SyncPlugins),
+                (
+                    String::from("Roll Back Lockfile"),
+                    PickerItem::OpenLockfileHistory,
+                ),
+            ],
+            state: 0,
+        });
+        self.output = match nvim::detect() {
+            Some(manager) => format!("Plugins are managed by {}.", manager.name()),
+            None => String::from("Neither lazy.nvim nor packer.nvim is installed for NeoVim."),
+        };
+    }
 
-                            if let Err(e) = index.write() {
-                                self.output = format!("Failed to write index: {}", e);
-                                return;
-                            }
+    fn sync_plugins(&mut self) {
+        if !self.is_command_exist("nvim", Some("--version")) {
+            self.output = String::from("NeoVim is not installed.");
+            return;
+        }
+        self.output = String::from("Syncing NeoVim plugins...");
+        self.task = Some(Task::spawn("Syncing NeoVim plugins", nvim::sync));
+    }
 
-                            let tree_oid = match index.write_tree() {
-                                Ok(oid) => oid,
-                                Err(e) => {
-                                    self.output = format!("Failed to write tree: {}", e);
-                                    return;
-                                }
-                            };
+    fn open_lockfile_history(&mut self) {
+        if nvim::detect() == Some(nvim::PluginManager::Packer) {
+            self.output = format!(
+                "packer.nvim keeps no lockfile, only lazy.nvim's {} can be rolled back.",
+                nvim::LOCKFILE
+            );
+            return;
+        }
+        let commits = self
+            .open_dotfiles_repo()
+            .map_err(|e| e.to_string())
+            .and_then(|repo| {
+                let path = nvim::lockfile_in_repo(&repo)?;
+                nvim::lockfile_history(&repo, &path).map_err(|e| e.to_string())
+            });
+        let commits = match commits {
+            Ok(commits) if commits.is_empty() => {
+                self.output = format!("{} was never committed.", nvim::LOCKFILE);
+                return;
+            }
+            Ok(commits) => commits,
+            Err(e) => {
+                self.output = e;
+                return;
+            }
+        };
+        self.picker = Some(Picker {
+            title: format!("{} History", nvim::LOCKFILE),
+            items: commits
+                .into_iter()
+                .map(|(oid, line)| (line, PickerItem::RestoreLockfile(oid)))
+                .collect(),
+            state: 0,
+        });
+        self.output = format!(
+            "Commits that changed {}, newest first. Select one to restore its lockfile.",
+            nvim::LOCKFILE
+        );
+    }
 
-                            let tree = match repo.find_tree(tree_oid) {
-                                Ok(tree) => tree,
-                                Err(e) => {
-                                    self.output = format!("Failed to find tree: {}", e);
-                                    return;
-                                }
-                            };
-
-                            let head_commit =
-                                match repo.head().and_then(|head| head.peel_to_commit()) {
-                                    Ok(commit) => commit,
-                                    Err(e) => {
-                                        self.output = format!("Failed to get HEAD commit: {}", e);
-                                        return;
-                                    }
-                                };
-
-                            if let Err(e) = repo.commit(
-                                Some("HEAD"),
-                                &signature,
-                                &signature,
-                                "Merge remote changes",
-                                &tree,
-                                &[&head_commit, &fetch_commit],
-                            ) {
-                                self.output = format!("Failed to commit merge: {}", e);
-                                return;
-                            }
+    fn restore_lockfile(&mut self, oid: Oid) {
+        let restored = self
+            .open_dotfiles_repo()
+            .map_err(|e| e.to_string())
+            .and_then(|repo| {
+                let path = nvim::lockfile_in_repo(&repo)?;
+                nvim::restore_lockfile(&repo, oid, &path)
+            });
+        let diff = match restored {
+            Ok(diff) => diff,
+            Err(e) => {
+                self.output = e;
+                return;
+            }
+        };
+        self.picker = None;
+        self.output = format!(
+            "Restored {} from {}.\n\n{}",
+            nvim::LOCKFILE,
+            history::short_id(oid),
+            diff
+        );
+        let title = format!("Restoring plugins to {}", history::short_id(oid));
+        let report = self.output.clone();
+        self.task = Some(Task::spawn(&title, move |sender| {
+            task::progress(sender, String::from("Running Lazy! restore..."), 0, 1);
+            format!("{}\n\n{}", report, nvim::restore_plugins())
+        }));
+    }
 
-                            // Clean up the repository state
-                            if let Err(e) = repo.cleanup_state() {
-                                self.output = format!("Failed to cleanup repository state: {}", e);
-                                return;
-                            }
-                        }
+    // Backup Functions
+    fn open_backups(&mut self) {
+        if self.config.backups.profiles.is_empty() {
+            self.output = String::from("No backup profiles are configured.");
+            return;
+        }
+        let items = self
+            .config
+            .backups
+            .profiles
+            .iter()
+            .enumerate()
+            .map(|(index, profile)| {
+                (
+                    format!("{} ({})", profile.name, profile.paths.join(", ")),
+                    PickerItem::BackupProfile(index),
+                )
+            })
+            .collect();
+        self.picker = Some(Picker {
+            title: String::from("Backups"),
+            items,
+            state: 0,
+        });
+        self.output = String::from("Select a backup to back it up or restore it.");
+    }
 
-                        self.output = String::from("Dotfiles repository updated successfully!");
-                    }
-                    Err(e) => {
-                        self.output = format!("Failed to find remote 'origin': {}", e);
-                    }
+    fn open_backup_profile(&mut self, index: usize) {
+        let backups = self.list_backups(index);
+        let profile = &self.config.backups.profiles[index];
+        let (target, kind) = match profile.target {
+            BackupTarget::Archive => (
+                format!(
+                    "archives in {}",
+                    backup::profile_dir(&self.config.backups, profile).display()
+                ),
+                "Archive",
+            ),
+            BackupTarget::Store => (
+                format!(
+                    "store at {}",
+                    store::store_dir(&self.config.backups, profile).display()
+                ),
+                "Snapshot",
+            ),
+        };
+        self.output = format!(
+            "Backup {}\n\nPaths:\n{}\n\nExcludes: {}\nTarget: {}\n{}s on this host: {}\nLatest: {}",
+            profile.name,
+            profile
+                .paths
+                .iter()
+                .map(|path| format!("- {}", path))
+                .collect::<Vec<_>>()
+                .join("\n"),
+            if profile.exclude.is_empty() {
+                String::from("none")
+            } else {
+                profile.exclude.join(", ")
+            },
+            target,
+            kind,
+            backups.len(),
+            backups
+                .first()
+                .map(|backup| backup.display().to_string())
+                .unwrap_or_else(|| String::from("none"))
+        );
+        let mut items = vec![
+            (String::from("Back Up Now"), PickerItem::BackUp(index)),
+            (
+                format!("Restore From {}", kind),
+                PickerItem::OpenRestore(index),
+            ),
+            (format!("Verify {}", kind), PickerItem::OpenVerify(index)),
+            (format!("Compare {}s", kind), PickerItem::OpenCompare(index)),
+        ];
+        match profile.target {
+            BackupTarget::Archive => items.push((
+                String::from("Browse Archive"),
+                PickerItem::OpenBrowse(index),
+            )),
+            BackupTarget::Store => items.push((
+                String::from("Collect Garbage"),
+                PickerItem::CollectGarbage(index),
+            )),
+        }
+        items.push((String::from("Back To Backups"), PickerItem::BackToBackups));
+        self.picker = Some(Picker {
+            title: format!("Backup {}", profile.name),
+            items,
+            state: 0,
+        });
+    }
+
+    // Keys for encrypted archives, with the passphrase of this session
+    fn backup_keys(&self) -> BackupKeys<'_> {
+        BackupKeys {
+            config: &self.config.encryption,
+            passphrase: self.passphrase.as_ref(),
+        }
+    }
+
+    // Whether an archive can be opened, asking for its passphrase first when
+    // the one typed before does not open it. The item runs again once it does.
+    fn unlock(&mut self, archive: &Path, item: &PickerItem) -> bool {
+        if !encryption::needs_passphrase(archive) {
+            return true;
+        }
+        if let Some(passphrase) = &self.passphrase {
+            if encryption::check_passphrase(archive, passphrase).is_ok() {
+                return true;
+            }
+        }
+        self.input = Some(Input {
+            prompt: format!(
+                "Passphrase for {}:",
+                archive.file_name().unwrap_or_default().to_string_lossy()
+            ),
+            value: String::new(),
+            masked: true,
+            action: InputAction::Unlock(archive.to_path_buf(), item.clone()),
+        });
+        false
+    }
+
+    fn back_up(&mut self, index: usize) {
+        let profile = &self.config.backups.profiles[index];
+        if profile.target == BackupTarget::Archive
+            && self.config.backups.encryption.mode == BackupEncryptionMode::Passphrase
+            && self.passphrase.is_none()
+        {
+            self.input = Some(Input {
+                prompt: String::from("Passphrase to encrypt backup archives with:"),
+                value: String::new(),
+                masked: true,
+                action: InputAction::NewPassphrase(PickerItem::BackUp(index)),
+            });
+            return;
+        }
+        let home = PathBuf::from(self.get_home_directory());
+        let config = self.config.backups.clone();
+        let encryption = self.config.encryption.clone();
+        let passphrase = self.passphrase.clone();
+        let profile = self.config.backups.profiles[index].clone();
+        self.output = format!("Backing up {}, press Esc to cancel.", profile.name);
+        let title = format!("Backing up {}", profile.name);
+        self.task = Some(Task::spawn_cancellable(&title, move |sender, cancelled| {
+            let keys = BackupKeys {
+                config: &encryption,
+                passphrase: passphrase.as_ref(),
+            };
+            let mut progress = Progress::new(sender, cancelled);
+            let result = match profile.target {
+                BackupTarget::Archive => {
+                    backup::back_up(&config, &profile, &home, &keys, &mut progress)
                 }
+                BackupTarget::Store => store::back_up(&config, &profile, &home, &mut progress),
+            };
+            match result {
+                Ok(report) => format!("{} backed up successfully!\n\n{}", profile.name, report),
+                Err(e) => format!("Failed to back up {}: {}", profile.name, e),
+            }
+        }));
+    }
+
+    // Archives or snapshots of a backup profile on this host, newest first
+    fn list_backups(&self, index: usize) -> Vec<PathBuf> {
+        let profile = &self.config.backups.profiles[index];
+        match profile.target {
+            BackupTarget::Archive => {
+                let home = PathBuf::from(env::var("HOME").unwrap_or_default());
+                let mut archives = backup::list_archives(&self.config.backups, profile);
+                archives.extend(backup::legacy_archive(profile, &home));
+                archives
             }
+            BackupTarget::Store => store::list_snapshots(&self.config.backups, profile),
+        }
+    }
+
+    // Archives of a backup profile, newest first, as picker items
+    fn open_archives(
+        &mut self,
+        index: usize,
+        title: &str,
+        item: impl Fn(PathBuf, usize) -> PickerItem,
+    ) -> bool {
+        let archives = self.list_backups(index);
+        let profile = &self.config.backups.profiles[index];
+        if archives.is_empty() {
+            self.output = match profile.target {
+                BackupTarget::Archive => format!(
+                    "No {} archives found in {}.",
+                    profile.name,
+                    backup::profile_dir(&self.config.backups, profile).display()
+                ),
+                BackupTarget::Store => format!(
+                    "No {} snapshots found in {}.",
+                    profile.name,
+                    store::store_dir(&self.config.backups, profile).display()
+                ),
+            };
+            return false;
+        }
+        let title = format!("{} {}", title, profile.name);
+        let items = archives
+            .into_iter()
+            .map(|archive| {
+                let modified = std::fs::metadata(&archive)
+                    .and_then(|metadata| metadata.modified())
+                    .map(|time| {
+                        chrono::DateTime::<chrono::Local>::from(time)
+                            .format("%Y-%m-%d %H:%M")
+                            .to_string()
+                    })
+                    .unwrap_or_default();
+                let name = archive
+                    .file_name()
+                    .map(|name| name.to_string_lossy().to_string())
+                    .unwrap_or_default();
+                (format!("{} {}", modified, name), item(archive, index))
+            })
+            .collect();
+        self.picker = Some(Picker {
+            title,
+            items,
+            state: 0,
+        });
+        true
+    }
+
+    fn open_restore(&mut self, index: usize) {
+        if self.open_archives(index, "Restore", PickerItem::RestoreArchive) {
+            self.output = String::from("Select a backup to show its contents.");
+        }
+    }
+
+    fn open_verify(&mut self, index: usize) {
+        if self.open_archives(index, "Verify", PickerItem::VerifyArchive) {
+            self.output = String::from("Select a backup to check it.");
+        }
+    }
+
+    fn open_compare(&mut self, index: usize) {
+        if self.open_archives(index, "Compare", PickerItem::CompareFrom) {
+            self.output = String::from("Select the backup to compare from.");
+        }
+    }
+
+    // The second side of a comparison: the live files or another backup
+    fn open_compare_with(&mut self, from: PathBuf, index: usize) {
+        let with = from.clone();
+        self.open_archives(index, "Compare With", |archive, index| {
+            PickerItem::CompareWith(with.clone(), Some(archive), index)
+        });
+        if let Some(picker) = &mut self.picker {
+            picker.items.retain(
+                |(_, item)| !matches!(item, PickerItem::CompareWith(_, Some(to), _) if *to == from),
+            );
+            picker.items.insert(
+                0,
+                (
+                    String::from("Live Files"),
+                    PickerItem::CompareWith(from.clone(), None, index),
+                ),
+            );
+        }
+        self.output = format!(
+            "Select what to compare {} with.",
+            from.file_name().unwrap_or_default().to_string_lossy()
+        );
+    }
+
+    fn compare_backups(&mut self, from: &Path, to: Option<&Path>, index: usize) {
+        let home = PathBuf::from(self.get_home_directory());
+        let profile = &self.config.backups.profiles[index];
+        let side = |path| match profile.target {
+            BackupTarget::Archive => Side::Archive(path),
+            BackupTarget::Store => Side::Snapshot(path),
+        };
+        let to = to.map(side).unwrap_or(Side::Live);
+        self.output = match compare::compare(&side(from), &to, profile, &home, &self.backup_keys())
+        {
+            Ok(report) => report,
+            Err(e) => format!("Failed to compare: {}", e),
+        };
+    }
+
+    fn restore_archive(&mut self, archive: &Path, index: usize) {
+        let home = PathBuf::from(self.get_home_directory());
+        let profile = &self.config.backups.profiles[index];
+        let restored = match profile.target {
+            BackupTarget::Archive => backup::restore(archive, profile, &home, &self.backup_keys()),
+            BackupTarget::Store => store::restore(archive, profile, &home),
+        };
+        self.output = match restored {
+            Ok(report) => report,
+            Err(e) => e,
+        };
+        self.picker = None;
+    }
+
+    fn open_browse(&mut self, index: usize) {
+        if self.open_archives(index, "Browse", PickerItem::BrowseArchive) {
+            self.output = String::from("Select an archive to browse its files.");
+        }
+    }
+
+    fn browse_archive(&mut self, archive: PathBuf, profile: usize) {
+        let entries = match backup::list_entries(&archive, &self.backup_keys()) {
+            Ok(entries) => entries,
             Err(e) => {
-                self.output = format!("Failed to open repository: {}", e);
+                self.output = format!("Failed to read {}: {}", archive.display(), e);
+                return;
+            }
+        };
+        self.browser = Some(Browser {
+            archive,
+            profile,
+            entries,
+            query: String::new(),
+            selection: BTreeSet::new(),
+        });
+        self.refresh_browser(Some(0));
+    }
+
+    // Rebuild the picker from the browser, keeping the cursor where it was
+    // unless a position is given
+    fn refresh_browser(&mut self, position: Option<usize>) {
+        let Some(browser) = &self.browser else {
+            return;
+        };
+        let query = browser.query.to_lowercase();
+        let mut items = vec![
+            (
+                if query.is_empty() {
+                    String::from("Search... (/)")
+                } else {
+                    format!("Search: {}", browser.query)
+                },
+                PickerItem::BrowserSearch,
+            ),
+            (
+                format!(
+                    "Extract {} Selected To Original Location",
+                    browser.selection.len()
+                ),
+                PickerItem::BrowserExtract,
+            ),
+            (
+                format!(
+                    "Extract {} Selected To Directory...",
+                    browser.selection.len()
+                ),
+                PickerItem::BrowserExtractTo,
+            ),
+        ];
+        let mut matches = 0;
+        for entry in &browser.entries {
+            let path = entry.path.to_string_lossy();
+            if !query.is_empty() && !path.to_lowercase().contains(&query) {
+                continue;
+            }
+            matches += 1;
+            let mark = if browser
+                .selection
+                .iter()
+                .any(|selected| entry.path.starts_with(selected))
+            {
+                "[x]"
+            } else {
+                "[ ]"
+            };
+            // The tree is indented by depth, search results show full paths
+            let name = if query.is_empty() {
+                format!(
+                    "{}{}",
+                    "  ".repeat(entry.path.components().count().saturating_sub(1)),
+                    entry
+                        .path
+                        .file_name()
+                        .map(|name| name.to_string_lossy().to_string())
+                        .unwrap_or_default()
+                )
+            } else {
+                path.to_string()
+            };
+            let label = if entry.is_dir {
+                format!("{} {}/", mark, name)
+            } else {
+                format!(
+                    "{} {}  {}",
+                    mark,
+                    name,
+                    remote::format_bytes(entry.size as usize)
+                )
+            };
+            items.push((label, PickerItem::BrowserToggle(entry.path.clone())));
+        }
+
+        self.output = format!(
+            "{}\n{} of {} entries shown, {} selected.\n\n\
+             Enter selects a file or a whole directory, / searches, Esc closes the browser.",
+            browser.archive.display(),
+            matches,
+            browser.entries.len(),
+            browser.selection.len()
+        );
+        let title = format!(
+            "Browse {}",
+            browser
+                .archive
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default()
+        );
+        let state = position
+            .or(self.picker.as_ref().map(|picker| picker.state))
+            .unwrap_or(0)
+            .min(items.len() - 1);
+        self.picker = Some(Picker {
+            title,
+            items,
+            state,
+        });
+    }
+
+    fn toggle_browser_entry(&mut self, path: PathBuf) {
+        if let Some(browser) = &mut self.browser {
+            let covering = browser
+                .selection
+                .iter()
+                .find(|selected| path.starts_with(selected))
+                .cloned();
+            match covering {
+                Some(selected) if selected == path => {
+                    browser.selection.remove(&path);
+                }
+                // Leaving out an entry of a selected directory selects the
+                // rest of the directory instead: everything else in it, each
+                // by the topmost path that does not also hold the entry
+                Some(selected) => {
+                    browser.selection.remove(&selected);
+                    let rest: Vec<PathBuf> = browser
+                        .entries
+                        .iter()
+                        .map(|entry| &entry.path)
+                        .filter(|other| {
+                            other.starts_with(&selected)
+                                && !path.starts_with(other)
+                                && !other.starts_with(&path)
+                        })
+                        .map(|other| {
+                            let shared = other
+                                .components()
+                                .zip(path.components())
+                                .take_while(|(a, b)| a == b)
+                                .count();
+                            other.components().take(shared + 1).collect()
+                        })
+                        .collect();
+                    browser.selection.extend(rest);
+                }
+                None => {
+                    // A selected directory covers everything inside it
+                    browser
+                        .selection
+                        .retain(|selected| !selected.starts_with(&path));
+                    browser.selection.insert(path);
+                }
             }
         }
+        self.refresh_browser(None);
     }
 
-    // NeoVim Menu Functions
-    fn create_tar_gz(&self, src_dir: &str, dest_dir: &str) -> Result<(), std::io::Error> {
-        let src_path = Path::new(src_dir);
+    pub fn open_search(&mut self) {
+        let Some(browser) = &self.browser else {
+            return;
+        };
+        self.input = Some(Input {
+            prompt: String::from("Search the archive for paths containing:"),
+            value: browser.query.clone(),
+            masked: false,
+            action: InputAction::SearchArchive,
+        });
+    }
 
-        if !src_path.exists() {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::NotFound,
-                format!("Source directory {} not found", src_dir),
-            ));
+    // Extract the browser's selection to where it was backed up from, or
+    // into the given directory
+    fn extract_selection(&mut self, directory: Option<PathBuf>) {
+        let home = PathBuf::from(self.get_home_directory());
+        let Some(browser) = &self.browser else {
+            return;
+        };
+        let flatten = directory.is_some();
+        let root = match directory {
+            Some(directory) => Ok(directory),
+            None => backup::original_root(
+                &browser.archive,
+                &self.config.backups.profiles[browser.profile],
+                &home,
+                &self.backup_keys(),
+            ),
+        };
+        let result = root.and_then(|root| {
+            backup::extract(
+                &browser.archive,
+                &browser.selection,
+                &root,
+                flatten,
+                &self.backup_keys(),
+            )
+        });
+        let output = match result {
+            Ok(report) => {
+                if let Some(browser) = &mut self.browser {
+                    browser.selection.clear();
+                }
+                report
+            }
+            Err(e) => e,
+        };
+        self.refresh_browser(None);
+        self.output = output;
+    }
+
+    // Bundle Functions
+    fn open_bundles(&mut self) {
+        let mut items = vec![(String::from("Export Bundle"), PickerItem::ExportBundle)];
+        items.extend(
+            bundle::list_bundles(&self.config.backups)
+                .into_iter()
+                .map(|path| {
+                    (
+                        format!(
+                            "Import {}",
+                            path.file_name().unwrap_or_default().to_string_lossy()
+                        ),
+                        PickerItem::ImportBundle(path),
+                    )
+                }),
+        );
+        self.picker = Some(Picker {
+            title: String::from("Bundles"),
+            items,
+            state: 0,
+        });
+        self.output = format!(
+            "Bundles are kept in {}. Copy one there to import it on another machine.",
+            bundle::bundles_dir(&self.config.backups).display()
+        );
+    }
+
+    fn export_bundle(&mut self) {
+        if self.config.backups.encryption.mode == BackupEncryptionMode::Passphrase
+            && self.passphrase.is_none()
+            && !self.config.backups.profiles.is_empty()
+        {
+            self.input = Some(Input {
+                prompt: String::from("Passphrase to encrypt backup archives with:"),
+                value: String::new(),
+                masked: true,
+                action: InputAction::NewPassphrase(PickerItem::ExportBundle),
+            });
+            return;
         }
-        let tar_gz = File::create(dest_dir)?;
-        let enc = GzEncoder::new(tar_gz, Compression::default());
-        let mut tar = Builder::new(enc);
-
-        fn add_files_to_tar<W: std::io::Write>(
-            tar: &mut Builder<W>,
-            src_dir: &Path,
-            base_path: &Path,
-        ) -> Result<(), std::io::Error> {
-            if src_dir.is_dir() {
-                for entry in std::fs::read_dir(src_dir)? {
-                    let entry = entry?;
-                    let path = entry.path();
-
-                    // Skip git directories
-                    if path.file_name().and_then(|n| n.to_str()) == Some(".git") {
-                        continue;
-                    }
+        let home = PathBuf::from(self.get_home_directory());
+        let contents = bundle::Contents {
+            dotfiles: PathBuf::from(self.get_dotfiles_path()),
+            repo_url: self.config.repo_url.clone(),
+            bare: self.config.bare,
+            layers: self
+                .config
+                .layers
+                .iter()
+                .map(|layer| (layer.name.clone(), layers::layer_path(layer)))
+                .collect(),
+            packages: bundle::Packages {
+                system: self.packages.iter().map(|p| p.to_string()).collect(),
+                aur: self.aur_packages.iter().map(|p| p.to_string()).collect(),
+                stow: self.selected_packages(),
+            },
+            backups: self.config.backups.clone(),
+        };
+        let encryption = self.config.encryption.clone();
+        let passphrase = self.passphrase.clone();
+        self.output = String::from("Exporting a bundle, press Esc to cancel.");
+        self.task = Some(Task::spawn_cancellable(
+            "Exporting bundle",
+            move |sender, cancelled| {
+                let keys = BackupKeys {
+                    config: &encryption,
+                    passphrase: passphrase.as_ref(),
+                };
+                let mut progress = Progress::new(sender, cancelled);
+                match bundle::export(&contents, &home, &keys, &mut progress) {
+                    Ok(report) => format!("Bundle exported successfully!\n\n{}", report),
+                    Err(e) => format!("Failed to export a bundle: {}", e),
+                }
+            },
+        ));
+    }
 
-                    if path.is_dir() {
-                        add_files_to_tar(tar, &path, base_path)?;
-                    } else {
-                        let rel_path = path.strip_prefix(base_path).unwrap_or(&path);
-                        tar.append_path_with_name(&path, rel_path)?;
-                    }
+    // Set up this machine from a bundle without network access. The
+    // passphrase of its backups is asked for first, the import runs in the
+    // background and the packages are linked once it finishes.
+    fn import_bundle(&mut self, path: &Path) {
+        self.picker = None;
+        let locked = bundle::read_manifest(path)
+            .and_then(|manifest| bundle::locked(path, &manifest, self.passphrase.as_ref()));
+        match locked {
+            Ok(false) => {}
+            Ok(true) => {
+                if self.passphrase.is_some() {
+                    self.output = String::from("The passphrase does not open the bundled backups.");
                 }
+                self.input = Some(Input {
+                    prompt: format!(
+                        "Passphrase for the backups in {}:",
+                        path.file_name().unwrap_or_default().to_string_lossy()
+                    ),
+                    value: String::new(),
+                    masked: true,
+                    action: InputAction::UnlockBundle(path.to_path_buf()),
+                });
+                return;
+            }
+            Err(e) => {
+                self.output = format!("Failed to import {}: {}", path.display(), e);
+                return;
             }
-            Ok(())
         }
+        let bundle = path.to_path_buf();
+        let dotfiles = PathBuf::from(self.get_dotfiles_path());
+        let home = PathBuf::from(self.get_home_directory());
+        let passphrase = self.passphrase.clone();
+        let unpacked = Arc::new(AtomicBool::new(false));
+        self.imported = Some(Arc::clone(&unpacked));
+        self.output = format!("Importing {}...", path.display());
+        self.task = Some(Task::spawn(
+            "Importing bundle",
+            move |sender| match bundle::import(
+                &bundle,
+                &dotfiles,
+                &home,
+                passphrase.as_ref(),
+                &unpacked,
+                sender,
+            ) {
+                Ok(report) => format!("Imported {}\n\n{}", bundle.display(), report),
+                Err(e) => format!("Failed to import {}: {}", bundle.display(), e),
+            },
+        ));
+    }
 
-        add_files_to_tar(&mut tar, src_path, src_path)?;
-        tar.finish()?;
-        Ok(())
+    // Pick up the config and package selection a finished import installed,
+    // then link the packages it cloned
+    fn finish_import(&mut self) {
+        let report = std::mem::take(&mut self.output);
+        if let Ok(config) = config::load() {
+            self.config = config;
+        }
+        self.state = State::load();
+        if !Path::new(&self.get_dotfiles_path()).exists() {
+            self.output = report;
+            return;
+        }
+        self.link_dot_files();
+        self.output = format!("{}\n\nLinking: {}", report, self.output.trim());
     }
-    fn backup_share(&mut self) {
-        let home = self.get_home_directory();
-        let share_path = format!("{}/.local/share/nvim", home);
-        let target_path = format!("{}/.local/share/nvim.tar.gz", home);
-        match self.create_tar_gz(&share_path, &target_path) {
-            Ok(_) => {
-                self.output = String::from("Share directory backed up successfully!");
+
+    // Dotfiles History Functions
+    fn open_history(&mut self) {
+        let commits = match self
+            .open_dotfiles_repo()
+            .and_then(|repo| history::list_commits(&repo))
+        {
+            Ok(commits) => commits,
+            Err(e) => {
+                self.output = format!("Failed to read dotfiles history: {}", e);
+                return;
             }
+        };
+        if commits.is_empty() {
+            self.output = String::from("Dotfiles repository has no commits.");
+            return;
+        }
+
+        let items = commits
+            .into_iter()
+            .map(|(oid, line)| (line, PickerItem::Commit(oid)))
+            .collect();
+        self.picker = Some(Picker {
+            title: String::from("Dotfiles History"),
+            items,
+            state: 0,
+        });
+        self.output = String::from("Select a commit to show its diff and rollback actions.");
+    }
+
+    fn open_commit(&mut self, oid: Oid) {
+        match self
+            .open_dotfiles_repo()
+            .and_then(|repo| history::commit_diff(&repo, oid))
+        {
+            Ok(diff) => self.output = diff,
             Err(e) => {
-                self.output = format!("Failed to backup share directory: {}", e);
+                self.output = format!("Failed to show commit: {}", e);
+                return;
             }
         }
+
+        let short_id = history::short_id(oid);
+        self.picker = Some(Picker {
+            title: format!("Commit {}", short_id),
+            items: vec![
+                (
+                    String::from("Check Out Into Safety Branch"),
+                    PickerItem::CheckoutSafetyBranch(oid),
+                ),
+                (String::from("Revert Commit"), PickerItem::RevertCommit(oid)),
+                (
+                    String::from("Hard Reset To Commit"),
+                    PickerItem::HardReset(oid),
+                ),
+                (String::from("Back To History"), PickerItem::BackToHistory),
+            ],
+            state: 0,
+        });
     }
 
-    fn backup_state(&mut self) {
-        let home = self.get_home_directory();
-        let state_path = format!("{}/.local/state/nvim", home);
-        let target_path = format!("{}/.local/state/nvim.tar.gz", home);
-        match self.create_tar_gz(&state_path, &target_path) {
-            Ok(_) => {
-                self.output = String::from("State directory backed up successfully!");
+    fn checkout_safety_branch(&mut self, oid: Oid) {
+        match self
+            .open_dotfiles_repo()
+            .and_then(|repo| history::checkout_safety_branch(&repo, oid))
+        {
+            Ok((branch, packages)) => {
+                self.output = format!(
+                    "Checked out {} into branch {}.",
+                    history::short_id(oid),
+                    branch
+                );
+                let relinked = self.relink_packages(&packages);
+                self.output.push_str(&relinked);
+            }
+            Err(e) => self.output = format!("Failed to check out commit: {}", e),
+        }
+        self.reopen_keeping_output(Self::open_history);
+    }
+
+    fn revert_commit(&mut self, oid: Oid) {
+        match self
+            .open_dotfiles_repo()
+            .and_then(|repo| history::revert_commit(&repo, oid))
+        {
+            Ok(packages) => {
+                self.output = format!("Reverted commit {}.", history::short_id(oid));
+                let relinked = self.relink_packages(&packages);
+                self.output.push_str(&relinked);
+            }
+            Err(e) => self.output = format!("Failed to revert commit: {}", e),
+        }
+        self.reopen_keeping_output(Self::open_history);
+    }
+
+    fn hard_reset(&mut self, oid: Oid) {
+        match self
+            .open_dotfiles_repo()
+            .and_then(|repo| history::hard_reset(&repo, oid))
+        {
+            Ok(packages) => {
+                self.output = format!("Reset dotfiles to {}.", history::short_id(oid));
+                let relinked = self.relink_packages(&packages);
+                self.output.push_str(&relinked);
             }
+            Err(e) => self.output = format!("Failed to reset dotfiles: {}", e),
+        }
+        self.reopen_keeping_output(Self::open_history);
+    }
+
+    // Refresh a picker after an action without replacing the action's report
+    fn reopen_keeping_output(&mut self, open: fn(&mut Self)) {
+        let output = std::mem::take(&mut self.output);
+        open(self);
+        self.output = output;
+    }
+
+    // Restow the given packages so their links follow the new working tree
+    fn relink_packages(&mut self, packages: &[String]) -> String {
+        // Git already updated the files in place in $HOME
+        if self.config.bare {
+            return String::new();
+        }
+        // Packages that are not enabled on this machine are never linked
+        let selected = self.selected_packages();
+        let packages: Vec<&String> = packages
+            .iter()
+            .filter(|package| selected.contains(package))
+            .collect();
+        if packages.is_empty() {
+            return String::from("\nNo packages needed relinking.");
+        }
+        if self.is_layered() {
+            let home = self.get_home_directory();
+            let packages: Vec<String> = packages.into_iter().cloned().collect();
+            let dotfile_layers = self.dotfile_layers();
+            let mut report = format!(
+                "\n\n{}",
+                layers::link(&dotfile_layers, Path::new(&home), &packages)
+            );
+            report.push_str(&encryption::decrypt_packages(
+                Path::new(&self.get_dotfiles_path()),
+                Path::new(&home),
+                &packages,
+                &self.config.encryption,
+            ));
+            return report;
+        }
+        if !self.is_command_exist("stow", None) {
+            return String::from("\nstow is not installed, packages were not relinked.");
+        }
+
+        let home_folder = self.get_home_directory();
+        let dotfiles_path = self.get_dotfiles_path();
+        let mut report = String::from("\n");
+        let mut relinked = Vec::new();
+        for package in packages {
+            if !Path::new(&dotfiles_path).join(package).is_dir() {
+                report.push_str(&format!(
+                    "\n- {}: removed from repository, unlink stale links manually",
+                    package
+                ));
+                continue;
+            }
+            let output = Command::new("stow")
+                .arg("-R")
+                .arg(encryption::STOW_IGNORE)
+                .arg("-d")
+                .arg(&dotfiles_path)
+                .arg("-t")
+                .arg(&home_folder)
+                .arg(package)
+                .output();
+            match output {
+                Ok(output) if output.status.success() => {
+                    report.push_str(&format!("\n- {}: relinked", package))
+                }
+                Ok(output) => report.push_str(&format!(
+                    "\n- {}: {}",
+                    package,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                )),
+                Err(e) => report.push_str(&format!("\n- {}: {}", package, e)),
+            }
+            relinked.push(package.clone());
+        }
+        report.push_str(&encryption::decrypt_packages(
+            Path::new(&dotfiles_path),
+            Path::new(&home_folder),
+            &relinked,
+            &self.config.encryption,
+        ));
+        report
+    }
+
+    // Stow packages of the configured set that exist in the dotfiles checkout
+    fn available_stow_packages(&mut self) -> Vec<String> {
+        let dotfile_layers = self.dotfile_layers();
+        self.selected_packages()
+            .into_iter()
+            .filter(|package| {
+                dotfile_layers
+                    .iter()
+                    .any(|layer| layer.path.join(package).is_dir())
+            })
+            .collect()
+    }
+
+    // Packages enabled on this machine, defaulting to the built-in stow set
+    fn selected_packages(&self) -> Vec<String> {
+        self.state.packages_for_host().unwrap_or_else(|| {
+            self.stow_packages
+                .iter()
+                .map(|package| package.to_string())
+                .collect()
+        })
+    }
+
+    fn unlink_packages(&mut self, packages: &[String]) -> String {
+        if self.config.bare {
+            return String::new();
+        }
+        if self.is_layered() {
+            let home = self.get_home_directory();
+            let dotfile_layers = self.dotfile_layers();
+            let mut report = format!(
+                "\n\n{}",
+                layers::unlink(&dotfile_layers, Path::new(&home), packages)
+            );
+            report.push_str(&encryption::remove_decrypted(
+                Path::new(&self.get_dotfiles_path()),
+                Path::new(&home),
+                packages,
+                &self.config.encryption,
+            ));
+            return report;
+        }
+        if !self.is_command_exist("stow", None) {
+            return String::from("\nstow is not installed, packages were not unlinked.");
+        }
+
+        let home_folder = self.get_home_directory();
+        let dotfiles_path = self.get_dotfiles_path();
+        let mut report = String::from("\n");
+        for package in packages {
+            let output = Command::new("stow")
+                .arg("-D")
+                .arg(encryption::STOW_IGNORE)
+                .arg("-d")
+                .arg(&dotfiles_path)
+                .arg("-t")
+                .arg(&home_folder)
+                .arg(package)
+                .output();
+            match output {
+                Ok(output) if output.status.success() => {
+                    report.push_str(&format!("\n- {}: unlinked", package))
+                }
+                Ok(output) => report.push_str(&format!(
+                    "\n- {}: {}",
+                    package,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                )),
+                Err(e) => report.push_str(&format!("\n- {}: {}", package, e)),
+            }
+        }
+        report.push_str(&encryption::remove_decrypted(
+            Path::new(&dotfiles_path),
+            Path::new(&home_folder),
+            packages,
+            &self.config.encryption,
+        ));
+        report
+    }
+
+    // Branch Profile Functions
+    fn open_branches(&mut self) {
+        let branches = match self
+            .open_dotfiles_repo()
+            .and_then(|repo| branches::list_branches(&repo))
+        {
+            Ok(branches) => branches,
             Err(e) => {
-                self.output = format!("Failed to backup state directory: {}", e);
+                self.output = format!("Failed to list branches: {}", e);
+                return;
+            }
+        };
+
+        let remembered = self.state.branch_for_host();
+        let items = branches
+            .into_iter()
+            .map(|(name, branch_type, is_head)| {
+                let (kind, marker) = match (branch_type, is_head) {
+                    (BranchType::Local, true) => ("local", "* "),
+                    (BranchType::Local, false) => ("local", "  "),
+                    (BranchType::Remote, _) => ("remote", "  "),
+                };
+                let mut label = format!("{}{} ({})", marker, name, kind);
+                if branch_type == BranchType::Local && remembered.as_deref() == Some(name.as_str())
+                {
+                    label.push_str(" [this host]");
+                }
+                (label, PickerItem::Branch(name, branch_type))
+            })
+            .collect();
+        self.picker = Some(Picker {
+            title: String::from("Branches"),
+            items,
+            state: 0,
+        });
+        self.output = format!(
+            "Select a branch for {}. Packages are unlinked before the switch and relinked after it.",
+            state::hostname()
+        );
+    }
+
+    fn switch_branch(&mut self, name: &str, branch_type: BranchType) {
+        let repo = match self.open_dotfiles_repo() {
+            Ok(repo) => repo,
+            Err(e) => {
+                self.output = format!("Failed to open repository: {}", e);
+                return;
             }
+        };
+        if self.refuse_unlinked(&repo) {
+            return;
         }
+
+        let linked = self.available_stow_packages();
+        let unlinked = self.unlink_packages(&linked);
+        match branches::checkout_branch(&repo, name, branch_type) {
+            Ok(branch) => {
+                self.output = format!("Switched to branch {}.{}", branch, unlinked);
+                let packages = self.available_stow_packages();
+                let relinked = self.relink_packages(&packages);
+                self.output.push_str(&relinked);
+
+                self.state
+                    .branches
+                    .insert(state::hostname(), branch.clone());
+                match self.state.save() {
+                    Ok(()) => self.output.push_str(&format!(
+                        "\n\nRemembered {} for host {}.",
+                        branch,
+                        state::hostname()
+                    )),
+                    Err(e) => self.output.push_str(&format!("\n\n{}", e)),
+                }
+            }
+            Err(e) => {
+                // Put the links of the branch we stayed on back in place
+                self.output = format!("Failed to switch branch: {}", e);
+                let relinked = self.relink_packages(&linked);
+                self.output.push_str(&relinked);
+            }
+        }
+        self.reopen_keeping_output(Self::open_branches);
     }
 
-    fn backup_cache(&mut self) {
+    fn show_layers(&mut self) {
         let home = self.get_home_directory();
-        let cache_path = format!("{}/.cache/nvim", home);
-        let target_path = format!("{}/.cache/nvim.tar.gz", home);
-        match self.create_tar_gz(&cache_path, &target_path) {
-            Ok(_) => {
-                self.output = String::from("Cache directory backed up successfully!");
+        let dotfile_layers = self.dotfile_layers();
+        let packages = self.selected_packages();
+        self.output = layers::describe(&dotfile_layers, Path::new(&home), &packages);
+    }
+
+    // Package Selection Functions
+    fn open_packages(&mut self) {
+        if self.config.bare {
+            self.output = String::from(
+                "A bare dotfiles repository tracks $HOME directly and has no packages to select.",
+            );
+            return;
+        }
+        let packages = match self
+            .open_dotfiles_repo()
+            .and_then(|repo| sparse::list_packages(&repo))
+        {
+            Ok(packages) => packages,
+            Err(e) => {
+                self.output = format!("Failed to list packages: {}", e);
+                return;
             }
+        };
+
+        let selected = self.selected_packages();
+        let state = self
+            .picker
+            .as_ref()
+            .filter(|picker| picker.title == "Packages")
+            .map_or(0, |picker| picker.state);
+        let items = packages
+            .into_iter()
+            .map(|package| {
+                let mark = if selected.contains(&package) {
+                    "x"
+                } else {
+                    " "
+                };
+                (
+                    format!("[{}] {}", mark, package),
+                    PickerItem::TogglePackage(package),
+                )
+            })
+            .collect();
+        self.picker = Some(Picker {
+            title: String::from("Packages"),
+            items,
+            state,
+        });
+        self.output = format!(
+            "Toggle the stow packages used on {} with Enter.",
+            state::hostname()
+        );
+    }
+
+    fn toggle_package(&mut self, package: &str) {
+        let repo = match self.open_dotfiles_repo() {
+            Ok(repo) => repo,
             Err(e) => {
-                self.output = format!("Failed to backup cache directory: {}", e);
+                self.output = format!("Failed to open repository: {}", e);
+                return;
             }
+        };
+
+        let mut selected = self.selected_packages();
+        let enabled = !selected.iter().any(|p| p == package);
+        let package = package.to_string();
+        if enabled {
+            selected.push(package.clone());
+            selected.sort();
+            self.output = format!("Enabled {}.", package);
+        } else {
+            selected.retain(|p| *p != package);
+            self.output = format!("Disabled {}.", package);
+            let unlinked = self.unlink_packages(std::slice::from_ref(&package));
+            self.output.push_str(&unlinked);
+        }
+        self.state
+            .packages
+            .insert(state::hostname(), selected.clone());
+        if let Err(e) = self.state.save() {
+            self.output.push_str(&format!("\n{}", e));
+        }
+
+        // Sparse checkouts follow the selection so enabled packages exist on disk
+        if sparse::packages(&repo).is_some() {
+            match sparse::apply(&repo, &selected) {
+                Ok(kept) if kept.is_empty() => self.output.push_str("\nSparse checkout updated."),
+                Ok(kept) => self.output.push_str(&format!(
+                    "\nSparse checkout updated. Kept locally modified files:\n- {}",
+                    kept.join("\n- ")
+                )),
+                Err(e) => self
+                    .output
+                    .push_str(&format!("\nFailed to update sparse checkout: {}", e)),
+            }
+        }
+
+        if enabled {
+            let relinked = self.relink_packages(std::slice::from_ref(&package));
+            self.output.push_str(&relinked);
         }
+        self.reopen_keeping_output(Self::open_packages);
     }
 }
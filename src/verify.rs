@@ -0,0 +1,301 @@
+use crate::config::{self, VerifyConfig};
+use crate::history;
+use git2::{Oid, Repository};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+// Check every commit reachable from `incoming` but not from `head`, returning
+// a report of the commits that are unsigned or not signed by a trusted key
+pub fn verify_incoming(
+    repo: &Repository,
+    head: Oid,
+    incoming: Oid,
+    policy: &VerifyConfig,
+) -> Result<(), String> {
+    let mut revwalk = repo
+        .revwalk()
+        .map_err(|e| format!("Failed to walk incoming commits: {}", e))?;
+    revwalk
+        .push(incoming)
+        .and_then(|_| revwalk.hide(head))
+        .map_err(|e| format!("Failed to walk incoming commits: {}", e))?;
+
+    let mut failures = Vec::new();
+    let mut checked = 0;
+    for oid in revwalk {
+        let oid = oid.map_err(|e| format!("Failed to walk incoming commits: {}", e))?;
+        let commit = repo
+            .find_commit(oid)
+            .map_err(|e| format!("Failed to read commit {}: {}", oid, e))?;
+        checked += 1;
+
+        let result = match repo.extract_signature(&oid, None) {
+            Ok((signature, signed_data)) => verify_signature(
+                &signature,
+                &signed_data,
+                commit.committer().email().unwrap_or(""),
+                policy,
+            ),
+            Err(_) => Err(String::from("unsigned")),
+        };
+        if let Err(reason) = result {
+            failures.push(format!(
+                "{} {}: {}",
+                history::short_id(oid),
+                commit.summary().unwrap_or(""),
+                reason
+            ));
+        }
+    }
+
+    if failures.is_empty() {
+        return Ok(());
+    }
+    let mut report = format!(
+        "Sync blocked: {} of {} incoming commits failed signature verification:",
+        failures.len(),
+        checked
+    );
+    for failure in failures {
+        report.push_str(&format!("\n- {}", failure));
+    }
+    Err(report)
+}
+
+fn verify_signature(
+    signature: &[u8],
+    signed_data: &[u8],
+    signer: &str,
+    policy: &VerifyConfig,
+) -> Result<(), String> {
+    if signature.starts_with(b"-----BEGIN SSH SIGNATURE-----") {
+        verify_ssh(signature, signed_data, signer, policy)
+    } else if signature.starts_with(b"-----BEGIN PGP SIGNATURE-----") {
+        verify_gpg(signature, signed_data, policy)
+    } else {
+        Err(String::from("unsupported signature format"))
+    }
+}
+
+fn verify_ssh(
+    signature: &[u8],
+    signed_data: &[u8],
+    signer: &str,
+    policy: &VerifyConfig,
+) -> Result<(), String> {
+    let allowed_signers = policy
+        .allowed_signers
+        .as_deref()
+        .map(config::expand_home)
+        .ok_or_else(|| String::from("SSH signature but no allowed_signers file configured"))?;
+
+    let signature_file = write_signature(signature)?;
+    let mut command = Command::new("ssh-keygen");
+    command
+        .arg("-Y")
+        .arg("verify")
+        .arg("-f")
+        .arg(&allowed_signers)
+        .arg("-I")
+        .arg(signer)
+        .arg("-n")
+        .arg("git")
+        .arg("-s")
+        .arg(&signature_file.path);
+    let result = run_with_input(&mut command, signed_data);
+
+    let (verified, _) = result.map_err(|e| format!("failed to run ssh-keygen: {}", e))?;
+    if verified {
+        Ok(())
+    } else {
+        Err(format!("SSH signature not trusted for {}", signer))
+    }
+}
+
+fn verify_gpg(signature: &[u8], signed_data: &[u8], policy: &VerifyConfig) -> Result<(), String> {
+    let signature_file = write_signature(signature)?;
+    let mut command = Command::new("gpg");
+    command
+        .arg("--status-fd")
+        .arg("1")
+        .arg("--verify")
+        .arg(&signature_file.path)
+        .arg("-");
+    if let Some(gpg_home) = &policy.gpg_home {
+        command.env("GNUPGHOME", config::expand_home(gpg_home));
+    }
+    let result = run_with_input(&mut command, signed_data);
+
+    let (_, status) = result.map_err(|e| format!("failed to run gpg: {}", e))?;
+    // A good signature alone only proves the key signed it, the key must also
+    // be trusted in the keyring
+    let good = status.contains("[GNUPG:] GOODSIG");
+    let trusted =
+        status.contains("[GNUPG:] TRUST_FULLY") || status.contains("[GNUPG:] TRUST_ULTIMATE");
+    match (good, trusted) {
+        (true, true) => Ok(()),
+        (true, false) => Err(String::from("GPG signature from an untrusted key")),
+        _ => Err(String::from("bad or unknown GPG signature")),
+    }
+}
+
+// A signature handed to a verifier as a file, in a directory only this user
+// can enter. Both are removed once it is dropped.
+struct SignatureFile {
+    dir: PathBuf,
+    path: PathBuf,
+}
+
+impl Drop for SignatureFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+// The directory and file are created fresh, so nothing another user placed
+// in the shared temp directory beforehand is followed or written to
+fn write_signature(signature: &[u8]) -> Result<SignatureFile, String> {
+    let dir = std::env::temp_dir().join(format!(
+        "dot-utils-{}-{}",
+        std::process::id(),
+        chrono::Local::now()
+            .timestamp_nanos_opt()
+            .unwrap_or_default()
+    ));
+    fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&dir)
+        .map_err(|e| format!("failed to create {}: {}", dir.display(), e))?;
+    let file = SignatureFile {
+        path: dir.join("signature"),
+        dir,
+    };
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&file.path)
+        .and_then(|mut out| out.write_all(signature))
+        .map_err(|e| format!("failed to write {}: {}", file.path.display(), e))?;
+    Ok(file)
+}
+
+// Run a verifier with the signed payload on stdin, returning whether it
+// succeeded together with its stdout
+fn run_with_input(command: &mut Command, input: &[u8]) -> std::io::Result<(bool, String)> {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(input)?;
+    }
+    let output = child.wait_with_output()?;
+    Ok((
+        output.status.success(),
+        String::from_utf8_lossy(&output.stdout).to_string(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use git2::Signature;
+    use std::path::Path;
+    use tempfile::TempDir;
+
+    // An ed25519 key for signing, without a passphrase
+    fn key(dir: &Path, name: &str) -> PathBuf {
+        let key = dir.join(name);
+        let status = Command::new("ssh-keygen")
+            .args(["-q", "-t", "ed25519", "-N", "", "-C", name, "-f"])
+            .arg(&key)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        key
+    }
+
+    fn trust(dir: &Path, key: &Path) -> VerifyConfig {
+        let public = fs::read_to_string(key.with_extension("pub")).unwrap();
+        let allowed_signers = dir.join("allowed_signers");
+        fs::write(&allowed_signers, format!("test@example.com {}", public)).unwrap();
+        VerifyConfig {
+            enabled: true,
+            allowed_signers: Some(allowed_signers.to_string_lossy().to_string()),
+            gpg_home: None,
+        }
+    }
+
+    // Commit the index on top of `parent` with an SSH signature, leaving HEAD
+    // where it is the way a fetch does
+    fn signed_commit(repo: &Repository, parent: Oid, key: &Path) -> Oid {
+        let parent = repo.find_commit(parent).unwrap();
+        let tree = repo
+            .find_tree(repo.index().unwrap().write_tree().unwrap())
+            .unwrap();
+        let signature = Signature::now("Test", "test@example.com").unwrap();
+        let buffer = repo
+            .commit_create_buffer(&signature, &signature, "Signed", &tree, &[&parent])
+            .unwrap();
+        let mut command = Command::new("ssh-keygen");
+        command.args(["-Y", "sign", "-n", "git", "-f"]).arg(key);
+        let (signed, armored) = run_with_input(&mut command, &buffer).unwrap();
+        assert!(signed);
+        repo.commit_signed(buffer.as_str().unwrap(), &armored, None)
+            .unwrap()
+    }
+
+    fn repo() -> (TempDir, Repository, Oid) {
+        let (dir, repo) = testing::repo();
+        testing::stage(&repo, "zsh/.zshrc", "one\n");
+        let head = testing::commit(&repo, "Add zsh");
+        (dir, repo, head)
+    }
+
+    #[test]
+    fn accepts_commits_signed_by_a_trusted_key() {
+        let (_dir, repo, head) = repo();
+        let keys = TempDir::new().unwrap();
+        let key = key(keys.path(), "trusted");
+        let incoming = signed_commit(&repo, head, &key);
+
+        let policy = trust(keys.path(), &key);
+        assert_eq!(verify_incoming(&repo, head, incoming, &policy), Ok(()));
+    }
+
+    #[test]
+    fn rejects_unsigned_commits() {
+        let (_dir, repo, head) = repo();
+        let keys = TempDir::new().unwrap();
+        let key = key(keys.path(), "trusted");
+        let signed = signed_commit(&repo, head, &key);
+        repo.set_head_detached(signed).unwrap();
+        testing::stage(&repo, "zsh/.zshrc", "two\n");
+        let incoming = testing::commit(&repo, "Unsigned");
+
+        let report = verify_incoming(&repo, head, incoming, &trust(keys.path(), &key));
+        let report = report.unwrap_err();
+        assert!(report.contains("1 of 2 incoming commits"));
+        assert!(report.contains("Unsigned: unsigned"));
+    }
+
+    #[test]
+    fn rejects_commits_signed_by_an_unknown_key() {
+        let (_dir, repo, head) = repo();
+        let keys = TempDir::new().unwrap();
+        let trusted = key(keys.path(), "trusted");
+        let other = key(keys.path(), "other");
+        let incoming = signed_commit(&repo, head, &other);
+
+        let report = verify_incoming(&repo, head, incoming, &trust(keys.path(), &trusted));
+        assert!(report
+            .unwrap_err()
+            .contains("SSH signature not trusted for test@example.com"));
+    }
+}